@@ -0,0 +1,256 @@
+use crate::source::Source;
+use crate::{Rate, Timestamp, Volume};
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+// 默认最少需要几个数据源同意
+// Default number of agreeing sources needed before a minute is accepted.
+pub const MIN_SOURCES_QUORUM: u32 = 2;
+
+// 默认允许偏离中位数的比例
+// Default maximum relative distance from the median before a source is treated as an outlier.
+pub const MAX_SOURCE_DEVIATION: f32 = 0.02;
+
+// 聚合配置
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct AggregationConfig {
+    // The sources fetched for every job
+    pub sources: Vec<Source>,
+
+    // How many sources must agree on a minute for it to be stored
+    pub min_sources: u32,
+
+    // A source whose rate is further than this fraction away from the median is rejected
+    pub max_deviation: f32,
+}
+
+impl Default for AggregationConfig {
+    fn default() -> Self {
+        AggregationConfig {
+            sources: Source::quoting("USD"),
+            min_sources: MIN_SOURCES_QUORUM,
+            max_deviation: MAX_SOURCE_DEVIATION,
+        }
+    }
+}
+
+impl AggregationConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.sources.is_empty() {
+            return Err("At least one source must be configured".to_string());
+        }
+        // Only ICP/USD is tracked, a rate in another currency would skew the median.
+        if let Some(source) = self.sources.iter().find(|source| source.quote() != "USD") {
+            return Err(format!(
+                "{:?} quotes {}, only sources quoting USD can be aggregated",
+                source,
+                source.quote()
+            ));
+        }
+        if self.min_sources == 0 || self.min_sources as usize > self.sources.len() {
+            return Err(format!(
+                "min_sources must be between 1 and the number of sources ({})",
+                self.sources.len()
+            ));
+        }
+        if !(self.max_deviation > 0.0) {
+            return Err("max_deviation must be positive".to_string());
+        }
+        Ok(())
+    }
+
+    // 某一分钟的聚合配置
+    // The configuration a minute is aggregated with at `now`: only the sources that still serve
+    // the minute can report it, and the quorum is capped to their number. Otherwise the minutes
+    // older than Kraken's history could never reach the default quorum of two.
+    pub fn at(&self, minute: Timestamp, now: Timestamp, granularity: u64) -> AggregationConfig {
+        let sources: Vec<Source> = self
+            .sources
+            .iter()
+            .filter(|source| source.oldest(now, granularity) <= minute)
+            .cloned()
+            .collect();
+        AggregationConfig {
+            min_sources: self.min_sources.min(sources.len() as u32).max(1),
+            sources,
+            max_deviation: self.max_deviation,
+        }
+    }
+}
+
+// 聚合结果的可信度信息
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct RateConfidence {
+    // Sources whose rate contributed to the aggregate
+    pub sources: Vec<Source>,
+
+    // Sources that reported a rate but were rejected as outliers
+    pub outliers: Vec<Source>,
+
+    // Largest relative distance between a contributing source and the aggregate
+    pub spread: f32,
+}
+
+impl RateConfidence {
+    // 合并多个可信度信息
+    // Combine the confidence of several rates, e.g. when they are rolled up into one bucket.
+    pub fn merge(&mut self, other: &RateConfidence) {
+        self.sources.extend(other.sources.iter().cloned());
+        self.outliers.extend(other.outliers.iter().cloned());
+        self.sources.sort();
+        self.sources.dedup();
+        self.outliers.sort();
+        self.outliers.dedup();
+        self.spread = self.spread.max(other.spread);
+    }
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct AggregatedRate {
+    pub rate: Rate,
+    // The volume of the contributing sources
+    pub volume: Volume,
+    pub confidence: RateConfidence,
+}
+
+// 中位数
+fn median(rates: &mut Vec<Rate>) -> Rate {
+    rates.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let middle = rates.len() / 2;
+    if rates.len() % 2 == 0 {
+        (rates[middle - 1] + rates[middle]) / 2.0
+    } else {
+        rates[middle]
+    }
+}
+
+// 聚合同一分钟的多个数据源
+// Aggregate the rates reported by different sources for the same minute. Sources further than
+// max_deviation from the median of all samples are rejected, and the median of the remaining
+// ones is returned, along with their total volume. Returns None if fewer than min_sources samples
+// survive.
+pub fn aggregate(
+    samples: &[(Source, Rate, Volume)],
+    config: &AggregationConfig,
+) -> Option<AggregatedRate> {
+    let samples: Vec<(Source, Rate, Volume)> = samples
+        .iter()
+        .filter(|(_, rate, _)| rate.is_finite() && *rate > 0.0)
+        .cloned()
+        .collect();
+    if samples.len() < config.min_sources as usize {
+        return None;
+    }
+
+    let overall = median(&mut samples.iter().map(|(_, rate, _)| *rate).collect());
+    let (accepted, rejected): (Vec<_>, Vec<_>) = samples
+        .into_iter()
+        .partition(|(_, rate, _)| (rate - overall).abs() / overall <= config.max_deviation);
+    if accepted.len() < config.min_sources as usize {
+        return None;
+    }
+
+    let rate = median(&mut accepted.iter().map(|(_, rate, _)| *rate).collect());
+    let spread = accepted
+        .iter()
+        .map(|(_, r, _)| (r - rate).abs() / rate)
+        .fold(0.0, f32::max);
+    let volume = accepted.iter().map(|(_, _, volume)| volume.max(0.0)).sum();
+    let mut sources: Vec<Source> = accepted.into_iter().map(|(source, _, _)| source).collect();
+    let mut outliers: Vec<Source> = rejected.into_iter().map(|(source, _, _)| source).collect();
+    sources.sort();
+    outliers.sort();
+
+    Some(AggregatedRate {
+        rate,
+        volume,
+        confidence: RateConfidence {
+            sources,
+            outliers,
+            spread,
+        },
+    })
+}
+
+// 测试用的单一数据源结果
+// A rate reported by Coinbase alone, for the tests of the modules storing and sampling rates.
+#[cfg(test)]
+pub fn test_rate(rate: Rate, volume: Volume) -> AggregatedRate {
+    AggregatedRate {
+        rate,
+        volume,
+        confidence: RateConfidence {
+            sources: vec![Source::Coinbase],
+            outliers: vec![],
+            spread: 0.0,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aggregate_rejects_outlier() {
+        let config = AggregationConfig::default();
+        let samples = vec![
+            (Source::Coinbase, 9.56, 10.0),
+            (Source::Binance, 9.58, 20.0),
+            (Source::Kraken, 12.0, 40.0),
+        ];
+        let aggregated = aggregate(&samples, &config).unwrap();
+        assert!((aggregated.rate - 9.57).abs() < 1e-4);
+        assert_eq!(aggregated.volume, 30.0);
+        assert_eq!(
+            aggregated.confidence.sources,
+            vec![Source::Coinbase, Source::Binance]
+        );
+        assert_eq!(aggregated.confidence.outliers, vec![Source::Kraken]);
+    }
+
+    #[test]
+    fn test_sources_must_quote_usd() {
+        let config = AggregationConfig::default();
+        assert_eq!(config.sources, vec![Source::Coinbase, Source::Kraken]);
+        assert_eq!(config.validate(), Ok(()));
+        let binance = AggregationConfig {
+            sources: vec![Source::Coinbase, Source::Binance],
+            ..config
+        };
+        assert!(binance.validate().is_err());
+    }
+
+    #[test]
+    fn test_aggregate_requires_quorum() {
+        let config = AggregationConfig::default();
+        assert_eq!(aggregate(&[(Source::Coinbase, 9.56, 1.0)], &config), None);
+        // Two sources that disagree can not form a quorum of two.
+        let samples = vec![(Source::Coinbase, 9.56, 1.0), (Source::Kraken, 12.0, 1.0)];
+        assert_eq!(aggregate(&samples, &config), None);
+    }
+
+    #[test]
+    fn test_quorum_of_minutes_older_than_kraken_history() {
+        let config = AggregationConfig::default();
+        let now = 1652454180;
+        let samples = vec![(Source::Coinbase, 9.56, 1.0)];
+
+        let recent = config.at(now - 719 * 60, now, 60);
+        assert_eq!(recent, config);
+        assert_eq!(aggregate(&samples, &recent), None);
+
+        // Kraken no longer serves the minute, Coinbase alone is enough
+        let old = config.at(now - 720 * 60, now, 60);
+        assert_eq!(old.sources, vec![Source::Coinbase]);
+        assert_eq!(old.min_sources, 1);
+        assert_eq!(aggregate(&samples, &old).unwrap().rate, 9.56);
+
+        let kraken = AggregationConfig {
+            sources: vec![Source::Kraken],
+            min_sources: 1,
+            ..config
+        };
+        assert_eq!(kraken.at(now - 800 * 60, now, 60).min_sources, 1);
+    }
+}
@@ -0,0 +1,124 @@
+use crate::stable::StoredRate;
+use crate::{config, Rate, Timestamp, Volume};
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+// 时间段内的统计数据
+#[derive(CandidType, Clone, Deserialize, Debug, PartialEq, Serialize)]
+pub struct RateStats {
+    // The coarsest interval of the stored data the statistics are computed from, in seconds
+    pub interval: u64,
+    pub points: u64,
+
+    // The first and the last stored minute of the range, and the change between them in percent
+    pub open: (Timestamp, Rate),
+    pub close: (Timestamp, Rate),
+    pub change_percent: f32,
+
+    // Lowest and highest minute of the range
+    pub min: (Timestamp, Rate),
+    pub max: (Timestamp, Rate),
+
+    // Time-weighted average price. Every point holds until the next one, so gaps are filled with
+    // the last known rate.
+    pub twap: Rate,
+
+    // Volume-weighted average price, None if no volume was reported for the range
+    pub vwap: Option<Rate>,
+    pub volume: Volume,
+
+    // Realized volatility: the square root of the sum of the squared log returns between
+    // consecutive points. It isn't annualized.
+    pub volatility: f64,
+}
+
+// 计算统计数据
+// Compute the statistics of the points, which must be sorted by timestamp. Rolled up buckets are
+// used as they are, so e.g. the volatility inside a bucket isn't taken into account.
+pub fn analyze(points: &[(Timestamp, StoredRate)], interval: u64) -> Option<RateStats> {
+    let (first, last) = (&points.first()?.1, &points.last()?.1);
+
+    let mut min = (first.low_timestamp, first.low);
+    let mut max = (first.high_timestamp, first.high);
+    let mut weighted = 0.0;
+    let mut duration = 0;
+    let mut volume = 0.0;
+    let mut turnover = 0.0;
+    let mut squared_returns = 0.0;
+    for (i, (timestamp, rate)) in points.iter().enumerate() {
+        if rate.low < min.1 {
+            min = (rate.low_timestamp, rate.low);
+        }
+        if rate.high > max.1 {
+            max = (rate.high_timestamp, rate.high);
+        }
+
+        let until = match points.get(i + 1) {
+            Some((next, _)) => *next,
+            None => rate.close_timestamp + config::granularity(),
+        };
+        weighted += rate.average as f64 * (until - timestamp) as f64;
+        duration += until - timestamp;
+
+        volume += rate.volume;
+        turnover += rate.turnover;
+
+        if i > 0 {
+            let log_return = (rate.close as f64 / points[i - 1].1.close as f64).ln();
+            squared_returns += log_return * log_return;
+        }
+    }
+
+    Some(RateStats {
+        interval,
+        points: points.len() as u64,
+        open: (first.open_timestamp, first.open),
+        close: (last.close_timestamp, last.close),
+        change_percent: (last.close - first.open) / first.open * 100.0,
+        min,
+        max,
+        twap: (weighted / duration.max(1) as f64) as Rate,
+        vwap: if volume > 0.0 {
+            Some((turnover / volume) as Rate)
+        } else {
+            None
+        },
+        volume,
+        volatility: squared_returns.sqrt(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aggregate::test_rate;
+
+    fn point(timestamp: Timestamp, rate: Rate, volume: Volume) -> (Timestamp, StoredRate) {
+        let rate = test_rate(rate, volume);
+        (timestamp, StoredRate::new(timestamp, &rate))
+    }
+
+    #[test]
+    fn test_analyze() {
+        // The minute at 120 is missing, so the rate of 60 holds for two minutes.
+        let points = vec![
+            point(0, 2.0, 1.0),
+            point(60, 1.0, 3.0),
+            point(180, 4.0, 0.0),
+        ];
+        let stats = analyze(&points, 60).unwrap();
+        assert_eq!(stats.points, 3);
+        assert_eq!(stats.open, (0, 2.0));
+        assert_eq!(stats.close, (180, 4.0));
+        assert_eq!(stats.change_percent, 100.0);
+        assert_eq!(stats.min, (60, 1.0));
+        assert_eq!(stats.max, (180, 4.0));
+        assert_eq!(stats.twap, (2.0 + 2.0 * 1.0 + 4.0) / 4.0);
+        assert_eq!(stats.vwap, Some((2.0 + 3.0) / 4.0));
+        assert_eq!(stats.volume, 4.0);
+        let expected = (0.5f64.ln().powi(2) + 4f64.ln().powi(2)).sqrt();
+        assert!((stats.volatility - expected).abs() < 1e-9);
+
+        assert_eq!(analyze(&[], 60), None);
+    }
+}
@@ -0,0 +1,148 @@
+use crate::source::Source;
+use crate::{DATA_POINTS_PER_API, MAX_RESPONSE_BYTES, RATE_LIMIT_FACTOR, REMOTE_FETCH_GRANULARITY};
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+
+// 所有数据源都支持的粒度
+// Candle widths in seconds that every source provides.
+pub const SUPPORTED_GRANULARITIES: [u64; 4] = [60, 5 * 60, 15 * 60, 60 * 60];
+
+// http_request 响应的上限
+pub const MAX_HTTP_RESPONSE_BYTES: u64 = 2 * 1024 * 1024;
+
+// 获取数据的配置
+// Defaults to the constants of the same names.
+#[derive(CandidType, Clone, Deserialize, Debug, PartialEq, Serialize)]
+pub struct FetchConfig {
+    // REMOTE_FETCH_GRANULARITY, the width of the fetched candles in seconds
+    pub granularity: u64,
+    // RATE_LIMIT_FACTOR, a job is fetched every that many heartbeats
+    pub rate_limit_factor: u64,
+    // DATA_POINTS_PER_API, the number of candles per job
+    pub data_points_per_api: u64,
+    // MAX_RESPONSE_BYTES, the response limit of Coinbase
+    pub max_response_bytes: u64,
+}
+
+impl Default for FetchConfig {
+    fn default() -> Self {
+        FetchConfig {
+            granularity: REMOTE_FETCH_GRANULARITY,
+            rate_limit_factor: RATE_LIMIT_FACTOR as u64,
+            data_points_per_api: DATA_POINTS_PER_API,
+            max_response_bytes: MAX_RESPONSE_BYTES,
+        }
+    }
+}
+
+impl FetchConfig {
+    // 每个任务覆盖的时间段
+    pub fn job_span(&self) -> u64 {
+        self.granularity * self.data_points_per_api
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !SUPPORTED_GRANULARITIES.contains(&self.granularity) {
+            return Err(format!(
+                "The granularity must be one of {:?}",
+                SUPPORTED_GRANULARITIES
+            ));
+        }
+        if self.rate_limit_factor == 0 {
+            return Err("The rate limit factor must be at least 1".to_string());
+        }
+        // Every source must be able to return a whole job in one call.
+        let max_data_points = Source::all()
+            .iter()
+            .map(|source| source.max_data_points())
+            .min()
+            .unwrap();
+        if self.data_points_per_api == 0 || self.data_points_per_api > max_data_points {
+            return Err(format!(
+                "The data points per call must be between 1 and {}",
+                max_data_points
+            ));
+        }
+        // See MAX_RESPONSE_BYTES for the size of a candle
+        let min_response_bytes = 10 * 6 * self.data_points_per_api;
+        if self.max_response_bytes < min_response_bytes
+            || self.max_response_bytes > MAX_HTTP_RESPONSE_BYTES
+        {
+            return Err(format!(
+                "The max response bytes must be between {} and {}",
+                min_response_bytes, MAX_HTTP_RESPONSE_BYTES
+            ));
+        }
+        Ok(())
+    }
+}
+
+thread_local! {
+    static CONFIG: RefCell<FetchConfig> = RefCell::new(FetchConfig::default());
+}
+
+pub fn get() -> FetchConfig {
+    CONFIG.with(|config| config.borrow().clone())
+}
+
+// The caller must make sure the granularity doesn't change while rates are stored.
+pub fn set(config: FetchConfig) -> Result<(), String> {
+    config.validate()?;
+    CONFIG.with(|c| *c.borrow_mut() = config);
+    Ok(())
+}
+
+pub fn granularity() -> u64 {
+    CONFIG.with(|config| config.borrow().granularity)
+}
+
+pub fn job_span() -> u64 {
+    CONFIG.with(|config| config.borrow().job_span())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(FetchConfig::default().validate(), Ok(()));
+
+        let valid = FetchConfig {
+            granularity: 300,
+            rate_limit_factor: 1,
+            data_points_per_api: 300,
+            max_response_bytes: 10 * 6 * 300,
+        };
+        assert_eq!(valid.validate(), Ok(()));
+        assert_eq!(valid.job_span(), 300 * 300);
+
+        for invalid in [
+            FetchConfig {
+                granularity: 120,
+                ..valid.clone()
+            },
+            FetchConfig {
+                rate_limit_factor: 0,
+                ..valid.clone()
+            },
+            // More than Coinbase returns per call
+            FetchConfig {
+                data_points_per_api: 301,
+                max_response_bytes: 10 * 6 * 301,
+                ..valid.clone()
+            },
+            FetchConfig {
+                max_response_bytes: 10 * 6 * 300 - 1,
+                ..valid.clone()
+            },
+            FetchConfig {
+                max_response_bytes: MAX_HTTP_RESPONSE_BYTES + 1,
+                ..valid.clone()
+            },
+        ] {
+            assert!(invalid.validate().is_err(), "{:?}", invalid);
+        }
+    }
+}
@@ -0,0 +1,271 @@
+use crate::source::{Pair, Source};
+use crate::Timestamp;
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+// http_request 的费用
+// The price of an outcall on a 13 node subnet: a base fee, plus a fee per byte of the request and
+// per byte of max_response_bytes. The response limit is charged in full, however small the actual
+// response is.
+pub const HTTP_REQUEST_BASE_FEE: u64 = 400_000_000;
+pub const HTTP_REQUEST_PER_BYTE_FEE: u64 = 100_000;
+
+// 预算按天计算
+// Budgets are reset at midnight UTC.
+pub const BUDGET_PERIOD: u64 = 24 * 60 * 60;
+
+// 估算一次 http_request 的费用
+pub fn outcall_cost(request_bytes: u64, max_response_bytes: u64) -> u64 {
+    HTTP_REQUEST_BASE_FEE + HTTP_REQUEST_PER_BYTE_FEE * (request_bytes + max_response_bytes)
+}
+
+// 周期预算
+#[derive(CandidType, Clone, Deserialize, Debug, PartialEq, Serialize)]
+pub struct CyclesBudget {
+    // At most this many cycles are spent on outcalls per day
+    pub daily: u64,
+    // No outcall is made that would leave the canister with less than this many cycles
+    pub min_balance: u64,
+}
+
+impl Default for CyclesBudget {
+    fn default() -> Self {
+        CyclesBudget {
+            daily: 500_000_000_000,         // 0.5T, about 45 jobs of Coinbase and Kraken
+            min_balance: 1_000_000_000_000, // 1T
+        }
+    }
+}
+
+impl CyclesBudget {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.daily < HTTP_REQUEST_BASE_FEE {
+            return Err(format!(
+                "The daily budget must allow at least one outcall of {} cycles",
+                HTTP_REQUEST_BASE_FEE
+            ));
+        }
+        Ok(())
+    }
+}
+
+// 暂停获取数据的原因
+#[derive(CandidType, Clone, Deserialize, Debug, PartialEq, Serialize)]
+pub enum PauseReason {
+    // Fetching resumes at the start of the next day
+    BudgetExhausted { resumes_at: Timestamp },
+    // Fetching resumes once the canister is topped up
+    LowBalance { balance: u64 },
+}
+
+#[derive(CandidType, Clone, Deserialize, Debug, PartialEq, Serialize)]
+pub struct CyclesSpent {
+    pub pair: Pair,
+    pub source: Source,
+    pub calls: u64,
+    pub cycles: u64,
+}
+
+#[derive(CandidType, Clone, Deserialize, Debug, PartialEq, Serialize)]
+pub struct CyclesMetrics {
+    pub budget: CyclesBudget,
+    pub day_start: Timestamp,
+    pub spent_today: u64,
+    // None while fetching
+    pub paused: Option<PauseReason>,
+    // Since the canister was installed
+    pub spent: Vec<CyclesSpent>,
+}
+
+// 升级时需要保存的周期统计
+#[derive(CandidType, Clone, Deserialize, Debug, Default)]
+pub struct CyclesState {
+    pub budget: CyclesBudget,
+    pub day_start: Timestamp,
+    pub spent_today: u64,
+    pub paused: Option<PauseReason>,
+    // (calls, cycles) per pair and source
+    pub spent: BTreeMap<(Pair, Source), (u64, u64)>,
+}
+
+impl CyclesState {
+    fn roll_day(&mut self, now: Timestamp) {
+        if now >= self.day_start + BUDGET_PERIOD {
+            self.day_start = now / BUDGET_PERIOD * BUDGET_PERIOD;
+            self.spent_today = 0;
+        }
+    }
+}
+
+thread_local! {
+    static CYCLES: RefCell<CyclesState> = RefCell::new(CyclesState::default());
+    // 已预留但调用尚未返回的周期，升级时不保存，因为调用不会跨越升级
+    static RESERVED: RefCell<u64> = RefCell::new(0);
+}
+
+pub fn save() -> CyclesState {
+    CYCLES.with(|cycles| cycles.borrow().clone())
+}
+
+pub fn restore(state: CyclesState) {
+    CYCLES.with(|cycles| *cycles.borrow_mut() = state);
+}
+
+pub fn set_budget(budget: CyclesBudget) -> Result<(), String> {
+    budget.validate()?;
+    CYCLES.with(|cycles| cycles.borrow_mut().budget = budget);
+    Ok(())
+}
+
+// 检查是否可以花费
+// Whether `cost` cycles can be spent now given the canister balance, on top of the ones reserved
+// for outcalls in flight. Fetching is paused for as long as this fails, and resumes by itself once
+// the day is over or the canister is topped up.
+pub fn check(cost: u64, balance: u64, now: Timestamp) -> Result<(), PauseReason> {
+    let reserved = RESERVED.with(|reserved| *reserved.borrow());
+    CYCLES.with(|cycles| {
+        let mut cycles = cycles.borrow_mut();
+        cycles.roll_day(now);
+        let result = if balance < cycles.budget.min_balance.saturating_add(cost) {
+            Err(PauseReason::LowBalance { balance })
+        } else if cycles.spent_today + reserved + cost > cycles.budget.daily {
+            Err(PauseReason::BudgetExhausted {
+                resumes_at: cycles.day_start + BUDGET_PERIOD,
+            })
+        } else {
+            Ok(())
+        };
+
+        let paused = result.clone().err();
+        if paused.is_some() != cycles.paused.is_some() {
+            match &paused {
+                Some(reason) => ic_cdk::api::print(format!("Pausing fetching: {:?}", reason)),
+                None => ic_cdk::api::print("Resuming fetching."),
+            }
+        }
+        cycles.paused = paused;
+        result
+    })
+}
+
+// 预留周期
+// Count the estimated cost of an outcall against the budget until it returns and is settled, so
+// that jobs fetched concurrently can't overspend it between them.
+pub fn reserve(cost: u64) {
+    RESERVED.with(|reserved| *reserved.borrow_mut() += cost);
+}
+
+// 结算预留的周期
+// Release the `reserved` cycles of an outcall that returned, and record what it actually `spent`.
+pub fn settle(pair: &Pair, source: Source, reserved: u64, spent: u64, now: Timestamp) {
+    RESERVED.with(|held| {
+        let mut held = held.borrow_mut();
+        *held = held.saturating_sub(reserved);
+    });
+    record(pair, source, spent, now);
+}
+
+// 记录花费的周期
+pub fn record(pair: &Pair, source: Source, spent: u64, now: Timestamp) {
+    CYCLES.with(|cycles| {
+        let mut cycles = cycles.borrow_mut();
+        cycles.roll_day(now);
+        cycles.spent_today += spent;
+        let (calls, total) = cycles.spent.entry((pair.clone(), source)).or_default();
+        *calls += 1;
+        *total += spent;
+    });
+}
+
+pub fn metrics(now: Timestamp) -> CyclesMetrics {
+    CYCLES.with(|cycles| {
+        let mut cycles = cycles.borrow_mut();
+        cycles.roll_day(now);
+        CyclesMetrics {
+            budget: cycles.budget.clone(),
+            day_start: cycles.day_start,
+            spent_today: cycles.spent_today,
+            paused: cycles.paused.clone(),
+            spent: cycles
+                .spent
+                .iter()
+                .map(|((pair, source), (calls, total))| CyclesSpent {
+                    pair: pair.clone(),
+                    source: *source,
+                    calls: *calls,
+                    cycles: *total,
+                })
+                .collect(),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fetching_pauses_and_resumes() {
+        set_budget(CyclesBudget {
+            daily: 3 * HTTP_REQUEST_BASE_FEE,
+            min_balance: 1_000,
+        })
+        .unwrap();
+        let day = 19_000 * BUDGET_PERIOD;
+        let balance = u64::MAX;
+
+        assert_eq!(check(HTTP_REQUEST_BASE_FEE, balance, day), Ok(()));
+        record(
+            &Pair::icp_usd(),
+            Source::Coinbase,
+            HTTP_REQUEST_BASE_FEE,
+            day,
+        );
+        record(&Pair::icp_usd(), Source::Kraken, HTTP_REQUEST_BASE_FEE, day);
+        assert_eq!(check(HTTP_REQUEST_BASE_FEE, balance, day + 10), Ok(()));
+        // The outcall in flight is counted until it is settled
+        reserve(HTTP_REQUEST_BASE_FEE);
+        assert!(check(1, balance, day + 10).is_err());
+        settle(
+            &Pair::icp_usd(),
+            Source::Coinbase,
+            HTTP_REQUEST_BASE_FEE,
+            HTTP_REQUEST_BASE_FEE,
+            day + 10,
+        );
+
+        // The budget of the day is spent
+        let paused = PauseReason::BudgetExhausted {
+            resumes_at: day + BUDGET_PERIOD,
+        };
+        assert_eq!(check(1, balance, day + 20), Err(paused.clone()));
+        assert_eq!(metrics(day + 20).paused, Some(paused));
+
+        // The balance is checked as well
+        let low = HTTP_REQUEST_BASE_FEE + 999;
+        assert_eq!(
+            check(HTTP_REQUEST_BASE_FEE, low, day + BUDGET_PERIOD),
+            Err(PauseReason::LowBalance { balance: low })
+        );
+
+        // The next day, after a top up
+        assert_eq!(
+            check(HTTP_REQUEST_BASE_FEE, balance, day + BUDGET_PERIOD),
+            Ok(())
+        );
+        let metrics = metrics(day + BUDGET_PERIOD);
+        assert_eq!(metrics.paused, None);
+        assert_eq!(metrics.spent_today, 0);
+        assert_eq!(
+            metrics.spent[0],
+            CyclesSpent {
+                pair: Pair::icp_usd(),
+                source: Source::Coinbase,
+                calls: 2,
+                cycles: 2 * HTTP_REQUEST_BASE_FEE,
+            }
+        );
+    }
+}
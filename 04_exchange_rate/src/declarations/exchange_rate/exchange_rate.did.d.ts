@@ -1,12 +1,163 @@
 import type { Principal } from '@dfinity/principal';
 import type { ActorMethod } from '@dfinity/agent';
 
+export interface AggregationConfig {
+  'sources' : Array<Source>,
+  'min_sources' : number,
+  'max_deviation' : number,
+}
+export interface Backfill {
+  'id' : bigint,
+  'pair' : Pair,
+  'range' : TimeRange,
+  'total_jobs' : bigint,
+  'completed_jobs' : bigint,
+  'failed_jobs' : bigint,
+  'in_flight' : BigUint64Array,
+  'cursor' : bigint,
+  'started_at' : bigint,
+  'finished_at' : [] | [bigint],
+}
+export interface Candle {
+  'pair' : Pair,
+  'timestamp' : bigint,
+  'rate' : number,
+  'volume' : number,
+  'confidence' : RateConfidence,
+}
+export interface CyclesBudget { 'daily' : bigint, 'min_balance' : bigint }
+export interface CyclesMetrics {
+  'budget' : CyclesBudget,
+  'day_start' : bigint,
+  'spent_today' : bigint,
+  'paused' : [] | [PauseReason],
+  'spent' : Array<CyclesSpent>,
+}
+export interface CyclesSpent {
+  'pair' : Pair,
+  'source' : Source,
+  'calls' : bigint,
+  'cycles' : bigint,
+}
+export interface DataQuality {
+  'interval' : bigint,
+  'buckets' : bigint,
+  'covered' : bigint,
+  'coverage_percent' : number,
+  'gaps' : Array<TimeRange>,
+  'missing' : Array<TimeRange>,
+}
+export interface FetchConfig {
+  'granularity' : bigint,
+  'rate_limit_factor' : bigint,
+  'data_points_per_api' : bigint,
+  'max_response_bytes' : bigint,
+}
+export interface HttpErrorCount {
+  'source' : Source,
+  'status' : bigint,
+  'count' : bigint,
+  'last_seen' : bigint,
+}
+export interface HttpRequest {
+  'method' : string,
+  'url' : string,
+  'headers' : Array<[string, string]>,
+  'body' : Uint8Array,
+}
+export interface HttpResponse {
+  'status_code' : number,
+  'headers' : Array<[string, string]>,
+  'body' : Uint8Array,
+}
+export type JobState = { 'Unknown' : null } |
+  {
+    'Pending' : {
+      'attempts' : number,
+      'next_attempt' : bigint,
+      'last_error' : [] | [string],
+    }
+  } |
+  { 'Failed' : { 'attempts' : number, 'last_error' : [] | [string] } } |
+  { 'Unavailable' : { 'completed_at' : bigint, 'missing' : bigint } } |
+  { 'Complete' : { 'completed_at' : bigint } };
+export interface JobStatus { 'job' : bigint, 'state' : JobState }
+export interface LatestRate { 'candle' : Candle, 'staleness' : bigint }
+export interface Pair { 'base' : string, 'quote' : string }
+export type PauseReason = { 'BudgetExhausted' : { 'resumes_at' : bigint } } |
+  { 'LowBalance' : { 'balance' : bigint } };
+export interface RateConfidence {
+  'sources' : Array<Source>,
+  'outliers' : Array<Source>,
+  'spread' : number,
+}
+export interface RateStats {
+  'interval' : bigint,
+  'points' : bigint,
+  'open' : [bigint, number],
+  'close' : [bigint, number],
+  'change_percent' : number,
+  'min' : [bigint, number],
+  'max' : [bigint, number],
+  'twap' : number,
+  'vwap' : [] | [number],
+  'volume' : number,
+  'volatility' : number,
+}
 export interface RatesWithInterval {
   'interval' : bigint,
   'rates' : Array<[bigint, number]>,
+  'confidence' : Array<[bigint, RateConfidence]>,
+  'missing' : Array<TimeRange>,
+}
+export type Result = { 'Ok' : null } |
+  { 'Err' : string };
+export type Result_1 = { 'Ok' : bigint } |
+  { 'Err' : string };
+export interface RetentionPolicy {
+  'minute' : [] | [bigint],
+  'hour' : [] | [bigint],
+  'day' : [] | [bigint],
+}
+export type SampleMode = { 'Average' : null } |
+  { 'Last' : null };
+export interface SampleOptions {
+  'mode' : SampleMode,
+  'max_points' : [] | [bigint],
+  'forward_fill' : [] | [boolean],
+}
+export type Source = { 'Coinbase' : null } |
+  { 'Binance' : null } |
+  { 'Kraken' : null };
+export interface Subscription {
+  'subscriber' : Principal,
+  'pair' : Pair,
+  'method' : string,
 }
 export interface TimeRange { 'end' : bigint, 'start' : bigint }
 export interface _SERVICE {
-  'get_rates' : ActorMethod<[TimeRange], RatesWithInterval>,
+  'data_quality' : ActorMethod<[TimeRange], DataQuality>,
+  'get_aggregation_config' : ActorMethod<[], AggregationConfig>,
+  'get_backfills' : ActorMethod<[], Array<Backfill>>,
+  'get_cycles_metrics' : ActorMethod<[], CyclesMetrics>,
+  'get_fetch_config' : ActorMethod<[], FetchConfig>,
+  'get_http_errors' : ActorMethod<[], Array<HttpErrorCount>>,
+  'get_job_status' : ActorMethod<[TimeRange], Array<JobStatus>>,
+  'get_rate_stats' : ActorMethod<[TimeRange], [] | [RateStats]>,
+  'get_rates' : ActorMethod<[TimeRange, [] | [SampleOptions]], RatesWithInterval>,
   'get_rates2' : ActorMethod<[], string>,
+  'get_retention_policy' : ActorMethod<[], RetentionPolicy>,
+  'get_tracked_pairs' : ActorMethod<[], Array<Pair>>,
+  'http_request' : ActorMethod<[HttpRequest], HttpResponse>,
+  'latest_rate' : ActorMethod<[Pair], [] | [LatestRate]>,
+  'my_subscriptions' : ActorMethod<[], Array<Subscription>>,
+  'request_backfill' : ActorMethod<[TimeRange], Result_1>,
+  'set_aggregation_config' : ActorMethod<[AggregationConfig], Result>,
+  'set_cycles_budget' : ActorMethod<[CyclesBudget], Result>,
+  'set_fetch_config' : ActorMethod<[FetchConfig], Result>,
+  'set_retention_policy' : ActorMethod<[RetentionPolicy], Result>,
+  'set_tracked_pairs' : ActorMethod<[Array<Pair>], Result>,
+  'start_backfill' : ActorMethod<[Pair, TimeRange], Result_1>,
+  'subscribe' : ActorMethod<[Pair, string], Result>,
+  'unsubscribe' : ActorMethod<[Pair], Result>,
 }
@@ -1,12 +1,180 @@
 export const idlFactory = ({ IDL }) => {
   const TimeRange = IDL.Record({ 'end' : IDL.Nat64, 'start' : IDL.Nat64 });
+  const DataQuality = IDL.Record({
+    'interval' : IDL.Nat64,
+    'buckets' : IDL.Nat64,
+    'covered' : IDL.Nat64,
+    'coverage_percent' : IDL.Float32,
+    'gaps' : IDL.Vec(TimeRange),
+    'missing' : IDL.Vec(TimeRange),
+  });
+  const Source = IDL.Variant({
+    'Coinbase' : IDL.Null,
+    'Binance' : IDL.Null,
+    'Kraken' : IDL.Null,
+  });
+  const AggregationConfig = IDL.Record({
+    'sources' : IDL.Vec(Source),
+    'min_sources' : IDL.Nat32,
+    'max_deviation' : IDL.Float32,
+  });
+  const Pair = IDL.Record({ 'base' : IDL.Text, 'quote' : IDL.Text });
+  const Backfill = IDL.Record({
+    'id' : IDL.Nat64,
+    'pair' : Pair,
+    'range' : TimeRange,
+    'total_jobs' : IDL.Nat64,
+    'completed_jobs' : IDL.Nat64,
+    'failed_jobs' : IDL.Nat64,
+    'in_flight' : IDL.Vec(IDL.Nat64),
+    'cursor' : IDL.Nat64,
+    'started_at' : IDL.Nat64,
+    'finished_at' : IDL.Opt(IDL.Nat64),
+  });
+  const CyclesBudget = IDL.Record({
+    'daily' : IDL.Nat64,
+    'min_balance' : IDL.Nat64,
+  });
+  const PauseReason = IDL.Variant({
+    'BudgetExhausted' : IDL.Record({ 'resumes_at' : IDL.Nat64 }),
+    'LowBalance' : IDL.Record({ 'balance' : IDL.Nat64 }),
+  });
+  const CyclesSpent = IDL.Record({
+    'pair' : Pair,
+    'source' : Source,
+    'calls' : IDL.Nat64,
+    'cycles' : IDL.Nat64,
+  });
+  const CyclesMetrics = IDL.Record({
+    'budget' : CyclesBudget,
+    'day_start' : IDL.Nat64,
+    'spent_today' : IDL.Nat64,
+    'paused' : IDL.Opt(PauseReason),
+    'spent' : IDL.Vec(CyclesSpent),
+  });
+  const FetchConfig = IDL.Record({
+    'granularity' : IDL.Nat64,
+    'rate_limit_factor' : IDL.Nat64,
+    'data_points_per_api' : IDL.Nat64,
+    'max_response_bytes' : IDL.Nat64,
+  });
+  const HttpErrorCount = IDL.Record({
+    'source' : Source,
+    'status' : IDL.Nat64,
+    'count' : IDL.Nat64,
+    'last_seen' : IDL.Nat64,
+  });
+  const JobState = IDL.Variant({
+    'Unknown' : IDL.Null,
+    'Pending' : IDL.Record({
+      'attempts' : IDL.Nat32,
+      'next_attempt' : IDL.Nat64,
+      'last_error' : IDL.Opt(IDL.Text),
+    }),
+    'Failed' : IDL.Record({
+      'attempts' : IDL.Nat32,
+      'last_error' : IDL.Opt(IDL.Text),
+    }),
+    'Unavailable' : IDL.Record({
+      'completed_at' : IDL.Nat64,
+      'missing' : IDL.Nat64,
+    }),
+    'Complete' : IDL.Record({ 'completed_at' : IDL.Nat64 }),
+  });
+  const JobStatus = IDL.Record({ 'job' : IDL.Nat64, 'state' : JobState });
+  const RateStats = IDL.Record({
+    'interval' : IDL.Nat64,
+    'points' : IDL.Nat64,
+    'open' : IDL.Tuple(IDL.Nat64, IDL.Float32),
+    'close' : IDL.Tuple(IDL.Nat64, IDL.Float32),
+    'change_percent' : IDL.Float32,
+    'min' : IDL.Tuple(IDL.Nat64, IDL.Float32),
+    'max' : IDL.Tuple(IDL.Nat64, IDL.Float32),
+    'twap' : IDL.Float32,
+    'vwap' : IDL.Opt(IDL.Float32),
+    'volume' : IDL.Float64,
+    'volatility' : IDL.Float64,
+  });
+  const SampleMode = IDL.Variant({ 'Average' : IDL.Null, 'Last' : IDL.Null });
+  const SampleOptions = IDL.Record({
+    'mode' : SampleMode,
+    'max_points' : IDL.Opt(IDL.Nat64),
+    'forward_fill' : IDL.Opt(IDL.Bool),
+  });
+  const RateConfidence = IDL.Record({
+    'sources' : IDL.Vec(Source),
+    'outliers' : IDL.Vec(Source),
+    'spread' : IDL.Float32,
+  });
   const RatesWithInterval = IDL.Record({
     'interval' : IDL.Nat64,
     'rates' : IDL.Vec(IDL.Tuple(IDL.Nat64, IDL.Float32)),
+    'confidence' : IDL.Vec(IDL.Tuple(IDL.Nat64, RateConfidence)),
+    'missing' : IDL.Vec(TimeRange),
+  });
+  const RetentionPolicy = IDL.Record({
+    'minute' : IDL.Opt(IDL.Nat64),
+    'hour' : IDL.Opt(IDL.Nat64),
+    'day' : IDL.Opt(IDL.Nat64),
+  });
+  const HttpRequest = IDL.Record({
+    'method' : IDL.Text,
+    'url' : IDL.Text,
+    'headers' : IDL.Vec(IDL.Tuple(IDL.Text, IDL.Text)),
+    'body' : IDL.Vec(IDL.Nat8),
+  });
+  const HttpResponse = IDL.Record({
+    'status_code' : IDL.Nat16,
+    'headers' : IDL.Vec(IDL.Tuple(IDL.Text, IDL.Text)),
+    'body' : IDL.Vec(IDL.Nat8),
+  });
+  const Candle = IDL.Record({
+    'pair' : Pair,
+    'timestamp' : IDL.Nat64,
+    'rate' : IDL.Float32,
+    'volume' : IDL.Float64,
+    'confidence' : RateConfidence,
+  });
+  const LatestRate = IDL.Record({
+    'candle' : Candle,
+    'staleness' : IDL.Nat64,
+  });
+  const Subscription = IDL.Record({
+    'subscriber' : IDL.Principal,
+    'pair' : Pair,
+    'method' : IDL.Text,
   });
+  const Result_1 = IDL.Variant({ 'Ok' : IDL.Nat64, 'Err' : IDL.Text });
+  const Result = IDL.Variant({ 'Ok' : IDL.Null, 'Err' : IDL.Text });
   return IDL.Service({
-    'get_rates' : IDL.Func([TimeRange], [RatesWithInterval], []),
+    'data_quality' : IDL.Func([TimeRange], [DataQuality], ['query']),
+    'get_aggregation_config' : IDL.Func([], [AggregationConfig], ['query']),
+    'get_backfills' : IDL.Func([], [IDL.Vec(Backfill)], ['query']),
+    'get_cycles_metrics' : IDL.Func([], [CyclesMetrics], ['query']),
+    'get_fetch_config' : IDL.Func([], [FetchConfig], ['query']),
+    'get_http_errors' : IDL.Func([], [IDL.Vec(HttpErrorCount)], ['query']),
+    'get_job_status' : IDL.Func([TimeRange], [IDL.Vec(JobStatus)], ['query']),
+    'get_rate_stats' : IDL.Func([TimeRange], [IDL.Opt(RateStats)], ['query']),
+    'get_rates' : IDL.Func(
+        [TimeRange, IDL.Opt(SampleOptions)],
+        [RatesWithInterval],
+        ['query'],
+      ),
     'get_rates2' : IDL.Func([], [IDL.Text], []),
+    'get_retention_policy' : IDL.Func([], [RetentionPolicy], ['query']),
+    'get_tracked_pairs' : IDL.Func([], [IDL.Vec(Pair)], ['query']),
+    'http_request' : IDL.Func([HttpRequest], [HttpResponse], ['query']),
+    'latest_rate' : IDL.Func([Pair], [IDL.Opt(LatestRate)], ['query']),
+    'my_subscriptions' : IDL.Func([], [IDL.Vec(Subscription)], ['query']),
+    'request_backfill' : IDL.Func([TimeRange], [Result_1], []),
+    'set_aggregation_config' : IDL.Func([AggregationConfig], [Result], []),
+    'set_cycles_budget' : IDL.Func([CyclesBudget], [Result], []),
+    'set_fetch_config' : IDL.Func([FetchConfig], [Result], []),
+    'set_retention_policy' : IDL.Func([RetentionPolicy], [Result], []),
+    'set_tracked_pairs' : IDL.Func([IDL.Vec(Pair)], [Result], []),
+    'start_backfill' : IDL.Func([Pair, TimeRange], [Result_1], []),
+    'subscribe' : IDL.Func([Pair, IDL.Text], [Result], []),
+    'unsubscribe' : IDL.Func([Pair], [Result], []),
   });
 };
 export const init = ({ IDL }) => { return []; };
@@ -0,0 +1,424 @@
+use crate::aggregate::RateConfidence;
+use crate::source::Pair;
+use crate::{config, Rate, Timestamp, Volume};
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+
+// 订阅数量上限
+pub const MAX_SUBSCRIPTIONS: usize = 100;
+
+// 每个订阅者的订阅数量上限
+// A single subscriber can hold at most this many of the MAX_SUBSCRIPTIONS, one per pair, so that
+// it can't lock the others out.
+pub const MAX_SUBSCRIPTIONS_PER_SUBSCRIBER: usize = 5;
+
+// 回调方法名的最大长度
+pub const MAX_METHOD_LEN: usize = 64;
+
+// 待推送的数据上限，超出时丢弃最旧的
+// At most this many deliveries are queued. When a subscriber falls too far behind, the oldest
+// deliveries are dropped first.
+pub const MAX_PENDING_DELIVERIES: usize = 10_000;
+
+// 每个订阅者待推送的数据上限
+// A single subscriber can have at most this many deliveries queued, so that one that keeps
+// failing doesn't push the others' deliveries out of the queue. Its oldest ones are dropped first.
+pub const MAX_PENDING_PER_SUBSCRIBER: usize = 100;
+
+// 每次心跳最多推送多少条
+pub const DELIVERIES_PER_HEARTBEAT: usize = 20;
+
+// 推送失败的重试
+// A failed delivery is retried after DELIVERY_BACKOFF_BASE seconds, doubled after every failure,
+// and dropped after DELIVERY_MAX_ATTEMPTS attempts.
+pub const DELIVERY_MAX_ATTEMPTS: u32 = 5;
+pub const DELIVERY_BACKOFF_BASE: u64 = 10;
+
+// 一分钟的数据
+// A finalized minute of a pair, as pushed to the subscribers.
+#[derive(CandidType, Clone, Deserialize, Debug, PartialEq, Serialize)]
+pub struct Candle {
+    pub pair: Pair,
+    // The start of the minute
+    pub timestamp: Timestamp,
+    pub rate: Rate,
+    // Traded during the minute, in the base currency, summed over the sources
+    pub volume: Volume,
+    pub confidence: RateConfidence,
+}
+
+// 订阅
+// The subscriber canister is called with `method(Candle)` for every new minute of the pair.
+#[derive(CandidType, Clone, Deserialize, Debug, PartialEq, Serialize)]
+pub struct Subscription {
+    pub subscriber: Principal,
+    pub pair: Pair,
+    pub method: String,
+}
+
+#[derive(CandidType, Clone, Deserialize, Debug, PartialEq, Serialize)]
+pub struct Delivery {
+    pub subscriber: Principal,
+    pub method: String,
+    pub candle: Candle,
+    pub attempts: u32,
+    pub next_attempt: Timestamp,
+}
+
+#[derive(CandidType, Clone, Deserialize, Debug, PartialEq, Serialize)]
+pub struct LatestRate {
+    pub candle: Candle,
+    // Seconds since the end of the minute
+    pub staleness: u64,
+}
+
+// 升级时需要保存的推送状态
+#[derive(CandidType, Clone, Deserialize, Debug, Default)]
+pub struct FeedState {
+    pub subscriptions: Vec<Subscription>,
+    pub deliveries: VecDeque<Delivery>,
+    pub latest: BTreeMap<Pair, Candle>,
+}
+
+impl FeedState {
+    fn pending_for(&self, subscriber: Principal) -> usize {
+        self.deliveries
+            .iter()
+            .filter(|d| d.subscriber == subscriber)
+            .count()
+    }
+}
+
+thread_local! {
+    static FEED: RefCell<FeedState> = RefCell::new(FeedState::default());
+}
+
+pub fn save() -> FeedState {
+    FEED.with(|feed| feed.borrow().clone())
+}
+
+pub fn restore(state: FeedState) {
+    FEED.with(|feed| *feed.borrow_mut() = state);
+}
+
+// 订阅，重复订阅时更新回调方法
+pub fn subscribe(subscription: Subscription) -> Result<(), String> {
+    // Canister ids are opaque principals, whose class byte is 0x01. Only canisters can be called
+    // back, and a new one costs cycles to create, unlike a new self-authenticating principal.
+    if subscription.subscriber.as_slice().last() != Some(&0x01) {
+        return Err("Only canisters can subscribe".to_string());
+    }
+    if subscription.method.is_empty() || subscription.method.len() > MAX_METHOD_LEN {
+        return Err(format!(
+            "The callback method must be between 1 and {} bytes long",
+            MAX_METHOD_LEN
+        ));
+    }
+    FEED.with(|feed| {
+        let subscriptions = &mut feed.borrow_mut().subscriptions;
+        match subscriptions
+            .iter_mut()
+            .find(|s| s.subscriber == subscription.subscriber && s.pair == subscription.pair)
+        {
+            Some(existing) => existing.method = subscription.method,
+            None => {
+                let own = subscriptions
+                    .iter()
+                    .filter(|s| s.subscriber == subscription.subscriber)
+                    .count();
+                if own >= MAX_SUBSCRIPTIONS_PER_SUBSCRIBER {
+                    return Err(format!(
+                        "A subscriber can hold at most {} subscriptions",
+                        MAX_SUBSCRIPTIONS_PER_SUBSCRIBER
+                    ));
+                }
+                if subscriptions.len() >= MAX_SUBSCRIPTIONS {
+                    return Err("Too many subscriptions".to_string());
+                }
+                subscriptions.push(subscription);
+            }
+        }
+        Ok(())
+    })
+}
+
+// 取消订阅，未推送的数据也一并删除
+pub fn unsubscribe(subscriber: Principal, pair: &Pair) -> Result<(), String> {
+    FEED.with(|feed| {
+        let mut feed = feed.borrow_mut();
+        let before = feed.subscriptions.len();
+        feed.subscriptions
+            .retain(|s| !(s.subscriber == subscriber && &s.pair == pair));
+        if feed.subscriptions.len() == before {
+            return Err("No such subscription".to_string());
+        }
+        feed.deliveries
+            .retain(|d| !(d.subscriber == subscriber && &d.candle.pair == pair));
+        Ok(())
+    })
+}
+
+pub fn subscriptions_of(subscriber: Principal) -> Vec<Subscription> {
+    FEED.with(|feed| {
+        feed.borrow()
+            .subscriptions
+            .iter()
+            .filter(|s| s.subscriber == subscriber)
+            .cloned()
+            .collect()
+    })
+}
+
+// 发布新的一分钟数据
+// Only minutes newer than the latest published one are pushed, so that backfilling the history
+// doesn't flood the subscribers. Minutes must be published in ascending order.
+pub fn publish(candle: Candle, now: Timestamp) {
+    FEED.with(|feed| {
+        let mut feed = feed.borrow_mut();
+        if let Some(latest) = feed.latest.get(&candle.pair) {
+            if latest.timestamp >= candle.timestamp {
+                return;
+            }
+        }
+        let deliveries: Vec<_> = feed
+            .subscriptions
+            .iter()
+            .filter(|s| s.pair == candle.pair)
+            .map(|s| Delivery {
+                subscriber: s.subscriber,
+                method: s.method.clone(),
+                candle: candle.clone(),
+                attempts: 0,
+                next_attempt: now,
+            })
+            .collect();
+        for delivery in deliveries {
+            let pending = feed.pending_for(delivery.subscriber);
+            if pending >= MAX_PENDING_PER_SUBSCRIBER {
+                if let Some(oldest) = feed
+                    .deliveries
+                    .iter()
+                    .position(|d| d.subscriber == delivery.subscriber)
+                {
+                    feed.deliveries.remove(oldest);
+                }
+            } else if feed.deliveries.len() >= MAX_PENDING_DELIVERIES {
+                feed.deliveries.pop_front();
+            }
+            feed.deliveries.push_back(delivery);
+        }
+        feed.latest.insert(candle.pair.clone(), candle);
+    });
+}
+
+pub fn latest(pair: &Pair, now: Timestamp) -> Option<LatestRate> {
+    FEED.with(|feed| {
+        feed.borrow().latest.get(pair).map(|candle| LatestRate {
+            candle: candle.clone(),
+            staleness: now.saturating_sub(candle.timestamp + config::granularity()),
+        })
+    })
+}
+
+// 取出到期的推送
+// Remove and return at most `limit` deliveries that are due, oldest first. The caller reports
+// failed ones back with `retry`.
+pub fn take_due(now: Timestamp, limit: usize) -> Vec<Delivery> {
+    FEED.with(|feed| {
+        let mut feed = feed.borrow_mut();
+        let mut due = vec![];
+        let mut rest = VecDeque::new();
+        while let Some(delivery) = feed.deliveries.pop_front() {
+            if due.len() < limit && delivery.next_attempt <= now {
+                due.push(delivery);
+            } else {
+                rest.push_back(delivery);
+            }
+        }
+        feed.deliveries = rest;
+        due
+    })
+}
+
+// 推送失败，稍后重试
+// Queue the delivery again with exponential backoff, unless it has failed too many times or the
+// subscription was cancelled in the meantime. Returns whether it was queued again.
+pub fn retry(mut delivery: Delivery, now: Timestamp) -> bool {
+    delivery.attempts += 1;
+    if delivery.attempts >= DELIVERY_MAX_ATTEMPTS {
+        return false;
+    }
+    delivery.next_attempt = now + DELIVERY_BACKOFF_BASE * (1 << (delivery.attempts - 1));
+    FEED.with(|feed| {
+        let mut feed = feed.borrow_mut();
+        let subscribed = feed
+            .subscriptions
+            .iter()
+            .any(|s| s.subscriber == delivery.subscriber && s.pair == delivery.candle.pair);
+        if subscribed
+            && feed.deliveries.len() < MAX_PENDING_DELIVERIES
+            && feed.pending_for(delivery.subscriber) < MAX_PENDING_PER_SUBSCRIBER
+        {
+            feed.deliveries.push_back(delivery);
+            true
+        } else {
+            false
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aggregate::test_rate;
+
+    fn candle(pair: Pair, timestamp: Timestamp) -> Candle {
+        let rate = test_rate(1.0, 0.0);
+        Candle {
+            pair,
+            timestamp,
+            rate: rate.rate,
+            volume: rate.volume,
+            confidence: rate.confidence,
+        }
+    }
+
+    fn subscriber(id: u8) -> Principal {
+        Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, id, 1, 1])
+    }
+
+    fn pair(base: &str) -> Pair {
+        Pair::new(base.to_string(), "USD".to_string()).unwrap()
+    }
+
+    #[test]
+    fn test_publish_only_pushes_new_minutes_to_subscribers_of_the_pair() {
+        let btc = Pair {
+            base: "BTC".to_string(),
+            quote: "USD".to_string(),
+        };
+        for (id, pair) in [(1, Pair::icp_usd()), (2, btc.clone())] {
+            subscribe(Subscription {
+                subscriber: subscriber(id),
+                pair,
+                method: "on_candle".to_string(),
+            })
+            .unwrap();
+        }
+
+        publish(candle(Pair::icp_usd(), 120), 200);
+        // Older than the latest one, e.g. from a backfill
+        publish(candle(Pair::icp_usd(), 60), 200);
+
+        let due = take_due(200, 10);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].subscriber, subscriber(1));
+        assert_eq!(due[0].candle.timestamp, 120);
+
+        let latest = latest(&Pair::icp_usd(), 200).unwrap();
+        assert_eq!(latest.candle.timestamp, 120);
+        assert_eq!(latest.staleness, 20);
+        assert_eq!(super::latest(&btc, 200), None);
+    }
+
+    #[test]
+    fn test_failed_deliveries_are_retried_then_dropped() {
+        subscribe(Subscription {
+            subscriber: subscriber(1),
+            pair: Pair::icp_usd(),
+            method: "on_candle".to_string(),
+        })
+        .unwrap();
+        publish(candle(Pair::icp_usd(), 0), 100);
+
+        let mut now = 100;
+        let mut delivery = take_due(now, 10).pop().unwrap();
+        for attempt in 1..DELIVERY_MAX_ATTEMPTS {
+            assert!(retry(delivery, now));
+            let backoff = DELIVERY_BACKOFF_BASE * (1 << (attempt - 1));
+            assert!(take_due(now + backoff - 1, 10).is_empty());
+            now += backoff;
+            delivery = take_due(now, 10).pop().unwrap();
+            assert_eq!(delivery.attempts, attempt);
+        }
+        assert!(!retry(delivery, now));
+        assert!(take_due(u64::MAX, 10).is_empty());
+
+        // Cancelled subscriptions aren't retried
+        publish(candle(Pair::icp_usd(), 60), now);
+        let delivery = take_due(now, 10).pop().unwrap();
+        unsubscribe(subscriber(1), &Pair::icp_usd()).unwrap();
+        assert!(!retry(delivery, now));
+    }
+
+    #[test]
+    fn test_pending_deliveries_are_capped_per_subscriber() {
+        for id in [1, 2] {
+            subscribe(Subscription {
+                subscriber: subscriber(id),
+                pair: Pair::icp_usd(),
+                method: "on_candle".to_string(),
+            })
+            .unwrap();
+        }
+        let minutes = MAX_PENDING_PER_SUBSCRIBER as u64 + 5;
+        for minute in 0..minutes {
+            publish(candle(Pair::icp_usd(), minute * 60), 100);
+        }
+
+        let due = take_due(100, usize::MAX);
+        assert_eq!(due.len(), 2 * MAX_PENDING_PER_SUBSCRIBER);
+        for id in [1, 2] {
+            let delivered: Vec<_> = due
+                .iter()
+                .filter(|d| d.subscriber == subscriber(id))
+                .map(|d| d.candle.timestamp)
+                .collect();
+            // The oldest minutes were dropped
+            assert_eq!(delivered.len(), MAX_PENDING_PER_SUBSCRIBER);
+            assert_eq!(delivered[0], 5 * 60);
+        }
+    }
+
+    #[test]
+    fn test_subscriptions_are_capped_per_subscriber() {
+        let subscription = |id, pair| Subscription {
+            subscriber: subscriber(id),
+            pair,
+            method: "on_candle".to_string(),
+        };
+        // A self-authenticating principal, as a user gets from a new key
+        let user = Principal::from_slice(&[0xab, 0xcd, 0xef, 2]);
+        assert!(subscribe(Subscription {
+            subscriber: user,
+            ..subscription(1, Pair::icp_usd())
+        })
+        .is_err());
+        assert!(subscribe(Subscription {
+            subscriber: Principal::anonymous(),
+            ..subscription(1, Pair::icp_usd())
+        })
+        .is_err());
+
+        let bases = ["A", "B", "C", "D", "E", "F"];
+        for base in &bases[..MAX_SUBSCRIPTIONS_PER_SUBSCRIBER] {
+            subscribe(subscription(1, pair(base))).unwrap();
+        }
+        let sixth = pair(bases[MAX_SUBSCRIPTIONS_PER_SUBSCRIBER]);
+        assert!(subscribe(subscription(1, sixth.clone())).is_err());
+        // Changing the method of an existing subscription is still allowed
+        subscribe(Subscription {
+            method: "on_rate".to_string(),
+            ..subscription(1, pair("A"))
+        })
+        .unwrap();
+        // The others can still subscribe
+        subscribe(subscription(2, sixth)).unwrap();
+        assert_eq!(
+            subscriptions_of(subscriber(1)).len(),
+            MAX_SUBSCRIPTIONS_PER_SUBSCRIBER
+        );
+    }
+}
@@ -0,0 +1,312 @@
+use crate::sampling::SampleOptions;
+use crate::source::Pair;
+use crate::{config, sampled_rates, RatesWithInterval, TimeRange, Timestamp};
+use candid::CandidType;
+use ic_certified_map::{AsHashTree, Hash, RbTree};
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use serde_cbor::Serializer;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+// 最近时间段的长度
+// `/rates` and `/rates.csv` without a range serve the last RECENT_WINDOW seconds, and those
+// responses are certified.
+pub const RECENT_WINDOW: u64 = 24 * 60 * 60;
+
+// 缓存时间
+// Responses covering the last HISTORY_AFTER seconds, or with missing data, may still change and
+// are only cached for RECENT_MAX_AGE seconds. Complete ranges further back are cached for a day.
+pub const RECENT_MAX_AGE: u64 = 60;
+pub const HISTORY_MAX_AGE: u64 = 24 * 60 * 60;
+pub const HISTORY_AFTER: u64 = 60 * 60;
+
+// http 请求的结构体
+#[derive(CandidType, Deserialize)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+// http 响应的结构体
+#[derive(CandidType, Deserialize)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    Json,
+    Csv,
+}
+
+impl Format {
+    fn content_type(&self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Csv => "text/csv; charset=utf-8",
+        }
+    }
+}
+
+thread_local! {
+    // 认证过的响应的 hash
+    static HASHES: RefCell<RbTree<String, Hash>> = RefCell::new(RbTree::new());
+    // 最近时间段的响应
+    static RECENT: RefCell<HashMap<String, Vec<u8>>> = RefCell::new(HashMap::new());
+}
+
+// 最近时间段的请求
+fn recent_range(now: Timestamp) -> TimeRange {
+    TimeRange {
+        start: now.saturating_sub(RECENT_WINDOW),
+        end: now,
+    }
+}
+
+// 更新最近时间段的认证数据
+// Render the responses for the most recent window, and certify them. Must be called from an
+// update, e.g. after new rates were stored.
+pub fn certify_recent(now: Timestamp) {
+    let rates = sampled_rates(
+        &Pair::icp_usd(),
+        &recent_range(now),
+        config::granularity(),
+        &SampleOptions::default(),
+        now,
+    );
+    RECENT.with(|recent| {
+        HASHES.with(|hashes| {
+            let mut recent = recent.borrow_mut();
+            let mut hashes = hashes.borrow_mut();
+            for (path, format) in [("/rates", Format::Json), ("/rates.csv", Format::Csv)] {
+                let body = render(&rates, format);
+                hashes.insert(path.to_string(), Sha256::digest(&body).into());
+                recent.insert(path.to_string(), body);
+            }
+            let cert = ic_certified_map::labeled_hash(b"http_assets", &hashes.root_hash());
+            ic_cdk::api::set_certified_data(&cert);
+        })
+    });
+}
+
+fn render(rates: &RatesWithInterval, format: Format) -> Vec<u8> {
+    match format {
+        Format::Json => serde_json::to_vec(rates).unwrap(),
+        Format::Csv => {
+            let mut csv = "timestamp,rate\n".to_string();
+            for (timestamp, rate) in &rates.rates {
+                csv.push_str(&format!("{},{}\n", timestamp, rate));
+            }
+            csv.into_bytes()
+        }
+    }
+}
+
+fn error(status_code: u16, message: String) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: HashMap::from([(
+            "Content-Type".to_string(),
+            "text/plain; charset=utf-8".to_string(),
+        )]),
+        body: message.into_bytes(),
+    }
+}
+
+// 解析交易对，例如 ICP-USD 或 ICP/USD
+fn parse_pair(pair: &str) -> Option<Pair> {
+    let (base, quote) = pair.split_once(|c| c == '-' || c == '/')?;
+    Some(Pair {
+        base: base.to_ascii_uppercase(),
+        quote: quote.to_ascii_uppercase(),
+    })
+}
+
+fn parse_number(params: &HashMap<String, String>, name: &str) -> Result<Option<u64>, String> {
+    params
+        .get(name)
+        .map(|value| {
+            value
+                .parse::<u64>()
+                .map_err(|_| format!("Invalid {}: {}", name, value))
+        })
+        .transpose()
+}
+
+// 处理 http 请求
+// GET /rates?pair=ICP-USD&start=&end=&interval= returns the RatesWithInterval of the range as
+// JSON, /rates.csv the same rates as CSV. `interval` is the minimum width of the buckets in seconds.
+// Without start and end, the most recent window is returned, with a certificate.
+pub fn handle(request: HttpRequest, now: Timestamp) -> HttpResponse {
+    if request.method != "GET" && request.method != "HEAD" {
+        return error(405, format!("Method {} not allowed", request.method));
+    }
+    let (path, query) = request
+        .url
+        .split_once('?')
+        .unwrap_or((request.url.as_str(), ""));
+    let format = match path {
+        "/rates" => Format::Json,
+        "/rates.csv" => Format::Csv,
+        _ => return error(404, format!("Not found: {}", path)),
+    };
+
+    let params: HashMap<String, String> = query
+        .split('&')
+        .filter_map(|param| param.split_once('='))
+        .map(|(name, value)| {
+            (
+                name.to_string(),
+                percent_decode_str(value).decode_utf8_lossy().into_owned(),
+            )
+        })
+        .collect();
+    let pair = match params.get("pair") {
+        Some(pair) => match parse_pair(pair) {
+            Some(pair) => pair,
+            None => return error(400, format!("Invalid pair: {}", pair)),
+        },
+        None => Pair::icp_usd(),
+    };
+    if pair != Pair::icp_usd() {
+        return error(404, "Only ICP/USD is tracked".to_string());
+    }
+    let (start, end, interval) = match (
+        parse_number(&params, "start"),
+        parse_number(&params, "end"),
+        parse_number(&params, "interval"),
+    ) {
+        (Ok(start), Ok(end), Ok(interval)) => (start, end, interval),
+        (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => return error(400, e),
+    };
+
+    let mut headers = HashMap::from([(
+        "Content-Type".to_string(),
+        format.content_type().to_string(),
+    )]);
+
+    // The certified responses of the most recent window
+    if start.is_none() && end.is_none() && interval.is_none() {
+        if let Some(body) = RECENT.with(|recent| recent.borrow().get(path).cloned()) {
+            if let Some(certificate) = ic_cdk::api::data_certificate() {
+                headers.insert(
+                    "IC-Certificate".to_string(),
+                    format!(
+                        "certificate=:{}:, tree=:{}:",
+                        base64::encode(certificate),
+                        witness(path)
+                    ),
+                );
+            }
+            headers.insert(
+                "Cache-Control".to_string(),
+                format!("public, max-age={}", RECENT_MAX_AGE),
+            );
+            return HttpResponse {
+                status_code: 200,
+                headers,
+                body,
+            };
+        }
+    }
+
+    let recent = recent_range(now);
+    let range = TimeRange {
+        start: start.unwrap_or(recent.start),
+        end: end.unwrap_or(recent.end),
+    };
+    if range.start >= range.end {
+        return error(400, "start must be before end".to_string());
+    }
+    let rates = sampled_rates(
+        &pair,
+        &range,
+        interval.unwrap_or(config::granularity()),
+        &SampleOptions::default(),
+        now,
+    );
+    let max_age = if rates.missing.is_empty() && range.end.saturating_add(HISTORY_AFTER) <= now {
+        HISTORY_MAX_AGE
+    } else {
+        RECENT_MAX_AGE
+    };
+    headers.insert(
+        "Cache-Control".to_string(),
+        format!("public, max-age={}", max_age),
+    );
+    HttpResponse {
+        status_code: 200,
+        headers,
+        body: render(&rates, format),
+    }
+}
+
+// 认证树中对应路径的证明
+fn witness(path: &str) -> String {
+    HASHES.with(|hashes| {
+        let hashes = hashes.borrow();
+        let witness = hashes.witness(path.as_bytes());
+        let tree = ic_certified_map::labeled(b"http_assets", witness);
+        let mut data = vec![];
+        let mut serializer = Serializer::new(&mut data);
+        serializer.self_describe().unwrap();
+        tree.serialize(&mut serializer).unwrap();
+        base64::encode(data)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(url: &str) -> HttpResponse {
+        let request = HttpRequest {
+            method: "GET".to_string(),
+            url: url.to_string(),
+            headers: HashMap::new(),
+            body: vec![],
+        };
+        handle(request, 1_800_000)
+    }
+
+    #[test]
+    fn test_http_request_routes_and_validates() {
+        assert_eq!(get("/").status_code, 404);
+        assert_eq!(get("/rates?start=abc").status_code, 400);
+        assert_eq!(get("/rates?pair=BTC-USD").status_code, 404);
+        assert_eq!(get("/rates?start=1700000&end=1600000").status_code, 400);
+
+        let response = get("/rates.csv?pair=icp%2Fusd&start=1600000&end=1700000&interval=300");
+        assert_eq!(response.status_code, 200);
+        assert_eq!(
+            response.headers.get("Content-Type").unwrap(),
+            "text/csv; charset=utf-8"
+        );
+        assert_eq!(
+            String::from_utf8(response.body).unwrap(),
+            "timestamp,rate\n"
+        );
+        // Nothing is stored, so the range may still change
+        assert_eq!(
+            response.headers.get("Cache-Control").unwrap(),
+            "public, max-age=60"
+        );
+
+        let response = get("/rates?start=1600000&end=1700000");
+        let rates: RatesWithInterval = serde_json::from_slice(&response.body).unwrap();
+        assert!(rates.rates.is_empty());
+        assert_eq!(
+            rates.missing,
+            vec![TimeRange {
+                start: 1_599_960,
+                end: 1_699_980
+            }]
+        );
+    }
+}
@@ -0,0 +1,318 @@
+use crate::source::Source;
+use crate::stable::{
+    memory, Memory, COMPLETED_MEMORY_ID, DEAD_LETTERS_MEMORY_ID, JOBS_MEMORY_ID, QUEUE_MEMORY_ID,
+};
+use crate::Timestamp;
+use candid::{CandidType, Decode, Encode};
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+// 最多尝试几次
+// After this many failed attempts, a job is moved to the dead letters and not retried anymore.
+pub const JOB_MAX_ATTEMPTS: u32 = 5;
+
+// 重试间隔，每失败一次翻倍
+// Delay in seconds before retrying a failed job, doubled after every failure up to JOB_BACKOFF_MAX.
+pub const JOB_BACKOFF_BASE: u64 = 60;
+pub const JOB_BACKOFF_MAX: u64 = 60 * 60;
+
+// 任务执行的租期
+// A job being fetched is leased for this many seconds. If the fetch never reports back, e.g.
+// because the canister was upgraded in the meantime, the job becomes due again afterwards.
+pub const JOB_LEASE: u64 = 5 * 60;
+
+// 错误信息最大长度
+// The last error is truncated to a quarter of this many bytes, the rest bounds the other fields.
+const MAX_ERROR_LEN: usize = 256;
+
+#[derive(CandidType, Clone, Deserialize, Debug, PartialEq, Serialize)]
+pub struct Job {
+    pub attempts: u32,
+    pub next_attempt: Timestamp,
+    pub last_error: Option<String>,
+    // The sources that failed on the last attempt, and are the only ones asked again. None asks
+    // every configured source.
+    pub retry_sources: Option<Vec<Source>>,
+}
+
+impl Storable for Job {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Job {
+    const MAX_SIZE: u32 = MAX_ERROR_LEN as u32 + 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// 队列的键：到期时间 + 任务
+// Jobs in the queue are ordered by when they are due, then by their timestamp.
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+struct QueueKey {
+    due: Timestamp,
+    job: Timestamp,
+}
+
+impl Storable for QueueKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = self.due.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&self.job.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let mut due = [0; 8];
+        let mut job = [0; 8];
+        due.copy_from_slice(&bytes[..8]);
+        job.copy_from_slice(&bytes[8..]);
+        QueueKey {
+            due: u64::from_be_bytes(due),
+            job: u64::from_be_bytes(job),
+        }
+    }
+}
+
+impl BoundedStorable for QueueKey {
+    const MAX_SIZE: u32 = 16;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+// 任务状态
+#[derive(CandidType, Clone, Deserialize, Debug, PartialEq, Serialize)]
+pub enum JobState {
+    // Never requested, or requested before the canister kept track of it
+    Unknown,
+    // Waiting in the queue, possibly after failed attempts
+    Pending {
+        attempts: u32,
+        next_attempt: Timestamp,
+        last_error: Option<String>,
+    },
+    // Gave up after JOB_MAX_ATTEMPTS failures
+    Failed {
+        attempts: u32,
+        last_error: Option<String>,
+    },
+    // Fetched, but the sources had no agreed rate for `missing` minutes of the job
+    Unavailable {
+        completed_at: Timestamp,
+        missing: u64,
+    },
+    // Fetched, and every minute of the job is stored
+    Complete {
+        completed_at: Timestamp,
+    },
+}
+
+#[derive(CandidType, Clone, Deserialize, Debug, PartialEq, Serialize)]
+pub struct JobStatus {
+    pub job: Timestamp,
+    pub state: JobState,
+}
+
+thread_local! {
+    // 所有未完成的任务
+    static JOBS: RefCell<StableBTreeMap<Timestamp, Job, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(JOBS_MEMORY_ID)));
+    // 按到期时间排序的队列，值没有用到
+    static QUEUE: RefCell<StableBTreeMap<QueueKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(QUEUE_MEMORY_ID)));
+    // 失败太多次的任务
+    static DEAD_LETTERS: RefCell<StableBTreeMap<Timestamp, Job, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(DEAD_LETTERS_MEMORY_ID)));
+    // 完成的任务及完成时间
+    static COMPLETED: RefCell<StableBTreeMap<Timestamp, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(COMPLETED_MEMORY_ID)));
+}
+
+fn schedule(job: Timestamp, mut state: Job, due: Timestamp) {
+    QUEUE.with(|queue| {
+        let mut queue = queue.borrow_mut();
+        queue.remove(&QueueKey {
+            due: state.next_attempt,
+            job,
+        });
+        queue.insert(QueueKey { due, job }, 0);
+    });
+    state.next_attempt = due;
+    JOBS.with(|jobs| jobs.borrow_mut().insert(job, state));
+}
+
+// 加入队列
+// Queue the job, unless it is already pending or has failed too many times.
+pub fn enqueue(job: Timestamp, now: Timestamp) {
+    let known = JOBS.with(|jobs| jobs.borrow().contains_key(&job))
+        || DEAD_LETTERS.with(|dead| dead.borrow().contains_key(&job));
+    if known {
+        return;
+    }
+    let state = Job {
+        attempts: 0,
+        next_attempt: now,
+        last_error: None,
+        retry_sources: None,
+    };
+    schedule(job, state, now);
+}
+
+// 取出下一个到期的任务
+// Lease the earliest due job for JOB_LEASE seconds and return it, or None if nothing is due.
+pub fn next_due(now: Timestamp) -> Option<Timestamp> {
+    let key = QUEUE.with(|queue| queue.borrow().iter().next().map(|(key, _)| key))?;
+    if key.due > now {
+        return None;
+    }
+    let state = JOBS.with(|jobs| jobs.borrow().get(&key.job));
+    match state {
+        Some(state) => schedule(key.job, state, now + JOB_LEASE),
+        None => {
+            // Should not happen, but don't let a stray entry block the queue.
+            QUEUE.with(|queue| queue.borrow_mut().remove(&key));
+            return None;
+        }
+    }
+    Some(key.job)
+}
+
+// 任务成功
+pub fn complete(job: Timestamp, now: Timestamp) {
+    if let Some(state) = JOBS.with(|jobs| jobs.borrow_mut().remove(&job)) {
+        QUEUE.with(|queue| {
+            queue.borrow_mut().remove(&QueueKey {
+                due: state.next_attempt,
+                job,
+            })
+        });
+    }
+    COMPLETED.with(|completed| completed.borrow_mut().insert(job, now));
+}
+
+// 任务失败，稍后重试
+// Record a failed attempt of the `sources`. The job is retried with exponential backoff, asking
+// only those sources, or moved to the dead letters once it has failed JOB_MAX_ATTEMPTS times.
+pub fn fail(job: Timestamp, mut error: String, sources: Vec<Source>, now: Timestamp) {
+    let mut state = match JOBS.with(|jobs| jobs.borrow().get(&job)) {
+        Some(state) => state,
+        None => return,
+    };
+    state.attempts += 1;
+    let end = (0..=MAX_ERROR_LEN / 4)
+        .rev()
+        .find(|end| error.is_char_boundary(*end))
+        .unwrap_or(0);
+    error.truncate(end);
+    state.last_error = Some(error);
+    state.retry_sources = Some(sources);
+
+    if state.attempts >= JOB_MAX_ATTEMPTS {
+        QUEUE.with(|queue| {
+            queue.borrow_mut().remove(&QueueKey {
+                due: state.next_attempt,
+                job,
+            })
+        });
+        JOBS.with(|jobs| jobs.borrow_mut().remove(&job));
+        DEAD_LETTERS.with(|dead| dead.borrow_mut().insert(job, state));
+        return;
+    }
+
+    let backoff = JOB_BACKOFF_BASE
+        .saturating_mul(1 << (state.attempts - 1).min(32))
+        .min(JOB_BACKOFF_MAX);
+    schedule(job, state, now + backoff);
+}
+
+// 重试时要请求的数据源
+// None when the job isn't pending, or every configured source is to be asked.
+pub fn retry_sources(job: Timestamp) -> Option<Vec<Source>> {
+    JOBS.with(|jobs| jobs.borrow().get(&job))?.retry_sources
+}
+
+// 任务状态，不检查数据是否完整
+// The state of the job as far as the queue knows. A completed job is reported as Complete, the
+// caller is expected to check whether its minutes are all stored.
+pub fn state(job: Timestamp) -> JobState {
+    if let Some(state) = JOBS.with(|jobs| jobs.borrow().get(&job)) {
+        return JobState::Pending {
+            attempts: state.attempts,
+            next_attempt: state.next_attempt,
+            last_error: state.last_error,
+        };
+    }
+    if let Some(state) = DEAD_LETTERS.with(|dead| dead.borrow().get(&job)) {
+        return JobState::Failed {
+            attempts: state.attempts,
+            last_error: state.last_error,
+        };
+    }
+    match COMPLETED.with(|completed| completed.borrow().get(&job)) {
+        Some(completed_at) => JobState::Complete { completed_at },
+        None => JobState::Unknown,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jobs_are_ordered_by_due_time() {
+        enqueue(2_000, 10);
+        enqueue(1_000, 20);
+        // Already pending
+        enqueue(2_000, 30);
+
+        assert_eq!(next_due(5), None);
+        assert_eq!(next_due(20), Some(2_000));
+        assert_eq!(next_due(20), Some(1_000));
+        // Both are leased, and due again at the same time
+        assert_eq!(next_due(20), None);
+        assert_eq!(next_due(20 + JOB_LEASE - 1), None);
+        assert_eq!(next_due(20 + JOB_LEASE), Some(1_000));
+    }
+
+    #[test]
+    fn test_failed_jobs_back_off_and_give_up() {
+        let job = 3_000;
+        let mut now = 100;
+        enqueue(job, now);
+        for attempt in 1..JOB_MAX_ATTEMPTS {
+            assert_eq!(next_due(now), Some(job));
+            fail(job, "timeout".to_string(), vec![Source::Kraken], now);
+            assert_eq!(retry_sources(job), Some(vec![Source::Kraken]));
+            let backoff = JOB_BACKOFF_BASE * (1 << (attempt - 1));
+            assert_eq!(next_due(now + backoff - 1), None);
+            match state(job) {
+                JobState::Pending { attempts, .. } => assert_eq!(attempts, attempt),
+                other => panic!("unexpected state {:?}", other),
+            }
+            now += backoff;
+        }
+        assert_eq!(next_due(now), Some(job));
+        fail(job, "timeout".to_string(), vec![Source::Kraken], now);
+        assert!(matches!(
+            state(job),
+            JobState::Failed {
+                attempts: JOB_MAX_ATTEMPTS,
+                ..
+            }
+        ));
+        assert_eq!(next_due(u64::MAX), None);
+        // Dead jobs are not queued again
+        enqueue(job, now);
+        assert!(matches!(state(job), JobState::Failed { .. }));
+
+        enqueue(4_000, now);
+        assert_eq!(next_due(now), Some(4_000));
+        assert_eq!(retry_sources(4_000), None);
+        complete(4_000, now);
+        assert_eq!(state(4_000), JobState::Complete { completed_at: now });
+    }
+}
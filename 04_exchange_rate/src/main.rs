@@ -1,13 +1,37 @@
+mod aggregate;
+mod analytics;
+mod config;
+mod cycles;
+mod feed;
+mod http;
+mod jobs;
+mod sampling;
+mod source;
+mod stable;
+mod tracking;
+mod transform;
+
+use aggregate::{aggregate, AggregatedRate, AggregationConfig, RateConfidence};
+use analytics::RateStats;
 use candid::{CandidType, Principal};
-use ic_cdk::storage;
-use ic_cdk_macros::{self, heartbeat, post_upgrade, pre_upgrade, query, update};
+use config::FetchConfig;
+use cycles::{CyclesBudget, CyclesMetrics};
+use feed::{Candle, LatestRate, Subscription, DELIVERIES_PER_HEARTBEAT};
+use ic_cdk::api::call::RejectionCode;
+use ic_cdk_macros::{self, heartbeat, init, post_upgrade, pre_upgrade, query, update};
+use jobs::{JobState, JobStatus};
+use sampling::{sample_with_interval, SampleOptions};
 use serde::{Deserialize, Serialize};
-use serde_json::{self, Value};
-use std::cell::{RefCell, RefMut};
-use std::collections::{HashMap, HashSet};
+use source::{DecodeError, Pair, Source};
+use stable::{Granularity, RetentionPolicy, StableState, StoredRate};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use tracking::Backfill;
 
 type Timestamp = u64;
 type Rate = f32;
+type Volume = f64;
 
 #[derive(CandidType, Clone, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
 pub struct TimeRange {
@@ -18,7 +42,44 @@ pub struct TimeRange {
 #[derive(Clone, Debug, PartialEq, CandidType, Serialize, Deserialize)]
 pub struct RatesWithInterval {
     pub interval: usize,
-    pub rates: HashMap<Timestamp, Rate>,
+    pub rates: Vec<(Timestamp, Rate)>,
+    pub confidence: Vec<(Timestamp, RateConfidence)>,
+    // Parts of the requested range that aren't stored, see request_backfill
+    pub missing: Vec<TimeRange>,
+}
+
+// 非 200 响应的统计
+#[derive(CandidType, Clone, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
+pub struct HttpErrorCount {
+    pub source: Source,
+    pub status: u64,
+    pub count: u64,
+    pub last_seen: Timestamp,
+}
+
+// 获取数据失败的原因
+#[derive(Debug)]
+pub enum FetchError {
+    // The http_request call itself was rejected, e.g. because the response was too large
+    Rejected(RejectionCode, String),
+    // The reply of the management canister couldn't be decoded
+    Candid(String),
+    // The source answered with a status other than 200
+    HttpStatus(u64),
+    Decode(DecodeError),
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FetchError::Rejected(code, message) => {
+                write!(f, "Rejected with {:?}: {}", code, message)
+            }
+            FetchError::Candid(error) => write!(f, "Invalid http_request reply: {}", error),
+            FetchError::HttpStatus(status) => write!(f, "HTTP status {}", status),
+            FetchError::Decode(error) => write!(f, "{}", error),
+        }
+    }
 }
 
 #[derive(CandidType, Clone, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
@@ -61,6 +122,7 @@ pub const MAX_DATA_POINTS_CANISTER_RESPONSE: usize = 1000000;
 // 粒度间隔
 // Remote fetch interval in secs. It is only the canister returned interval
 // that is dynamic according to the data size needs to be returned.
+// This and the next three constants are the defaults of FetchConfig, which can be changed at runtime.
 pub const REMOTE_FETCH_GRANULARITY: u64 = 60;
 
 // 心跳间隔
@@ -87,21 +149,122 @@ pub const DATA_POINTS_PER_API: u64 = 200;
 // 10 (bytes per field) * 6 (fields per timestamp) * 200 (timestamps)
 pub const MAX_RESPONSE_BYTES: u64 = 10 * 6 * DATA_POINTS_PER_API;
 
-// 还不知道干嘛的
-pub const RESPONSE_HEADERS_SANITIZATION: [&'static str; 7] = [
-    "Date",                    // DateTime of the request is made
-    "CF-Cache-Status",         // CloudFront caching status
-    "CF-RAY",                  // CloudFront custom Id
-    "Age",                     // Age of the data object since query
-    "Content-Security-Policy", // Long list of allowable domains for reference
-    "Last-Modified",           // Last time the object is modified
-    "Set-Cookie",              // cf-country=US;Path=/;
-];
+// 每次心跳最多删除的过期数据量
+// How many expired rates are removed per heartbeat at most, to stay within the instruction limit.
+pub const PRUNE_BATCH_SIZE: usize = 100;
+
+// 补数据的频率限制
+// Callers other than the owner can queue at most BACKFILL_JOBS_PER_WINDOW jobs every
+// BACKFILL_WINDOW seconds between them. The budget is shared rather than per caller, since anyone
+// can call from as many principals as they like.
+pub const BACKFILL_WINDOW: u64 = 60 * 60;
+pub const BACKFILL_JOBS_PER_WINDOW: u64 = 50;
+
+// 标记空缺前的等待时间
+// Sources may publish a candle a little after the minute is over, so a minute is only marked as a
+// gap if it still had no data this many seconds after it ended.
+pub const GAP_SETTLE_TIME: u64 = 5 * 60;
+
+// 单次查询最多返回的任务数
+// get_job_status is a query, so it reports on at most this many jobs per call.
+pub const MAX_JOB_STATUSES: usize = 1000;
 
 thread_local! {
-    pub static FETCHED: RefCell<HashMap<Timestamp, Rate>>  = RefCell::new(HashMap::new()); // ? 取得的数据？
-    pub static REQUESTED: RefCell<HashSet<Timestamp>> = RefCell::new(HashSet::new()); // ? 请求的数据？
     pub static RATE_COUNTER: RefCell<usize> = RefCell::new(0); // 心跳计数
+    pub static AGGREGATION: RefCell<AggregationConfig> = RefCell::new(AggregationConfig::default()); // 聚合配置
+    pub static OWNER: RefCell<Principal> = RefCell::new(Principal::anonymous()); // 部署者
+    pub static RETENTION: RefCell<RetentionPolicy> = RefCell::new(RetentionPolicy::default()); // 保留策略
+    pub static HTTP_ERRORS: RefCell<BTreeMap<(Source, u64), HttpErrorCount>> = RefCell::new(BTreeMap::new()); // 各数据源非 200 响应的统计
+    pub static BACKFILL_BUDGET: RefCell<(Timestamp, u64)> = RefCell::new((0, 0)); // 当前窗口的开始时间和已请求的任务数
+}
+
+// 当前时间，秒
+fn now() -> Timestamp {
+    ic_cdk::api::time() / 1_000_000_000
+}
+
+#[init]
+fn init() {
+    OWNER.with(|owner| *owner.borrow_mut() = ic_cdk::caller());
+    // Certified queries are answered from the start, even before anything was fetched.
+    http::certify_recent(now());
+}
+
+fn ensure_owner() -> Result<(), String> {
+    if OWNER.with(|owner| *owner.borrow() != ic_cdk::caller()) {
+        return Err("Only the owner of the canister can do this".to_string());
+    }
+    Ok(())
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn get_aggregation_config() -> AggregationConfig {
+    AGGREGATION.with(|config| config.borrow().clone())
+}
+
+// 修改聚合配置
+// Change the sources fetched for each job, and how many of them need to agree on a rate.
+#[update]
+#[candid::candid_method(update)]
+fn set_aggregation_config(config: AggregationConfig) -> Result<(), String> {
+    ensure_owner()?;
+    config.validate()?;
+    AGGREGATION.with(|c| *c.borrow_mut() = config);
+    Ok(())
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn get_retention_policy() -> RetentionPolicy {
+    RETENTION.with(|retention| retention.borrow().clone())
+}
+
+// 修改保留策略
+// Change how long the rates of each granularity are kept. Expired rates are removed by the heartbeat.
+#[update]
+#[candid::candid_method(update)]
+fn set_retention_policy(policy: RetentionPolicy) -> Result<(), String> {
+    ensure_owner()?;
+    policy.validate()?;
+    RETENTION.with(|retention| *retention.borrow_mut() = policy);
+    Ok(())
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn get_cycles_metrics() -> CyclesMetrics {
+    cycles::metrics(now())
+}
+
+// 修改周期预算
+// Change how many cycles can be spent on outcalls per day, and the balance below which fetching
+// pauses.
+#[update]
+#[candid::candid_method(update)]
+fn set_cycles_budget(budget: CyclesBudget) -> Result<(), String> {
+    ensure_owner()?;
+    cycles::set_budget(budget)
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn get_fetch_config() -> FetchConfig {
+    config::get()
+}
+
+// 修改获取数据的配置
+// Change the granularity, the number of candles per call, the Coinbase response limit and how
+// often a job is fetched. The granularity can only be changed before any rate is stored, since
+// the stored buckets and the queued jobs depend on it.
+#[update]
+#[candid::candid_method(update)]
+fn set_fetch_config(new_config: FetchConfig) -> Result<(), String> {
+    ensure_owner()?;
+    if new_config.granularity != config::granularity() && stable::has_rates() {
+        return Err("The granularity can't be changed once rates are stored".to_string());
+    }
+    config::set(new_config)
 }
 
 // 心跳函数
@@ -114,70 +277,349 @@ async fn heartbeat() {
         if state == 0 {
             should_fetch = true;
         }
-        counter.replace((state + 1) % RATE_LIMIT_FACTOR);
+        counter.replace((state + 1) % config::get().rate_limit_factor as usize);
     });
+    let retention = RETENTION.with(|retention| retention.borrow().clone());
+    stable::prune_expired(now(), &retention, PRUNE_BATCH_SIZE); // 清理过期数据
+    for delivery in feed::take_due(now(), DELIVERIES_PER_HEARTBEAT) {
+        deliver(delivery); // 推送给订阅者
+    }
     if should_fetch {
-        get_next_rate().await; // 每 5 次心跳获取一次数据
+        tracking::tick(now()); // 跟踪最新数据，推进补数据任务
+        get_next_rate().await; // 每 rate_limit_factor 次心跳获取一次数据
     }
 }
 
 // 获取指定时间范围的数据
-// Get rates for a time range defined by start time and end time. This function can be invoked as HTTP update call.
+// Get rates for a time range defined by start time and end time. This is a query, so it only
+// returns the stored rates, down-sampled as described by the options (see SampleOptions for the
+// defaults). The parts of the range that aren't stored are listed in `missing`, and can be
+// requested with request_backfill.
+#[query]
+#[candid::candid_method(query)]
+fn get_rates(range: TimeRange, options: Option<SampleOptions>) -> RatesWithInterval {
+    sampled_rates(
+        &Pair::icp_usd(),
+        &range,
+        config::granularity(),
+        &options.unwrap_or_default(),
+        now(),
+    )
+}
+
+// 图表数据的 http 接口
+// Serves /rates and /rates.csv, see http::handle.
+#[query]
+#[candid::candid_method(query)]
+fn http_request(request: http::HttpRequest) -> http::HttpResponse {
+    http::handle(request, now())
+}
+
+// 取得采样后的数据
+// The stored rates of the range sampled into buckets of at least min_interval seconds, along with
+// the missing parts of the range.
+fn sampled_rates(
+    pair: &Pair,
+    range: &TimeRange,
+    min_interval: u64,
+    options: &SampleOptions,
+    now: Timestamp,
+) -> RatesWithInterval {
+    let stored = stored_rates(pair, range, now);
+    let points = if options.forward_fill == Some(true) {
+        forward_fill(stored.rates, &stored.gaps)
+    } else {
+        stored.rates
+    };
+
+    // return sampled rates for available ranges
+    let mut rates = sample_with_interval(points, stored.interval.max(min_interval), options);
+    rates.missing = stored.missing;
+    rates
+}
+
+// 用前一个数据填补空缺
+// Fill each known gap with the close of the point before it, without volume. Gaps before the
+// first point are left alone.
+fn forward_fill(
+    points: Vec<(Timestamp, StoredRate)>,
+    gaps: &[Timestamp],
+) -> Vec<(Timestamp, StoredRate)> {
+    let mut filled = Vec::with_capacity(points.len() + gaps.len());
+    let mut gaps = gaps.iter().peekable();
+    for (timestamp, rate) in points {
+        while let Some(gap) = gaps.next_if(|gap| **gap < timestamp) {
+            if let Some((_, previous)) = filled.last() {
+                let fill = fill_rate(previous);
+                filled.push((*gap, StoredRate::new(*gap, &fill)));
+            }
+        }
+        filled.push((timestamp, rate));
+    }
+    for gap in gaps {
+        if let Some((_, previous)) = filled.last() {
+            let fill = fill_rate(previous);
+            filled.push((*gap, StoredRate::new(*gap, &fill)));
+        }
+    }
+    filled
+}
+
+fn fill_rate(previous: &StoredRate) -> AggregatedRate {
+    AggregatedRate {
+        rate: previous.close,
+        volume: 0.0,
+        confidence: previous.confidence.clone(),
+    }
+}
+
+// 数据质量报告
+#[derive(CandidType, Clone, Deserialize, Debug, PartialEq, Serialize)]
+pub struct DataQuality {
+    // The coarsest interval of the stored data of the range, like for get_rates
+    pub interval: u64,
+    // Buckets of the range that are over, and how many of them have a rate
+    pub buckets: u64,
+    pub covered: u64,
+    pub coverage_percent: f32,
+    // Minutes that were fetched, but none of the sources had data for
+    pub gaps: Vec<TimeRange>,
+    // Parts of the range that weren't fetched yet, see request_backfill
+    pub missing: Vec<TimeRange>,
+}
+
+// 时间段内数据的完整程度
+#[query]
+#[candid::candid_method(query)]
+fn data_quality(range: TimeRange) -> DataQuality {
+    let stored = stored_rates(&Pair::icp_usd(), &range, now());
+    let granularity = config::granularity();
+    let mut gaps: Vec<TimeRange> = vec![];
+    for gap in stored.gaps {
+        match gaps.last_mut() {
+            Some(last) if last.end == gap => last.end = gap + granularity,
+            _ => gaps.push(TimeRange {
+                start: gap,
+                end: gap + granularity,
+            }),
+        }
+    }
+    DataQuality {
+        interval: stored.interval,
+        buckets: stored.buckets,
+        covered: stored.covered,
+        coverage_percent: if stored.buckets == 0 {
+            100.0
+        } else {
+            stored.covered as f32 / stored.buckets as f32 * 100.0
+        },
+        gaps,
+        missing: stored.missing,
+    }
+}
+
+// 时间段内的统计数据
+// TWAP, VWAP, realized volatility, min/max and change of the stored rates of the range, see
+// RateStats. Returns None if nothing is stored for the range. Missing parts can be requested with
+// request_backfill like for get_rates.
+#[query]
+#[candid::candid_method(query)]
+fn get_rate_stats(range: TimeRange) -> Option<RateStats> {
+    let stored = stored_rates(&Pair::icp_usd(), &range, now());
+    analytics::analyze(&stored.rates, stored.interval)
+}
+
+// 请求下载缺失的数据
+// Queue jobs for the parts of the range that aren't stored yet. Returns how many jobs were queued.
+// Jobs that are queued already, gave up, or completed after their window was over aren't queued
+// again. Callers other than the owner are rate limited, see BACKFILL_JOBS_PER_WINDOW.
 #[update]
 #[candid::candid_method(update)]
-async fn get_rates(range: TimeRange) -> RatesWithInterval {
-    // round down start time and end time to the minute (chop off seconds), to be checked in the hashmap
-    let start_min = range.start / REMOTE_FETCH_GRANULARITY;
-    let end_min = range.end / REMOTE_FETCH_GRANULARITY;
-
-    // compose a return structure
-    let mut fetched = HashMap::new();
-
-    // pull available ranges from hashmap
-    FETCHED.with(|map| {
-        let map = map.borrow();
-        for requested_min in start_min..end_min {
-            let requested = requested_min * REMOTE_FETCH_GRANULARITY;
-            if map.contains_key(&requested) {
-                // The fetched slot is within user requested range. Add to result for later returning.
-                ic_cdk::api::print(format!("Found {} in map!", requested));
-                fetched.insert(requested, map.get(&requested).unwrap().clone());
-            } else {
-                ic_cdk::api::print(format!("Did not find {} in map!", requested));
-                // asynchoronously request downloads for unavailable ranges
+fn request_backfill(range: TimeRange) -> Result<u64, String> {
+    let now = now();
+    let missing = stored_rates(&Pair::icp_usd(), &range, now).missing;
 
-                add_job_to_job_set(requested); // 该时间戳没有数据，加入请求队列
+    let job_span = config::job_span();
+    let mut new_jobs = BTreeSet::new();
+    for range in missing {
+        let mut job = range.start / job_span * job_span;
+        while job < range.end {
+            let queue = match jobs::state(job) {
+                JobState::Unknown => true,
+                // The minutes that hadn't started yet when it completed are still to be fetched.
+                JobState::Complete { completed_at } => completed_at < job + job_span,
+                _ => false,
+            };
+            if queue {
+                new_jobs.insert(job);
             }
+            job += job_span;
         }
-    });
+    }
+    let count = new_jobs.len() as u64;
 
-    // return sampled rates for available ranges
-    sample_with_interval(fetched)
-}
-
-fn sample_with_interval(fetched: HashMap<Timestamp, Rate>) -> RatesWithInterval {
-    // in order to make sure that returned data do not exceed 2MB, which is about
-    // ~1M data points, calculate interval when data points count is beyond 900K.
-    let interval_options = vec![
-        1,       // 1 data point every minute
-        5,       // 1 data point every 5 minutes
-        15,      // 1 data point every 15 minutes
-        60,      // 1 data point every hour
-        60 * 12, // 1 data point every 12 hours
-        60 * 24, // 1 data point every day
-    ];
-    for i in interval_options {
-        if fetched.len() / i < MAX_DATA_POINTS_CANISTER_RESPONSE {
-            return RatesWithInterval {
-                interval: i * REMOTE_FETCH_GRANULARITY as usize,
-                rates: fetched
-                    .into_iter()
-                    .filter(|r| r.0 % (i as u64) == 0)
-                    .collect(),
-            };
+    if ensure_owner().is_err() {
+        BACKFILL_BUDGET.with(|budget| {
+            let (window_start, requested) = &mut *budget.borrow_mut();
+            if *window_start + BACKFILL_WINDOW <= now {
+                *window_start = now;
+                *requested = 0;
+            }
+            if *requested + count > BACKFILL_JOBS_PER_WINDOW {
+                return Err(format!(
+                    "The range needs {} jobs, but only {} more can be requested before {}",
+                    count,
+                    BACKFILL_JOBS_PER_WINDOW - *requested,
+                    *window_start + BACKFILL_WINDOW
+                ));
+            }
+            *requested += count;
+            Ok(())
+        })?;
+    }
+
+    for job in new_jobs {
+        add_job_to_job_set(job);
+    }
+    Ok(count)
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn get_tracked_pairs() -> Vec<Pair> {
+    tracking::tracked_pairs()
+}
+
+// 持续跟踪
+// Fetch the latest minutes of the pairs continuously, instead of only when they're requested.
+// An empty list turns tracking off.
+#[update]
+#[candid::candid_method(update)]
+fn set_tracked_pairs(pairs: Vec<Pair>) -> Result<(), String> {
+    ensure_owner()?;
+    tracking::set_tracked_pairs(pairs)
+}
+
+// 补充历史数据
+// Fetch the history of the pair for the range, job by job, skipping the jobs whose minutes are
+// all stored. Returns the id of the backfill, whose progress is reported by get_backfills.
+#[update]
+#[candid::candid_method(update)]
+fn start_backfill(pair: Pair, range: TimeRange) -> Result<u64, String> {
+    ensure_owner()?;
+    tracking::start_backfill(pair, range, now())
+}
+
+// 补数据的进度
+// The running backfill, if any, and the most recent finished ones.
+#[query]
+#[candid::candid_method(query)]
+fn get_backfills() -> Vec<Backfill> {
+    tracking::backfills()
+}
+
+// 存储中时间范围内的数据
+struct StoredRange {
+    // In ascending order of timestamps
+    rates: Vec<(Timestamp, StoredRate)>,
+    // The finest interval the rates can be sampled at
+    interval: u64,
+    // The parts of the range, up to now, that have no stored rates and aren't known gaps
+    missing: Vec<TimeRange>,
+    // The minutes of the range that were fetched but had no data, see stable::mark_gap
+    gaps: Vec<Timestamp>,
+    // How many buckets of the range are over, and how many of them have a stored rate
+    buckets: u64,
+    covered: u64,
+}
+
+// 从存储中取出时间范围内的数据
+fn stored_rates(pair: &Pair, range: &TimeRange, now: Timestamp) -> StoredRange {
+    let retention = RETENTION.with(|retention| retention.borrow().clone());
+
+    // round down start time and end time to the minute (chop off seconds), to be checked in the store
+    let granularity = config::granularity();
+    let start = range.start / granularity * granularity;
+    let end = range.end / granularity * granularity;
+
+    // compose a return structure, in ascending order of timestamps
+    let mut fetched = vec![];
+    let mut min_interval = granularity;
+    let mut missing: Vec<TimeRange> = vec![];
+    let mut gaps = vec![];
+    let mut buckets = 0;
+    let mut covered = 0;
+
+    // Minutes are only kept for a while, after which only the hourly and then the daily rollups
+    // are left. Serve each part of the range, oldest first, from the finest granularity that still
+    // retains it. A coarser part ends where the finer data starts, rounded up to a whole bucket.
+    let mut segment_start = start;
+    for (granularity, finer) in [
+        (Granularity::Day, Some(Granularity::Hour)),
+        (Granularity::Hour, Some(Granularity::Minute)),
+        (Granularity::Minute, None),
+    ] {
+        let width = granularity.seconds();
+        let segment_end = match finer {
+            Some(finer) => {
+                let cutoff = retention.cutoff(finer, now);
+                ((cutoff + width - 1) / width * width).min(end)
+            }
+            None => end,
+        };
+        if segment_start >= segment_end {
+            continue;
+        }
+
+        // pull available ranges from the store
+        let first = segment_start / width * width;
+        let stored = stable::get_range(pair, granularity, first, segment_end);
+        // Gaps are only tracked for minutes
+        let segment_gaps = match granularity {
+            Granularity::Minute => stable::get_gaps(pair, first, segment_end),
+            _ => vec![],
+        };
+
+        // collect the unavailable buckets, merging adjacent ones. The current bucket can't be
+        // fetched before it's over.
+        let mut present = stored.iter().map(|(timestamp, _)| *timestamp).peekable();
+        let mut known_gaps = segment_gaps.iter().peekable();
+        let mut bucket = first;
+        while bucket < segment_end && bucket + width <= now {
+            buckets += 1;
+            if present.peek() == Some(&bucket) {
+                present.next();
+                covered += 1;
+            } else if known_gaps.peek() == Some(&&bucket) {
+                known_gaps.next();
+            } else {
+                match missing.last_mut() {
+                    Some(last) if last.end == bucket => last.end = bucket + width,
+                    _ => missing.push(TimeRange {
+                        start: bucket,
+                        end: bucket + width,
+                    }),
+                }
+            }
+            bucket += width;
+        }
+
+        if !stored.is_empty() {
+            min_interval = min_interval.max(width);
         }
+        fetched.extend(stored);
+        gaps.extend(segment_gaps);
+        segment_start = segment_end;
+    }
+
+    StoredRange {
+        rates: fetched,
+        interval: min_interval,
+        missing,
+        gaps,
+        buckets,
+        covered,
     }
-    panic!("This shouldn't be happening! Couldn't find an interval that can keep total data points count in {}.", MAX_DATA_POINTS_CANISTER_RESPONSE);
 }
 
 // 添加时间戳到请求队列中
@@ -185,57 +627,275 @@ fn add_job_to_job_set(job: Timestamp) -> () {
     // Since Coinbase API allows DATA_POINTS_PER_API data points (5 hours of data) per API call,
     // and the response size is roughly 14KB, which is way below max_response_size,
     // we normalize the job to the beginning of 5 hours.
-    REQUESTED.with(|set| {
-        let mut set = set.borrow_mut();
-        let normalized_job = job / (REMOTE_FETCH_GRANULARITY * DATA_POINTS_PER_API)
-            * (REMOTE_FETCH_GRANULARITY * DATA_POINTS_PER_API); // 标准化时间戳
-        set.insert(normalized_job);
-    });
+    // Both are read from the live configuration.
+    let job_span = config::job_span();
+    let normalized_job = job / job_span * job_span; // 标准化时间戳
+    jobs::enqueue(normalized_job, now());
 }
 
 // 受心跳触发调用获取远程服务数据
 // Triggered by heartbeat() function to pick up the next job in the pipe for remote service call.
 async fn get_next_rate() {
-    let mut job_id: u64 = 0;
-
-    // Get the next downloading job
-    REQUESTED.with(|set| {
-        let mut set = set.borrow_mut();
+    // Only take a job if all of its outcalls can be paid for
+    let config = AGGREGATION.with(|config| config.borrow().clone());
+    let cost = config
+        .sources
+        .iter()
+        .map(|source| outcall_cost(*source, now()))
+        .sum();
+    if cycles::check(cost, ic_cdk::api::canister_balance(), now()).is_err() {
+        return;
+    }
 
-        if set.len() == 0 {
-            ic_cdk::api::print("Request set is empty, no more jobs to fetch.");
+    // Get the next downloading job that is due
+    let job_id = match jobs::next_due(now()) {
+        Some(job) => job,
+        None => {
+            ic_cdk::api::print("No job is due, no more jobs to fetch.");
             return;
         }
+    };
 
-        let item_to_remove = set.iter().next().cloned().unwrap();
-        if !set.remove(&item_to_remove) {
-            ic_cdk::api::print("Item not found in job set.");
-            return;
+    match stable::get_rate(&Pair::icp_usd(), Granularity::Minute, job_id) {
+        Some(_) => {
+            // If this job has already been downloaded. Only downloading it if doesn't already exist.
+            ic_cdk::api::print(format!(
+                "Rate for {} is already downloaded. Downloading the rest of the job again.",
+                job_id
+            ));
         }
+        None => {
+            // The requested time rate isn't found in map. Send a canister get_rate call to self
+            ic_cdk::api::print(format!("Fetching job {} now.", job_id));
+        }
+    }
+    fetch_job(job_id).await; // id 就是对应时间戳的价格
+}
+
+// 从所有数据源获取数据并聚合
+// Fetch the job from every configured source, and store the aggregated rate of each minute that
+// enough sources agree on. If any source failed, the job is retried later with a backoff.
+async fn fetch_job(job: Timestamp) {
+    let config = AGGREGATION.with(|config| config.borrow().clone());
+    let granularity = config::granularity();
+    let window = job..job + config::job_span();
+    let pair = Pair::icp_usd();
 
-        // Job is a valid
-        job_id = item_to_remove;
-
-        FETCHED.with(|fetched| {
-            match fetched.borrow().get(&item_to_remove) {
-                Some(_) => {
-                    // If this job has already been downloaded. Only downloading it if doesn't already exist.
-                    ic_cdk::api::print(format!(
-                        "Rate for {} is already downloaded. Skipping downloading again.",
-                        item_to_remove
-                    ));
-                    return;
+    // A retry only asks the sources that failed last time. The samples the others had for the
+    // minutes still without a rate were kept, see below.
+    let sources: Vec<Source> = match jobs::retry_sources(job) {
+        Some(retry) => retry
+            .into_iter()
+            .filter(|source| config.sources.contains(source))
+            .collect(),
+        None => config.sources.clone(),
+    };
+    // Sources quoting another currency can't be part of the pair's median, and the ones whose
+    // history doesn't reach the window anymore would only return newer minutes.
+    let sources: Vec<Source> = sources
+        .into_iter()
+        .filter(|source| source.quote() == pair.quote)
+        .filter(|source| source.oldest(now(), granularity) < window.end)
+        .collect();
+    // Reserve the whole job before the first outcall, get_rate settles each of them.
+    for source in sources.iter() {
+        cycles::reserve(outcall_cost(*source, job));
+    }
+    let mut samples: BTreeMap<Timestamp, Vec<(Source, Rate, Volume)>> =
+        stable::kept_samples(&pair, window.start, window.end)
+            .into_iter()
+            .collect();
+    let mut failed = vec![];
+    let mut errors = vec![];
+    for source in sources {
+        match get_rate(source, job).await {
+            Ok(rates) => {
+                for (timestamp, rate, volume) in rates {
+                    // Some sources return more than asked for, only keep the job's own window.
+                    if window.contains(&timestamp) {
+                        samples
+                            .entry(timestamp)
+                            .or_default()
+                            .push((source, rate, volume));
+                    }
                 }
-                None => {
-                    // The requested time rate isn't found in map. Send a canister get_rate call to self
-                    ic_cdk::api::print(format!("Fetching job {} now.", item_to_remove));
+            }
+            Err(error) => {
+                failed.push(source);
+                errors.push(format!("{:?}: {}", source, error));
+            }
+        }
+    }
+
+    let now = now();
+    let minute_cutoff =
+        RETENTION.with(|retention| retention.borrow().cutoff(Granularity::Minute, now));
+    // Minutes that could still get a rate if the failed sources answered
+    let mut unresolved = 0;
+    // The quorum of a minute only counts the sources that still serve it, see AggregationConfig::at.
+    let quorum = |minute: Timestamp| {
+        let config = config.at(minute, now, granularity);
+        let failing = failed
+            .iter()
+            .filter(|source| config.sources.contains(source))
+            .count();
+        (config, failing)
+    };
+
+    // The minutes none of the sources had a candle for are known gaps, unless the sources that
+    // failed are enough to agree on a rate for them.
+    let mut minute = window.start;
+    while minute < window.end && minute + granularity + GAP_SETTLE_TIME <= now {
+        if !samples.contains_key(&minute) {
+            let (config, failing) = quorum(minute);
+            if failing < config.min_sources as usize {
+                stable::mark_gap(&pair, minute, now);
+            } else if stable::get_rate(&pair, Granularity::Minute, minute).is_none() {
+                unresolved += 1;
+            }
+        }
+        minute += granularity;
+    }
+
+    stable::clear_samples(&pair, window.start, window.end);
+    // in ascending order of timestamps, so that the subscribers get the minutes in order
+    for (timestamp, rates) in samples {
+        let (config, failing) = quorum(timestamp);
+        match aggregate(&rates, &config) {
+            Some(aggregated) => {
+                stable::insert_rate(&pair, timestamp, &aggregated, minute_cutoff);
+                // The current minute may still change, only push the ones that are over.
+                if timestamp + granularity <= now {
+                    let candle = Candle {
+                        pair: pair.clone(),
+                        timestamp,
+                        rate: aggregated.rate,
+                        volume: aggregated.volume,
+                        confidence: aggregated.confidence,
+                    };
+                    feed::publish(candle, now);
                 }
             }
-        });
-    });
-    if job_id != 0 {
-        get_rate(job_id).await; // id 就是对应时间戳的价格
+            // Keep the samples until the failed sources are asked again.
+            None if failing > 0 && rates.len() + failing >= config.min_sources as usize => {
+                stable::keep_samples(&pair, timestamp, rates);
+                unresolved += 1;
+            }
+            None => ic_cdk::api::print(format!(
+                "Not enough sources agree on the rate for {}.",
+                timestamp
+            )),
+        }
+    }
+
+    // The most recent window may have changed
+    http::certify_recent(now);
+
+    // A source failing doesn't matter when the others had enough to aggregate every minute.
+    if failed.is_empty() || unresolved == 0 {
+        jobs::complete(job, now);
+    } else {
+        // Putting the job back into the queue to retry the sources that failed.
+        jobs::fail(job, errors.join("; "), failed, now);
+        if let JobState::Failed { .. } = jobs::state(job) {
+            stable::clear_samples(&pair, window.start, window.end);
+        }
+    }
+}
+
+// 订阅价格推送
+// Register the calling canister to be called with `method(Candle)` for every new minute of the pair.
+// Subscribing to the same pair again replaces the method.
+#[update]
+#[candid::candid_method(update)]
+fn subscribe(pair: Pair, method: String) -> Result<(), String> {
+    if pair != Pair::icp_usd() {
+        return Err("Only ICP/USD is tracked".to_string());
     }
+    feed::subscribe(Subscription {
+        subscriber: ic_cdk::caller(),
+        pair,
+        method,
+    })
+}
+
+#[update]
+#[candid::candid_method(update)]
+fn unsubscribe(pair: Pair) -> Result<(), String> {
+    feed::unsubscribe(ic_cdk::caller(), &pair)
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn my_subscriptions() -> Vec<Subscription> {
+    feed::subscriptions_of(ic_cdk::caller())
+}
+
+// 最新价格
+// The most recent finalized minute of the pair, and how many seconds ago it ended.
+#[query]
+#[candid::candid_method(query)]
+fn latest_rate(pair: Pair) -> Option<LatestRate> {
+    feed::latest(&pair, now())
+}
+
+// 推送一分钟的数据给订阅者，失败时稍后重试
+// The candle is sent as a one-way message, so a subscriber that is slow to reply, or never
+// replies, can't hold on to the canister's call contexts. Only failing to send it is retried.
+fn deliver(delivery: feed::Delivery) {
+    let result = ic_cdk::api::call::notify(
+        delivery.subscriber,
+        &delivery.method,
+        (delivery.candle.clone(),),
+    );
+    if let Err(r) = result {
+        ic_cdk::api::print(format!(
+            "Delivering {} to {} failed. RejectionCode: {:?}",
+            delivery.candle.timestamp, delivery.subscriber, r
+        ));
+        if !feed::retry(delivery, now()) {
+            ic_cdk::api::print("Giving up on the delivery.");
+        }
+    }
+}
+
+// 查询任务状态
+// The state of every job covering the time range, so that clients can tell whether missing data
+// is still pending, failed to be fetched, or isn't available upstream. At most MAX_JOB_STATUSES
+// jobs are returned, from the start of the range.
+#[query]
+#[candid::candid_method(query)]
+fn get_job_status(range: TimeRange) -> Vec<JobStatus> {
+    let pair = Pair::icp_usd();
+    // Minutes older than this have been pruned, so they can't be told apart from missing ones.
+    let cutoff = RETENTION.with(|retention| retention.borrow().cutoff(Granularity::Minute, now()));
+    let granularity = config::granularity();
+    let job_span = config::job_span();
+
+    let mut statuses = vec![];
+    let mut job = range.start / job_span * job_span;
+    while job < range.end && statuses.len() < MAX_JOB_STATUSES {
+        let state = match jobs::state(job) {
+            JobState::Complete { completed_at } => {
+                // Only the minutes that had passed when the job completed could be fetched.
+                let end = (job + job_span).min(completed_at / granularity * granularity);
+                let expected = end.saturating_sub(job) / granularity;
+                let stored = stable::get_range(&pair, Granularity::Minute, job, end).len() as u64;
+                if stored < expected && job >= cutoff {
+                    JobState::Unavailable {
+                        completed_at,
+                        missing: expected - stored,
+                    }
+                } else {
+                    JobState::Complete { completed_at }
+                }
+            }
+            state => state,
+        };
+        statuses.push(JobStatus { job, state });
+        job += job_span;
+    }
+    statuses
 }
 
 #[update]
@@ -258,7 +918,7 @@ async fn get_rates2() -> String {
             value: "exchange_rate_canister".to_string(),
         },
     ];
-    let url = format!("https://{host}/products/ICP-USD/candles?granularity={REMOTE_FETCH_GRANULARITY}&start={start_timestamp}&end={end_timestamp}");
+    let url = Source::Coinbase.url(start_timestamp, end_timestamp);
     ic_cdk::api::print(url.clone());
 
     // 构造请求头
@@ -266,7 +926,7 @@ async fn get_rates2() -> String {
         url,
         http_method: HttpMethod::GET,
         body: None,
-        max_response_bytes: Some(MAX_RESPONSE_BYTES),
+        max_response_bytes: Some(Source::Coinbase.max_response_bytes()),
         transform_method_name: Some("transform".to_string()),
         headers: request_headers,
     };
@@ -294,15 +954,14 @@ async fn get_rates2() -> String {
     }
 }
 
-// 获取某时间对应的数据
-// A function to call IC http_request function with sample interval of REMOTE_FETCH_GRANULARITY seconds. Each API
-// call fetches DATA_POINTS_PER_API data points, which is equivalent of DATA_POINTS_PER_API minutes of data.
-async fn get_rate(job: Timestamp) {
+// 构造请求
+// The http_request call fetching the job from the source, candid encoded.
+fn http_request_body(source: Source, job: Timestamp) -> Vec<u8> {
     let start_timestamp = job;
-    let end_timestamp = job + REMOTE_FETCH_GRANULARITY * DATA_POINTS_PER_API;
+    let end_timestamp = job + config::job_span();
 
-    let host = "api.exchange.coinbase.com";
-    let mut host_header = host.clone().to_owned();
+    let host = source.host();
+    let mut host_header = host.to_owned();
     host_header.push_str(":443");
     // prepare system http_request call
     let request_headers = vec![
@@ -315,87 +974,185 @@ async fn get_rate(job: Timestamp) {
             value: "exchange_rate_canister".to_string(),
         },
     ];
-    let url = format!("https://{host}/products/ICP-USD/candles?granularity={REMOTE_FETCH_GRANULARITY}&start={start_timestamp}&end={end_timestamp}");
-    ic_cdk::api::print(url.clone());
+    let url = source.url(start_timestamp, end_timestamp);
 
     // 构造请求头
     let request = CanisterHttpRequestArgs {
         url,
         http_method: HttpMethod::GET,
         body: None,
-        max_response_bytes: Some(MAX_RESPONSE_BYTES),
-        transform_method_name: Some("transform".to_string()),
+        max_response_bytes: Some(source.max_response_bytes()),
+        transform_method_name: Some(source.transform_method().to_string()),
         headers: request_headers,
     };
 
-    let body = candid::utils::encode_one(&request).unwrap(); // 包装请求头
-    ic_cdk::api::print(format!("Making IC http_request call {} now.", job));
+    candid::utils::encode_one(&request).unwrap() // 包装请求头
+}
+
+// 估算请求的费用
+fn outcall_cost(source: Source, job: Timestamp) -> u64 {
+    let body = http_request_body(source, job);
+    cycles::outcall_cost(body.len() as u64, source.max_response_bytes())
+}
+
+// 获取某时间对应的数据
+// A function to call IC http_request function with sample interval of REMOTE_FETCH_GRANULARITY seconds. Each API
+// call fetches DATA_POINTS_PER_API data points, which is equivalent of DATA_POINTS_PER_API minutes of data.
+// Returns an error if the remote request failed or its response couldn't be decoded.
+async fn get_rate(
+    source: Source,
+    job: Timestamp,
+) -> Result<Vec<(Timestamp, Rate, Volume)>, FetchError> {
+    let body = http_request_body(source, job);
+    let cost = cycles::outcall_cost(body.len() as u64, source.max_response_bytes());
+    ic_cdk::api::print(format!(
+        "Making IC http_request call {} to {:?} now, attaching {} cycles.",
+        job, source, cost
+    ));
 
     // 发起调用
-    match ic_cdk::api::call::call_raw(
+    let result = ic_cdk::api::call::call_raw(
         Principal::management_canister(),
         "http_request",
         &body[..],
-        0,
+        cost,
     )
-    .await
-    {
-        Ok(result) => {
+    .await;
+    // Whatever wasn't used is refunded
+    let spent = cost.saturating_sub(ic_cdk::api::call::msg_cycles_refunded());
+    cycles::settle(&Pair::icp_usd(), source, cost, spent, now());
+
+    let result = result
+        .map_err(|(r, m)| FetchError::Rejected(r, m))
+        .and_then(|result| {
             // 解码结果
             // decode the result
-            let decoded_result: CanisterHttpResponsePayload =
-                candid::utils::decode_one(&result).expect("IC http_request failed!");
-            // put the result to hashmap
-            FETCHED.with(|fetched| {
-                let mut fetched = fetched.borrow_mut();
-                let decoded_body = String::from_utf8(decoded_result.body)
-                    .expect("Remote service response is not UTF-8 encoded.");
-                decode_body_to_rates(&decoded_body, &mut fetched); // 解码响应体数据
-            });
-        }
-        Err((r, m)) => {
-            let message =
-                format!("The http_request resulted into error. RejectionCode: {r:?}, Error: {m}");
-            ic_cdk::api::print(message.clone());
-
-            // Since the remote request failed. Adding the de-queued job back again for retries.
-            add_job_to_job_set(job);
-        }
+            let response: CanisterHttpResponsePayload = candid::utils::decode_one(&result)
+                .map_err(|e| FetchError::Candid(e.to_string()))?;
+            if response.status != 200 {
+                record_http_error(source, response.status);
+                return Err(FetchError::HttpStatus(response.status));
+            }
+            // The transform already decoded and normalized the body of the source
+            transform::decode_response(response).map_err(FetchError::Decode) // 解码响应体数据
+        });
+    if let Err(error) = &result {
+        ic_cdk::api::print(format!(
+            "The http_request to {source:?} resulted into error: {error}"
+        ));
     }
+    result
 }
 
-fn decode_body_to_rates(body: &str, fetched: &mut RefMut<HashMap<u64, f32>>) {
-    //ic_cdk::api::print(format!("Got decoded result: {}", body));
-    let rates_array: Vec<Vec<Value>> = serde_json::from_str(&body).unwrap();
-    for rate in rates_array {
-        let timestamp = rate[0].as_u64().expect("Couldn't parse the timestamp.");
-        let close_rate = rate[4].as_f64().expect("Couldn't parse the rate.");
-        fetched.insert(timestamp as Timestamp, close_rate as Rate);
-    }
+// 记录非 200 响应
+fn record_http_error(source: Source, status: u64) {
+    HTTP_ERRORS.with(|errors| {
+        let mut errors = errors.borrow_mut();
+        let entry = errors.entry((source, status)).or_insert(HttpErrorCount {
+            source,
+            status,
+            count: 0,
+            last_seen: 0,
+        });
+        entry.count += 1;
+        entry.last_seen = now();
+    });
 }
 
+// 各数据源非 200 响应的次数
+// Counted since the last upgrade.
 #[query]
-async fn transform(raw: CanisterHttpResponsePayload) -> CanisterHttpResponsePayload {
-    let mut sanitized = raw.clone();
-    let mut processed_headers = vec![];
-    for header in raw.headers.iter() {
-        if !RESPONSE_HEADERS_SANITIZATION.contains(&header.name.as_str()) {
-            processed_headers.insert(0, header.clone());
-        }
+#[candid::candid_method(query)]
+fn get_http_errors() -> Vec<HttpErrorCount> {
+    HTTP_ERRORS.with(|errors| errors.borrow().values().cloned().collect())
+}
+
+// 处理响应，使所有副本得到相同的结果
+// Only keep the allow-listed headers, and leave the body as it is.
+#[query]
+fn transform(raw: CanisterHttpResponsePayload) -> CanisterHttpResponsePayload {
+    CanisterHttpResponsePayload {
+        status: raw.status,
+        headers: transform::sanitize_headers(&raw.headers),
+        body: raw.body,
     }
-    sanitized.headers = processed_headers;
-    return sanitized;
 }
 
+// Each source has its own transform, which also normalizes the body, see transform::transform_response.
+#[query]
+fn transform_coinbase(raw: CanisterHttpResponsePayload) -> CanisterHttpResponsePayload {
+    transform::transform_response(Source::Coinbase, raw)
+}
+
+#[query]
+fn transform_binance(raw: CanisterHttpResponsePayload) -> CanisterHttpResponsePayload {
+    transform::transform_response(Source::Binance, raw)
+}
+
+#[query]
+fn transform_kraken(raw: CanisterHttpResponsePayload) -> CanisterHttpResponsePayload {
+    transform::transform_response(Source::Kraken, raw)
+}
+
+// The rates and the pending jobs live in stable memory already, only the configuration and the
+// price feed are saved here.
 #[pre_upgrade]
 fn pre_upgrade() {
-    FETCHED.with(|fetched| storage::stable_save((fetched,)).unwrap());
+    stable::save_state(StableState {
+        owner: Some(OWNER.with(|owner| *owner.borrow())),
+        aggregation: AGGREGATION.with(|config| config.borrow().clone()),
+        retention: RETENTION.with(|retention| retention.borrow().clone()),
+        feed: Some(feed::save()),
+        cycles: Some(cycles::save()),
+        fetch: Some(config::get()),
+        tracking: Some(tracking::save()),
+    });
 }
 
 #[post_upgrade]
 fn post_upgrade() {
-    let (old_fetched,): (HashMap<Timestamp, Rate>,) = storage::stable_restore().unwrap();
-    FETCHED.with(|fetched| *fetched.borrow_mut() = old_fetched);
+    match stable::take_legacy_rates() {
+        Some(rates) => migrate_legacy_rates(rates),
+        None => restore_state(),
+    }
+    // The certified data doesn't survive upgrades
+    http::certify_recent(now());
+}
+
+fn restore_state() {
+    let state = stable::load_state();
+    if let Some(old_owner) = state.owner {
+        OWNER.with(|owner| *owner.borrow_mut() = old_owner);
+    }
+    // Versions before the quote currencies were checked aggregated Binance's USDT rates as well.
+    let aggregation = match state.aggregation.validate() {
+        Ok(()) => state.aggregation,
+        Err(error) => {
+            ic_cdk::api::print(format!("Resetting the aggregation config: {}", error));
+            AggregationConfig::default()
+        }
+    };
+    AGGREGATION.with(|config| *config.borrow_mut() = aggregation);
+    RETENTION.with(|retention| *retention.borrow_mut() = state.retention);
+    feed::restore(state.feed.unwrap_or_default());
+    cycles::restore(state.cycles.unwrap_or_default());
+    tracking::restore(state.tracking.unwrap_or_default());
+    if let Some(fetch) = state.fetch {
+        config::set(fetch).expect("Invalid fetch configuration");
+    }
+}
+
+// 从旧版本迁移
+// Move the minutes a version with the rates on the heap had fetched into the stable structures.
+// That version had no owner, so the controller upgrading it becomes one, and everything else it
+// didn't have starts from its defaults.
+fn migrate_legacy_rates(rates: Vec<(Timestamp, AggregatedRate)>) {
+    OWNER.with(|owner| *owner.borrow_mut() = ic_cdk::caller());
+    let pair = Pair::icp_usd();
+    let cutoff = RETENTION.with(|retention| retention.borrow().cutoff(Granularity::Minute, now()));
+    for (timestamp, rate) in rates {
+        stable::insert_rate(&pair, timestamp, &rate, cutoff);
+    }
 }
 
 #[cfg(any(target_arch = "wasm32", test))]
@@ -410,6 +1167,7 @@ fn main() {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashMap;
 
     #[test]
     fn test_decode_body_to_rates() {
@@ -441,10 +1199,56 @@ mod tests {
     ]
 ]
         ";
-        let results = RefCell::new(HashMap::<Timestamp, Rate>::new());
-        let mut fetched = results.borrow_mut();
-        decode_body_to_rates(body, &mut fetched);
+        let fetched: HashMap<Timestamp, Rate> = Source::Coinbase
+            .decode_body_to_rates(body)
+            .unwrap()
+            .into_iter()
+            .map(|(timestamp, rate, _)| (timestamp, rate))
+            .collect();
         assert!(fetched.len() == 3);
         assert!(fetched.get(&1652454180) == Some(&(9.56 as f32)));
     }
+
+    #[test]
+    fn test_gaps_are_not_missing_and_can_be_filled() {
+        let pair = Pair::icp_usd();
+        let rate = |rate| aggregate::test_rate(rate, 1.0);
+        let start = 1_800_000;
+        stable::insert_rate(&pair, start, &rate(1.0), 0);
+        stable::mark_gap(&pair, start + 60, start + 1_000);
+        stable::mark_gap(&pair, start + 120, start + 1_000);
+        stable::insert_rate(&pair, start + 180, &rate(2.0), 0);
+
+        let range = TimeRange {
+            start,
+            end: start + 300,
+        };
+        let stored = stored_rates(&pair, &range, start + 1_000);
+        assert_eq!(stored.gaps, vec![start + 60, start + 120]);
+        // Only the last minute was never fetched
+        assert_eq!(
+            stored.missing,
+            vec![TimeRange {
+                start: start + 240,
+                end: start + 300
+            }]
+        );
+        assert_eq!((stored.buckets, stored.covered), (5, 2));
+
+        let filled = forward_fill(stored.rates, &stored.gaps);
+        let closes: Vec<_> = filled
+            .iter()
+            .map(|(timestamp, rate)| (*timestamp, rate.close))
+            .collect();
+        assert_eq!(
+            closes,
+            vec![
+                (start, 1.0),
+                (start + 60, 1.0),
+                (start + 120, 1.0),
+                (start + 180, 2.0)
+            ]
+        );
+        assert_eq!(filled[1].1.volume, 0.0);
+    }
 }
@@ -0,0 +1,205 @@
+use crate::aggregate::RateConfidence;
+use crate::stable::StoredRate;
+use crate::{Rate, RatesWithInterval, Timestamp, MAX_DATA_POINTS_CANISTER_RESPONSE};
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+// The sampling intervals are whole minutes, whatever the fetch granularity.
+const MINUTE: u64 = 60;
+
+// 采样方式
+// How the rates falling into the same bucket are combined into one data point.
+#[derive(CandidType, Clone, Copy, Deserialize, Debug, Eq, PartialEq, Serialize)]
+pub enum SampleMode {
+    // The mean of all rates in the bucket
+    Average,
+    // The last (latest) rate in the bucket, like the close of a candle
+    Last,
+}
+
+#[derive(CandidType, Clone, Deserialize, Debug, PartialEq, Serialize)]
+pub struct SampleOptions {
+    pub mode: SampleMode,
+
+    // The maximum number of data points to return, capped at MAX_DATA_POINTS_CANISTER_RESPONSE
+    pub max_points: Option<u64>,
+
+    // Fill the minutes known to have no data with the previous rate, see data_quality
+    pub forward_fill: Option<bool>,
+}
+
+impl Default for SampleOptions {
+    fn default() -> Self {
+        SampleOptions {
+            mode: SampleMode::Last,
+            max_points: None,
+            forward_fill: None,
+        }
+    }
+}
+
+// 选取采样间隔
+// Pick the smallest bucket width (in minutes) of at least min_minutes that keeps the number of
+// non-empty buckets within max_points. The points must be sorted by timestamp.
+fn choose_interval(points: &[(Timestamp, StoredRate)], min_minutes: u64, max_points: usize) -> u64 {
+    let interval_options = vec![
+        1,       // 1 data point every minute
+        5,       // 1 data point every 5 minutes
+        15,      // 1 data point every 15 minutes
+        60,      // 1 data point every hour
+        60 * 12, // 1 data point every 12 hours
+        60 * 24, // 1 data point every day
+    ];
+    let bucket_count = |minutes: u64| {
+        let width = minutes * MINUTE;
+        let mut count = 0;
+        let mut last_bucket = None;
+        for (timestamp, _) in points {
+            let bucket = timestamp / width;
+            if last_bucket != Some(bucket) {
+                count += 1;
+                last_bucket = Some(bucket);
+            }
+        }
+        count
+    };
+
+    for minutes in interval_options
+        .iter()
+        .filter(|minutes| **minutes >= min_minutes)
+    {
+        if bucket_count(*minutes) <= max_points {
+            return *minutes;
+        }
+    }
+    // Even one data point a day is too much, keep doubling the width until the points fit.
+    let mut minutes = interval_options[interval_options.len() - 1].max(min_minutes);
+    while bucket_count(minutes) > max_points {
+        minutes *= 2;
+    }
+    minutes
+}
+
+// 合并同一个桶里的数据
+// Rolled up rates count as many times as the minutes they contain when averaging.
+fn combine(bucket: &[(Timestamp, StoredRate)], mode: SampleMode) -> (Rate, RateConfidence) {
+    match mode {
+        SampleMode::Last => {
+            let last = &bucket[bucket.len() - 1].1;
+            (last.close, last.confidence.clone())
+        }
+        SampleMode::Average => {
+            let count: u32 = bucket.iter().map(|(_, r)| r.count).sum();
+            let sum: f32 = bucket.iter().map(|(_, r)| r.average * r.count as f32).sum();
+            let mut confidence = bucket[0].1.confidence.clone();
+            for (_, r) in &bucket[1..] {
+                confidence.merge(&r.confidence);
+            }
+            (sum / count.max(1) as f32, confidence)
+        }
+    }
+}
+
+// 按时间桶降采样
+// Down-sample the points into buckets aligned to multiples of the interval, which is at least
+// min_interval seconds. Each bucket is returned as one data point stamped with the start of the
+// bucket, in ascending order. The points must be sorted by timestamp.
+pub fn sample_with_interval(
+    points: Vec<(Timestamp, StoredRate)>,
+    min_interval: u64,
+    options: &SampleOptions,
+) -> RatesWithInterval {
+    // in order to make sure that returned data do not exceed 2MB, which is about
+    // ~1M data points, never return more than MAX_DATA_POINTS_CANISTER_RESPONSE buckets.
+    let max_points = options
+        .max_points
+        .map(|max| max.max(1) as usize)
+        .unwrap_or(MAX_DATA_POINTS_CANISTER_RESPONSE)
+        .min(MAX_DATA_POINTS_CANISTER_RESPONSE);
+    let minutes = choose_interval(&points, min_interval / MINUTE, max_points);
+    let width = minutes * MINUTE;
+
+    let mut rates = vec![];
+    let mut confidence = vec![];
+    let mut start = 0;
+    while start < points.len() {
+        let bucket = points[start].0 / width;
+        let mut end = start + 1;
+        while end < points.len() && points[end].0 / width == bucket {
+            end += 1;
+        }
+        let (rate, rate_confidence) = combine(&points[start..end], options.mode);
+        rates.push((bucket * width, rate));
+        confidence.push((bucket * width, rate_confidence));
+        start = end;
+    }
+
+    RatesWithInterval {
+        interval: width as usize,
+        rates,
+        confidence,
+        missing: vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aggregate::test_rate;
+
+    fn point(timestamp: Timestamp, rate: f32) -> (Timestamp, StoredRate) {
+        (timestamp, StoredRate::new(timestamp, &test_rate(rate, 0.0)))
+    }
+
+    #[test]
+    fn test_sample_with_interval_buckets() {
+        // 10 minutes starting in the middle of a 5 minutes bucket
+        let points: Vec<_> = (0..10)
+            .map(|i| point(1_800_180 + i * 60, i as f32))
+            .collect();
+
+        let options = SampleOptions {
+            mode: SampleMode::Last,
+            max_points: Some(3),
+            forward_fill: None,
+        };
+        let sampled = sample_with_interval(points.clone(), 60, &options);
+        assert_eq!(sampled.interval, 300);
+        assert_eq!(
+            sampled.rates,
+            vec![(1_800_000, 1.0), (1_800_300, 6.0), (1_800_600, 9.0)]
+        );
+
+        let options = SampleOptions {
+            mode: SampleMode::Average,
+            max_points: Some(3),
+            forward_fill: None,
+        };
+        let sampled = sample_with_interval(points.clone(), 60, &options);
+        assert_eq!(
+            sampled.rates,
+            vec![(1_800_000, 0.5), (1_800_300, 4.0), (1_800_600, 8.0)]
+        );
+
+        let sampled = sample_with_interval(points, 60, &SampleOptions::default());
+        assert_eq!(sampled.interval, 60);
+        assert_eq!(sampled.rates.len(), 10);
+    }
+
+    #[test]
+    fn test_sample_with_interval_weights_rollups() {
+        // An hourly rollup of 60 minutes followed by a single minute
+        let mut hour = point(1_800_000, 1.0);
+        hour.1.count = 60;
+        let points = vec![hour, point(1_803_600, 3.0)];
+        let options = SampleOptions {
+            mode: SampleMode::Average,
+            max_points: Some(1),
+            forward_fill: None,
+        };
+        let sampled = sample_with_interval(points, 3600, &options);
+        assert_eq!(sampled.interval, 12 * 3600);
+        assert_eq!(sampled.rates.len(), 1);
+        assert!((sampled.rates[0].1 - 63.0 / 61.0).abs() < 1e-4);
+    }
+}
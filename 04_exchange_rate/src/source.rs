@@ -0,0 +1,394 @@
+use crate::{config, Rate, Timestamp, Volume};
+use candid::CandidType;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{self, Value};
+use std::fmt;
+
+// Kraken 每次返回的最大数据量
+// Kraken's OHLC endpoint ignores the end of the window and always returns up to 720 candles.
+pub const KRAKEN_MAX_DATA_POINTS: u64 = 720;
+
+// 交易对
+// A currency pair, e.g. ICP/USD. Symbols are upper case ASCII of at most MAX_SYMBOL_LEN bytes,
+// which is checked when a pair is decoded from an argument, see Pair::new.
+#[derive(
+    CandidType, Clone, Deserialize, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize,
+)]
+#[serde(try_from = "UncheckedPair")]
+pub struct Pair {
+    pub base: String,
+    pub quote: String,
+}
+
+pub const MAX_SYMBOL_LEN: usize = 8;
+
+impl Pair {
+    pub fn new(base: String, quote: String) -> Result<Pair, String> {
+        for symbol in [&base, &quote] {
+            let valid = symbol
+                .chars()
+                .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit());
+            if symbol.is_empty() || symbol.len() > MAX_SYMBOL_LEN || !valid {
+                return Err(format!(
+                    "Symbols must be 1 to {} upper case letters or digits, got {:?}",
+                    MAX_SYMBOL_LEN, symbol
+                ));
+            }
+        }
+        Ok(Pair { base, quote })
+    }
+
+    pub fn icp_usd() -> Pair {
+        Pair {
+            base: "ICP".to_string(),
+            quote: "USD".to_string(),
+        }
+    }
+}
+
+// 未经检查的交易对，解码后转换为 Pair
+#[derive(Deserialize)]
+struct UncheckedPair {
+    base: String,
+    quote: String,
+}
+
+impl TryFrom<UncheckedPair> for Pair {
+    type Error = String;
+
+    fn try_from(pair: UncheckedPair) -> Result<Self, Self::Error> {
+        Pair::new(pair.base, pair.quote)
+    }
+}
+
+// 数据源
+// Upstream exchanges the canister can pull ICP/USD candles from.
+#[derive(
+    CandidType, Clone, Copy, Deserialize, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize,
+)]
+pub enum Source {
+    Coinbase,
+    Binance,
+    Kraken,
+}
+
+impl Source {
+    pub fn all() -> Vec<Source> {
+        vec![Source::Coinbase, Source::Binance, Source::Kraken]
+    }
+
+    // 报价货币
+    // The currency the source quotes ICP in. Binance only has a USDT market, which isn't the same
+    // as USD, so it can't be aggregated into the ICP/USD rate with the others.
+    pub fn quote(&self) -> &'static str {
+        match self {
+            Source::Coinbase => "USD",
+            Source::Binance => "USDT",
+            Source::Kraken => "USD",
+        }
+    }
+
+    // The sources quoting ICP in the currency
+    pub fn quoting(quote: &str) -> Vec<Source> {
+        Source::all()
+            .into_iter()
+            .filter(|source| source.quote() == quote)
+            .collect()
+    }
+
+    pub fn host(&self) -> &'static str {
+        match self {
+            Source::Coinbase => "api.exchange.coinbase.com",
+            Source::Binance => "api.binance.com",
+            Source::Kraken => "api.kraken.com",
+        }
+    }
+
+    // 处理响应的方法
+    // The query that normalizes the responses of the source, see transform::transform_response.
+    pub fn transform_method(&self) -> &'static str {
+        match self {
+            Source::Coinbase => "transform_coinbase",
+            Source::Binance => "transform_binance",
+            Source::Kraken => "transform_kraken",
+        }
+    }
+
+    // 构造请求地址
+    // The candles endpoint of the source for the window [start, end) in seconds, at the configured
+    // granularity.
+    pub fn url(&self, start: Timestamp, end: Timestamp) -> String {
+        let host = self.host();
+        let config = config::get();
+        let granularity = config.granularity;
+        let data_points = config.data_points_per_api;
+        match self {
+            Source::Coinbase => format!("https://{host}/products/ICP-USD/candles?granularity={granularity}&start={start}&end={end}"),
+            Source::Binance => format!(
+                "https://{host}/api/v3/klines?symbol=ICPUSDT&interval={}&startTime={}&endTime={}&limit={data_points}",
+                binance_interval(granularity),
+                start * 1000,
+                end * 1000
+            ),
+            Source::Kraken => format!(
+                "https://{host}/0/public/OHLC?pair=ICPUSD&interval={}&since={start}",
+                granularity / 60
+            ),
+        }
+    }
+
+    // 数据源保留的最早时间
+    // The oldest minute the source still serves at `now`. Kraken only returns its most recent
+    // KRAKEN_MAX_DATA_POINTS candles whatever the start of the window, the current one included.
+    pub fn oldest(&self, now: Timestamp, granularity: u64) -> Timestamp {
+        match self {
+            Source::Kraken => now.saturating_sub((KRAKEN_MAX_DATA_POINTS - 1) * granularity),
+            Source::Coinbase | Source::Binance => 0,
+        }
+    }
+
+    // 每次调用最多返回的数据量
+    pub fn max_data_points(&self) -> u64 {
+        match self {
+            Source::Coinbase => 300,
+            Source::Binance => 1000,
+            Source::Kraken => KRAKEN_MAX_DATA_POINTS,
+        }
+    }
+
+    // 最大响应数据量
+    // Maximum raw response size of a single call, see MAX_RESPONSE_BYTES for how Coinbase's is derived.
+    // Binance returns 12 fields per kline, most of them quoted strings of less than 20 bytes.
+    // Kraken returns 8 fields per candle, and always up to KRAKEN_MAX_DATA_POINTS candles.
+    pub fn max_response_bytes(&self) -> u64 {
+        let config = config::get();
+        match self {
+            Source::Coinbase => config.max_response_bytes,
+            Source::Binance => 20 * 12 * config.data_points_per_api,
+            Source::Kraken => 15 * 8 * KRAKEN_MAX_DATA_POINTS,
+        }
+    }
+
+    // 解码响应体，取得收盘价和成交量
+    // Extract (timestamp in seconds, close rate, volume in the base currency) from the response
+    // body of the source.
+    pub fn decode_body_to_rates(
+        &self,
+        body: &str,
+    ) -> Result<Vec<(Timestamp, Rate, Volume)>, DecodeError> {
+        match self {
+            Source::Coinbase => {
+                // [[time, low, high, open, close, volume], ...]
+                let rates_array: Vec<Vec<Value>> = parse_json(body)?;
+                rates_array
+                    .iter()
+                    .enumerate()
+                    .map(|(row, rate)| {
+                        let timestamp = field(rate, row, 0, "timestamp", Value::as_u64)?;
+                        let close_rate = field(rate, row, 4, "close", Value::as_f64)?;
+                        let volume = field(rate, row, 5, "volume", Value::as_f64)?;
+                        Ok((timestamp as Timestamp, close_rate as Rate, volume))
+                    })
+                    .collect()
+            }
+            Source::Binance => {
+                // [[open time in ms, "open", "high", "low", "close", "volume", close time in ms, ...], ...]
+                let rates_array: Vec<Vec<Value>> = parse_json(body)?;
+                rates_array
+                    .iter()
+                    .enumerate()
+                    .map(|(row, rate)| {
+                        let timestamp = field(rate, row, 0, "timestamp", Value::as_u64)?;
+                        Ok((
+                            timestamp / 1000,
+                            field(rate, row, 4, "close", parse_quoted)? as Rate,
+                            field(rate, row, 5, "volume", parse_quoted)?,
+                        ))
+                    })
+                    .collect()
+            }
+            Source::Kraken => {
+                // {"error": [], "result": {"ICPUSD": [[time, "open", "high", "low", "close", "vwap", "volume", count], ...], "last": time}}
+                let response: Value = parse_json(body)?;
+                if let Some(errors) = response["error"].as_array() {
+                    if !errors.is_empty() {
+                        return Err(DecodeError::Upstream(
+                            Value::Array(errors.clone()).to_string(),
+                        ));
+                    }
+                }
+                let result = response["result"]
+                    .as_object()
+                    .ok_or_else(|| DecodeError::InvalidJson("no result".to_string()))?;
+                let mut rates = vec![];
+                for (name, candles) in result.iter().filter(|(name, _)| name.as_str() != "last") {
+                    let candles = candles.as_array().ok_or_else(|| {
+                        DecodeError::InvalidJson(format!("{} isn't a list", name))
+                    })?;
+                    for (row, rate) in candles.iter().enumerate() {
+                        let rate = rate.as_array().ok_or(DecodeError::InvalidField {
+                            row,
+                            field: "candle",
+                        })?;
+                        rates.push((
+                            field(rate, row, 0, "timestamp", Value::as_u64)?,
+                            field(rate, row, 4, "close", parse_quoted)? as Rate,
+                            field(rate, row, 6, "volume", parse_quoted)?,
+                        ));
+                    }
+                }
+                Ok(rates)
+            }
+        }
+    }
+}
+
+// Binance 的粒度写法，例如 1m 或 1h
+fn binance_interval(granularity: u64) -> String {
+    if granularity % (60 * 60) == 0 {
+        format!("{}h", granularity / (60 * 60))
+    } else {
+        format!("{}m", granularity / 60)
+    }
+}
+
+// 解码错误
+#[derive(Clone, Debug, PartialEq)]
+pub enum DecodeError {
+    // The body isn't valid UTF-8
+    NotUtf8,
+    // The body isn't JSON, or not shaped like the responses of the source
+    InvalidJson(String),
+    // A field of a candle is missing or of the wrong type
+    InvalidField { row: usize, field: &'static str },
+    // The source answered with an error in a successful response
+    Upstream(String),
+    // Decoding already failed in the transform, which only passes the message on
+    Transform(String),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::NotUtf8 => write!(f, "The response is not UTF-8 encoded"),
+            DecodeError::InvalidJson(error) => write!(f, "Invalid JSON response: {}", error),
+            DecodeError::InvalidField { row, field } => {
+                write!(f, "Invalid {} in row {} of the response", field, row)
+            }
+            DecodeError::Upstream(error) => write!(f, "The source returned an error: {}", error),
+            DecodeError::Transform(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+fn parse_json<T: DeserializeOwned>(body: &str) -> Result<T, DecodeError> {
+    serde_json::from_str(body).map_err(|e| DecodeError::InvalidJson(e.to_string()))
+}
+
+// 读取一行中的一个字段
+fn field<T>(
+    rate: &[Value],
+    row: usize,
+    index: usize,
+    name: &'static str,
+    parse: impl Fn(&Value) -> Option<T>,
+) -> Result<T, DecodeError> {
+    rate.get(index)
+        .and_then(parse)
+        .ok_or(DecodeError::InvalidField { row, field: name })
+}
+
+// Binance 和 Kraken 的价格和成交量是字符串
+fn parse_quoted(value: &Value) -> Option<f64> {
+    value.as_str().and_then(|number| number.parse::<f64>().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pairs_are_checked_when_decoded() {
+        let pair: Pair = serde_json::from_str(r#"{"base": "ICP", "quote": "USD"}"#).unwrap();
+        assert_eq!(pair, Pair::icp_usd());
+        for (base, quote) in [("ICP", "USDOLLARS"), ("", "USD"), ("icp", "USD")] {
+            let json = format!(r#"{{"base": "{}", "quote": "{}"}}"#, base, quote);
+            assert!(serde_json::from_str::<Pair>(&json).is_err());
+        }
+    }
+
+    #[test]
+    fn test_decode_binance_klines() {
+        let body = r#"[
+            [1652454180000, "9.55", "9.58", "9.54", "9.56", "1930.12", 1652454239999, "18452.3", 120, "900.1", "8600.2", "0"],
+            [1652454240000, "9.55", "9.55", "9.51", "9.52", "2385.97", 1652454299999, "22731.1", 98, "1100.4", "10480.7", "0"]
+        ]"#;
+        let rates = Source::Binance.decode_body_to_rates(body).unwrap();
+        assert_eq!(
+            rates,
+            vec![(1652454180, 9.56, 1930.12), (1652454240, 9.52, 2385.97)]
+        );
+    }
+
+    #[test]
+    fn test_decode_kraken_ohlc() {
+        let body = r#"{
+            "error": [],
+            "result": {
+                "ICPUSD": [
+                    [1652454180, "9.55", "9.58", "9.54", "9.57", "9.56", "1930.12", 12],
+                    [1652454240, "9.55", "9.55", "9.51", "9.53", "9.53", "2385.97", 9]
+                ],
+                "last": 1652454240
+            }
+        }"#;
+        let rates = Source::Kraken.decode_body_to_rates(body).unwrap();
+        assert_eq!(
+            rates,
+            vec![(1652454180, 9.57, 1930.12), (1652454240, 9.53, 2385.97)]
+        );
+    }
+
+    #[test]
+    fn test_decode_malformed_bodies() {
+        // Cut off by max_response_bytes
+        assert!(matches!(
+            Source::Coinbase.decode_body_to_rates("[[1652454180, 9.54, 9.58"),
+            Err(DecodeError::InvalidJson(_))
+        ));
+        // An error message instead of candles
+        assert!(matches!(
+            Source::Coinbase.decode_body_to_rates(r#"{"message": "Invalid start"}"#),
+            Err(DecodeError::InvalidJson(_))
+        ));
+        assert_eq!(
+            Source::Coinbase.decode_body_to_rates("[[1652454180, 9.54, 9.58, 9.55, 9.56]]"),
+            Err(DecodeError::InvalidField {
+                row: 0,
+                field: "volume"
+            })
+        );
+        assert_eq!(
+            Source::Binance.decode_body_to_rates(
+                r#"[[1652454180000, "9.55", "9.58", "9.54", "9.56", "1930.12"], [1652454240000, "9.55", "9.55", "9.51", 9.52, "2385.97"]]"#
+            ),
+            Err(DecodeError::InvalidField {
+                row: 1,
+                field: "close"
+            })
+        );
+        assert_eq!(
+            Source::Kraken.decode_body_to_rates(r#"{"error": ["EQuery:Unknown asset pair"]}"#),
+            Err(DecodeError::Upstream(
+                r#"["EQuery:Unknown asset pair"]"#.to_string()
+            ))
+        );
+        assert!(matches!(
+            Source::Kraken.decode_body_to_rates(r#"{"error": [], "result": {"ICPUSD": 1}}"#),
+            Err(DecodeError::InvalidJson(_))
+        ));
+        // Nothing traded yet is not an error
+        assert_eq!(Source::Binance.decode_body_to_rates("[]"), Ok(vec![]));
+    }
+}
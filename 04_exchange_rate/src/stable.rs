@@ -0,0 +1,879 @@
+use crate::aggregate::{AggregatedRate, AggregationConfig, RateConfidence};
+use crate::config::FetchConfig;
+use crate::cycles::CyclesState;
+use crate::feed::FeedState;
+use crate::source::{Pair, Source, MAX_SYMBOL_LEN};
+use crate::tracking::TrackingState;
+use crate::{config, Rate, Timestamp, Volume};
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{
+    BoundedStorable, DefaultMemoryImpl, StableBTreeMap, StableCell, Storable,
+};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::Bound::{Excluded, Unbounded};
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// 稳定内存的分区
+// Every structure below lives in its own virtual memory, so they can grow independently.
+const STATE_MEMORY_ID: u8 = 0;
+const MINUTE_RATES_MEMORY_ID: u8 = 1;
+const HOUR_RATES_MEMORY_ID: u8 = 2;
+const DAY_RATES_MEMORY_ID: u8 = 3;
+pub const JOBS_MEMORY_ID: u8 = 4;
+pub const QUEUE_MEMORY_ID: u8 = 5;
+pub const DEAD_LETTERS_MEMORY_ID: u8 = 6;
+pub const COMPLETED_MEMORY_ID: u8 = 7;
+const GAPS_MEMORY_ID: u8 = 8;
+const SAMPLES_MEMORY_ID: u8 = 9;
+
+// 数据粒度
+// Rates are fetched per minute, and rolled up into hourly and daily buckets so that they can be
+// kept for longer than the minutes themselves.
+#[derive(CandidType, Clone, Copy, Deserialize, Debug, Eq, PartialEq, Serialize)]
+pub enum Granularity {
+    // The configured fetch granularity, which is a minute unless changed, see FetchConfig
+    Minute,
+    Hour,
+    Day,
+}
+
+impl Granularity {
+    // From the finest to the coarsest
+    pub fn all() -> [Granularity; 3] {
+        [Granularity::Minute, Granularity::Hour, Granularity::Day]
+    }
+
+    pub fn seconds(&self) -> u64 {
+        match self {
+            Granularity::Minute => config::granularity(),
+            Granularity::Hour => 60 * 60,
+            Granularity::Day => 24 * 60 * 60,
+        }
+    }
+}
+
+// 保留策略
+// How many seconds of data to keep for each granularity. None keeps the data forever.
+#[derive(CandidType, Clone, Deserialize, Debug, PartialEq, Serialize)]
+pub struct RetentionPolicy {
+    pub minute: Option<u64>,
+    pub hour: Option<u64>,
+    pub day: Option<u64>,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy {
+            minute: Some(30 * 24 * 60 * 60),    // 30 days of minutes
+            hour: Some(2 * 365 * 24 * 60 * 60), // 2 years of hours
+            day: None,                          // days are kept forever
+        }
+    }
+}
+
+impl RetentionPolicy {
+    pub fn get(&self, granularity: Granularity) -> Option<u64> {
+        match granularity {
+            Granularity::Minute => self.minute,
+            Granularity::Hour => self.hour,
+            Granularity::Day => self.day,
+        }
+    }
+
+    // 过期时间点，早于该时间的数据会被删除
+    pub fn cutoff(&self, granularity: Granularity, now: Timestamp) -> Timestamp {
+        self.get(granularity)
+            .map(|keep| now.saturating_sub(keep))
+            .unwrap_or(0)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let mut finer: Option<u64> = Some(0);
+        for granularity in Granularity::all() {
+            let keep = self.get(granularity);
+            if let Some(keep) = keep {
+                if keep < granularity.seconds() {
+                    return Err(format!(
+                        "{:?} data must be kept for at least {} seconds",
+                        granularity,
+                        granularity.seconds()
+                    ));
+                }
+            }
+            // A coarser granularity must be kept at least as long as a finer one.
+            match (finer, keep) {
+                (None, Some(_)) => {
+                    return Err(format!(
+                        "{:?} data is kept shorter than finer data",
+                        granularity
+                    ))
+                }
+                (Some(finer), Some(keep)) if keep < finer => {
+                    return Err(format!(
+                        "{:?} data is kept shorter than finer data",
+                        granularity
+                    ))
+                }
+                _ => {}
+            }
+            finer = keep;
+        }
+        Ok(())
+    }
+}
+
+// 存储的键：交易对 + 时间戳
+// Keys are ordered by pair first, so that the rates of a pair are contiguous and sorted by time.
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct RateKey {
+    pub pair: Pair,
+    pub timestamp: Timestamp,
+}
+
+impl Storable for RateKey {
+    // Symbols are zero padded to MAX_SYMBOL_LEN, and the timestamp is big endian, so that the
+    // byte order matches the order of the keys.
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = vec![0; 2 * MAX_SYMBOL_LEN + 8];
+        bytes[..self.pair.base.len()].copy_from_slice(self.pair.base.as_bytes());
+        bytes[MAX_SYMBOL_LEN..MAX_SYMBOL_LEN + self.pair.quote.len()]
+            .copy_from_slice(self.pair.quote.as_bytes());
+        bytes[2 * MAX_SYMBOL_LEN..].copy_from_slice(&self.timestamp.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let symbol = |bytes: &[u8]| {
+            String::from_utf8(bytes.iter().cloned().take_while(|b| *b != 0).collect()).unwrap()
+        };
+        let mut timestamp = [0; 8];
+        timestamp.copy_from_slice(&bytes[2 * MAX_SYMBOL_LEN..]);
+        RateKey {
+            pair: Pair {
+                base: symbol(&bytes[..MAX_SYMBOL_LEN]),
+                quote: symbol(&bytes[MAX_SYMBOL_LEN..2 * MAX_SYMBOL_LEN]),
+            },
+            timestamp: u64::from_be_bytes(timestamp),
+        }
+    }
+}
+
+impl BoundedStorable for RateKey {
+    const MAX_SIZE: u32 = 2 * MAX_SYMBOL_LEN as u32 + 8;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+// 存储的数据
+// A minute, or a bucket of minutes rolled up into an hour or a day.
+#[derive(CandidType, Clone, Deserialize, Debug, PartialEq, Serialize)]
+pub struct StoredRate {
+    // The rate of the earliest minute in the bucket
+    pub open: Rate,
+    pub open_timestamp: Timestamp,
+
+    // The rate of the latest minute in the bucket
+    pub close: Rate,
+    pub close_timestamp: Timestamp,
+
+    // The lowest and highest minute in the bucket
+    pub low: Rate,
+    pub low_timestamp: Timestamp,
+    pub high: Rate,
+    pub high_timestamp: Timestamp,
+
+    // The mean rate of the minutes in the bucket
+    pub average: Rate,
+    pub count: u32,
+
+    // The volume traded in the bucket, and the sum of rate * volume of its minutes
+    pub volume: Volume,
+    pub turnover: f64,
+
+    pub confidence: RateConfidence,
+}
+
+impl StoredRate {
+    pub fn new(timestamp: Timestamp, rate: &AggregatedRate) -> Self {
+        StoredRate {
+            open: rate.rate,
+            open_timestamp: timestamp,
+            close: rate.rate,
+            close_timestamp: timestamp,
+            low: rate.rate,
+            low_timestamp: timestamp,
+            high: rate.rate,
+            high_timestamp: timestamp,
+            average: rate.rate,
+            count: 1,
+            volume: rate.volume,
+            turnover: rate.rate as f64 * rate.volume,
+            confidence: rate.confidence.clone(),
+        }
+    }
+
+    // 合并一分钟的数据到桶中
+    // Add a minute to the bucket. If the minute was stored before, `replaced` is the previous
+    // minute, which is swapped out of the average and the volume instead of counting the minute
+    // twice. The low and high are only moved by the new rate, so when the replaced minute was one
+    // of them, the caller recomputes them with `set_extremes`.
+    fn merge(
+        &mut self,
+        timestamp: Timestamp,
+        rate: &AggregatedRate,
+        replaced: Option<&StoredRate>,
+    ) {
+        match replaced {
+            Some(old) => {
+                self.average += (rate.rate - old.close) / self.count as f32;
+                self.volume -= old.volume;
+                self.turnover -= old.turnover;
+            }
+            None => {
+                self.average =
+                    (self.average * self.count as f32 + rate.rate) / (self.count + 1) as f32;
+                self.count += 1;
+            }
+        }
+        self.volume += rate.volume;
+        self.turnover += rate.rate as f64 * rate.volume;
+        if timestamp <= self.open_timestamp {
+            self.open = rate.rate;
+            self.open_timestamp = timestamp;
+        }
+        if timestamp >= self.close_timestamp {
+            self.close = rate.rate;
+            self.close_timestamp = timestamp;
+        }
+        if rate.rate < self.low {
+            self.low = rate.rate;
+            self.low_timestamp = timestamp;
+        }
+        if rate.rate > self.high {
+            self.high = rate.rate;
+            self.high_timestamp = timestamp;
+        }
+        self.confidence.merge(&rate.confidence);
+    }
+
+    // 根据桶内的分钟重新计算最低和最高价
+    // Set the low and high to those of the given minutes, the earliest one on a tie.
+    fn set_extremes(&mut self, minutes: &[(Timestamp, StoredRate)]) {
+        if let Some((timestamp, minute)) = minutes.first() {
+            (self.low, self.low_timestamp) = (minute.close, *timestamp);
+            (self.high, self.high_timestamp) = (minute.close, *timestamp);
+        }
+        for (timestamp, minute) in minutes {
+            if minute.close < self.low {
+                (self.low, self.low_timestamp) = (minute.close, *timestamp);
+            }
+            if minute.close > self.high {
+                (self.high, self.high_timestamp) = (minute.close, *timestamp);
+            }
+        }
+    }
+}
+
+impl Storable for StoredRate {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for StoredRate {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// 升级时需要保存的配置
+#[derive(CandidType, Clone, Deserialize, Debug, Default)]
+pub struct StableState {
+    pub owner: Option<Principal>,
+    pub aggregation: AggregationConfig,
+    pub retention: RetentionPolicy,
+    // None when upgrading from a version without the price feed
+    pub feed: Option<FeedState>,
+    // None when upgrading from a version without cycles budgeting
+    pub cycles: Option<CyclesState>,
+    // None when upgrading from a version with compile-time configuration
+    pub fetch: Option<FetchConfig>,
+    // None when upgrading from a version without tracking
+    pub tracking: Option<TrackingState>,
+}
+
+// 等待重试时保留的样本
+// The samples of a minute that had too few of them to aggregate, kept while the sources that
+// failed are asked again. At most one per source.
+#[derive(CandidType, Clone, Deserialize, Debug, PartialEq, Serialize)]
+struct KeptSamples {
+    samples: Vec<Sample>,
+}
+
+// The rate and volume a source reported for a minute
+pub type Sample = (Source, Rate, Volume);
+
+impl Storable for KeptSamples {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for KeptSamples {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for StableState {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    static STATE: RefCell<StableCell<StableState, Memory>> = RefCell::new(
+        StableCell::init(memory(STATE_MEMORY_ID), StableState::default())
+            .expect("Couldn't initialize the state cell.")
+    );
+
+    static MINUTE_RATES: RefCell<StableBTreeMap<RateKey, StoredRate, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(MINUTE_RATES_MEMORY_ID)));
+    static HOUR_RATES: RefCell<StableBTreeMap<RateKey, StoredRate, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(HOUR_RATES_MEMORY_ID)));
+    static DAY_RATES: RefCell<StableBTreeMap<RateKey, StoredRate, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(DAY_RATES_MEMORY_ID)));
+
+    // 已获取但没有数据的分钟，值为获取的时间
+    static GAPS: RefCell<StableBTreeMap<RateKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(GAPS_MEMORY_ID)));
+
+    // 等待重试的任务已取得的样本
+    static SAMPLES: RefCell<StableBTreeMap<RateKey, KeptSamples, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(SAMPLES_MEMORY_ID)));
+}
+
+// 读取旧版本的数据
+// Returns the minutes saved by a version that kept the rates on the heap, if the stable memory
+// holds its Candid message rather than the memory manager's layout. It must be called before
+// anything else touches the stable structures, since the memory manager takes over the memory.
+pub fn take_legacy_rates() -> Option<Vec<(Timestamp, AggregatedRate)>> {
+    if ic_cdk::api::stable::stable64_size() == 0 {
+        return None;
+    }
+    let mut magic = [0; 4];
+    ic_cdk::api::stable::stable64_read(0, &mut magic);
+    if &magic != b"DIDL" {
+        return None;
+    }
+    let bytes = ic_cdk::api::stable::stable_bytes();
+    Some(decode_legacy_rates(&bytes).expect("Couldn't decode the legacy state."))
+}
+
+// Before the rates moved to stable memory, pre_upgrade serialized the fetched ICP/USD closes with
+// `storage::stable_save((fetched,))`. They came from Coinbase alone, and had no volume. Returns
+// them in ascending order of timestamps. Like `storage::stable_restore`, this ignores what follows
+// the message, since the stable memory is padded to whole pages.
+fn decode_legacy_rates(bytes: &[u8]) -> Result<Vec<(Timestamp, AggregatedRate)>, String> {
+    let mut de = candid::de::IDLDeserialize::new(bytes).map_err(|e| e.to_string())?;
+    let fetched: HashMap<Timestamp, Rate> = de.get_value().map_err(|e| e.to_string())?;
+    let mut rates: Vec<_> = fetched
+        .into_iter()
+        .map(|(timestamp, rate)| {
+            let rate = AggregatedRate {
+                rate,
+                volume: 0.0,
+                confidence: RateConfidence {
+                    sources: vec![Source::Coinbase],
+                    outliers: vec![],
+                    spread: 0.0,
+                },
+            };
+            (timestamp, rate)
+        })
+        .collect();
+    rates.sort_by_key(|(timestamp, _)| *timestamp);
+    Ok(rates)
+}
+
+pub fn memory(id: u8) -> Memory {
+    MEMORY_MANAGER.with(|manager| manager.borrow().get(MemoryId::new(id)))
+}
+
+fn with_rates<R>(
+    granularity: Granularity,
+    f: impl FnOnce(&mut StableBTreeMap<RateKey, StoredRate, Memory>) -> R,
+) -> R {
+    let rates = match granularity {
+        Granularity::Minute => &MINUTE_RATES,
+        Granularity::Hour => &HOUR_RATES,
+        Granularity::Day => &DAY_RATES,
+    };
+    rates.with(|rates| f(&mut rates.borrow_mut()))
+}
+
+pub fn save_state(state: StableState) {
+    STATE.with(|cell| {
+        cell.borrow_mut()
+            .set(state)
+            .expect("Couldn't save the state.")
+    });
+}
+
+// 是否已保存数据
+pub fn has_rates() -> bool {
+    Granularity::all()
+        .iter()
+        .any(|granularity| with_rates(*granularity, |rates| !rates.is_empty()))
+}
+
+pub fn load_state() -> StableState {
+    STATE.with(|cell| cell.borrow().get().clone())
+}
+
+// 保存一分钟的数据，并更新小时和天的汇总
+// Store a minute and update the hour and day it falls in. `minute_cutoff` is where the minutes are
+// pruned: a bucket starting before it may have lost minutes it counted, so a minute within the
+// span it already covers is taken as counted, and only extends the bucket when it falls outside.
+pub fn insert_rate(
+    pair: &Pair,
+    timestamp: Timestamp,
+    rate: &AggregatedRate,
+    minute_cutoff: Timestamp,
+) {
+    let key = RateKey {
+        pair: pair.clone(),
+        timestamp,
+    };
+    GAPS.with(|gaps| gaps.borrow_mut().remove(&key));
+    let replaced = with_rates(Granularity::Minute, |rates| {
+        rates.insert(key, StoredRate::new(timestamp, rate))
+    });
+
+    for granularity in [Granularity::Hour, Granularity::Day] {
+        let start = timestamp / granularity.seconds() * granularity.seconds();
+        let key = RateKey {
+            pair: pair.clone(),
+            timestamp: start,
+        };
+        let bucket = match with_rates(granularity, |rates| rates.get(&key)) {
+            Some(bucket)
+                if start < minute_cutoff
+                    && (bucket.open_timestamp..=bucket.close_timestamp).contains(&timestamp) =>
+            {
+                continue
+            }
+            Some(mut bucket) => {
+                bucket.merge(timestamp, rate, replaced.as_ref());
+                let was_extreme =
+                    bucket.low_timestamp == timestamp || bucket.high_timestamp == timestamp;
+                if replaced.is_some() && was_extreme {
+                    let end = start + granularity.seconds();
+                    bucket.set_extremes(&get_range(pair, Granularity::Minute, start, end));
+                }
+                bucket
+            }
+            None => StoredRate::new(timestamp, rate),
+        };
+        with_rates(granularity, |rates| rates.insert(key, bucket));
+    }
+}
+
+pub fn get_rate(pair: &Pair, granularity: Granularity, timestamp: Timestamp) -> Option<StoredRate> {
+    with_rates(granularity, |rates| {
+        rates.get(&RateKey {
+            pair: pair.clone(),
+            timestamp,
+        })
+    })
+}
+
+// 读取一段时间的数据
+// The stored rates of the pair in [start, end), in ascending order of timestamps.
+pub fn get_range(
+    pair: &Pair,
+    granularity: Granularity,
+    start: Timestamp,
+    end: Timestamp,
+) -> Vec<(Timestamp, StoredRate)> {
+    if start >= end {
+        return vec![];
+    }
+    let start = RateKey {
+        pair: pair.clone(),
+        timestamp: start,
+    };
+    let end = RateKey {
+        pair: pair.clone(),
+        timestamp: end,
+    };
+    with_rates(granularity, |rates| {
+        rates
+            .range(start..end)
+            .map(|(key, rate)| (key.timestamp, rate))
+            .collect()
+    })
+}
+
+// 标记没有数据的分钟
+// Remember that the minute was fetched, but none of the sources had a candle for it, so that it
+// isn't requested again. Storing a rate for the minute later removes the mark.
+pub fn mark_gap(pair: &Pair, timestamp: Timestamp, now: Timestamp) {
+    let key = RateKey {
+        pair: pair.clone(),
+        timestamp,
+    };
+    if with_rates(Granularity::Minute, |rates| rates.contains_key(&key)) {
+        return;
+    }
+    GAPS.with(|gaps| gaps.borrow_mut().insert(key, now));
+}
+
+// The known gaps of the pair in [start, end), in ascending order.
+pub fn get_gaps(pair: &Pair, start: Timestamp, end: Timestamp) -> Vec<Timestamp> {
+    if start >= end {
+        return vec![];
+    }
+    let start = RateKey {
+        pair: pair.clone(),
+        timestamp: start,
+    };
+    let end = RateKey {
+        pair: pair.clone(),
+        timestamp: end,
+    };
+    GAPS.with(|gaps| {
+        gaps.borrow()
+            .range(start..end)
+            .map(|(key, _)| key.timestamp)
+            .collect()
+    })
+}
+
+// 保留一分钟的样本
+// Keep the samples of a minute that couldn't be aggregated yet, see KeptSamples.
+pub fn keep_samples(pair: &Pair, timestamp: Timestamp, mut samples: Vec<Sample>) {
+    samples.sort_by_key(|(source, _, _)| *source);
+    samples.dedup_by_key(|(source, _, _)| *source);
+    let key = RateKey {
+        pair: pair.clone(),
+        timestamp,
+    };
+    SAMPLES.with(|kept| kept.borrow_mut().insert(key, KeptSamples { samples }));
+}
+
+// The kept samples of the pair in [start, end), in ascending order of timestamps.
+pub fn kept_samples(
+    pair: &Pair,
+    start: Timestamp,
+    end: Timestamp,
+) -> Vec<(Timestamp, Vec<Sample>)> {
+    if start >= end {
+        return vec![];
+    }
+    let start = RateKey {
+        pair: pair.clone(),
+        timestamp: start,
+    };
+    let end = RateKey {
+        pair: pair.clone(),
+        timestamp: end,
+    };
+    SAMPLES.with(|kept| {
+        kept.borrow()
+            .range(start..end)
+            .map(|(key, kept)| (key.timestamp, kept.samples))
+            .collect()
+    })
+}
+
+// Forget the kept samples of the pair in [start, end).
+pub fn clear_samples(pair: &Pair, start: Timestamp, end: Timestamp) {
+    for (timestamp, _) in kept_samples(pair, start, end) {
+        let key = RateKey {
+            pair: pair.clone(),
+            timestamp,
+        };
+        SAMPLES.with(|kept| kept.borrow_mut().remove(&key));
+    }
+}
+
+// 删除过期数据
+// Remove at most `limit` rates that are older than the retention of their granularity, so that
+// a single message never runs out of instructions. Returns how many rates were removed.
+pub fn prune_expired(now: Timestamp, retention: &RetentionPolicy, limit: usize) -> usize {
+    let mut removed = 0;
+    for granularity in Granularity::all() {
+        if retention.get(granularity).is_none() || removed >= limit {
+            continue;
+        }
+        let cutoff = retention.cutoff(granularity, now);
+        with_rates(granularity, |rates| {
+            let mut expired = vec![];
+            // Visit each pair in turn, jumping over the rates that are still retained.
+            let mut next = rates.iter().next().map(|(key, _)| key);
+            while let Some(first) = next {
+                if first.timestamp < cutoff {
+                    let pair_cutoff = RateKey {
+                        pair: first.pair.clone(),
+                        timestamp: cutoff,
+                    };
+                    expired.extend(
+                        rates
+                            .range(first.clone()..pair_cutoff)
+                            .take(limit - removed - expired.len())
+                            .map(|(key, _)| key),
+                    );
+                }
+                if removed + expired.len() >= limit {
+                    break;
+                }
+                let pair_end = RateKey {
+                    pair: first.pair,
+                    timestamp: u64::MAX,
+                };
+                next = rates
+                    .range((Excluded(pair_end), Unbounded))
+                    .next()
+                    .map(|(key, _)| key);
+            }
+            removed += expired.len();
+            for key in expired {
+                rates.remove(&key);
+            }
+        });
+    }
+
+    // Gaps are kept as long as the minutes
+    if retention.minute.is_some() && removed < limit {
+        let cutoff = retention.cutoff(Granularity::Minute, now);
+        GAPS.with(|gaps| {
+            let mut gaps = gaps.borrow_mut();
+            let mut expired = vec![];
+            // Like the rates, visit each pair in turn and stop at its cutoff.
+            let mut next = gaps.iter().next().map(|(key, _)| key);
+            while let Some(first) = next {
+                if first.timestamp < cutoff {
+                    let pair_cutoff = RateKey {
+                        pair: first.pair.clone(),
+                        timestamp: cutoff,
+                    };
+                    expired.extend(
+                        gaps.range(first.clone()..pair_cutoff)
+                            .take(limit - removed - expired.len())
+                            .map(|(key, _)| key),
+                    );
+                }
+                if removed + expired.len() >= limit {
+                    break;
+                }
+                let pair_end = RateKey {
+                    pair: first.pair,
+                    timestamp: u64::MAX,
+                };
+                next = gaps
+                    .range((Excluded(pair_end), Unbounded))
+                    .next()
+                    .map(|(key, _)| key);
+            }
+            removed += expired.len();
+            for key in expired {
+                gaps.remove(&key);
+            }
+        });
+    }
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aggregate::test_rate;
+
+    fn rate(rate: Rate) -> AggregatedRate {
+        test_rate(rate, 1.0)
+    }
+
+    #[test]
+    fn test_rate_key_bytes_keep_order() {
+        let keys = vec![
+            RateKey {
+                pair: Pair::icp_usd(),
+                timestamp: 1_000,
+            },
+            RateKey {
+                pair: Pair::icp_usd(),
+                timestamp: 70_000,
+            },
+            RateKey {
+                pair: Pair {
+                    base: "ICPX".to_string(),
+                    quote: "USD".to_string(),
+                },
+                timestamp: 0,
+            },
+        ];
+        for window in keys.windows(2) {
+            assert!(window[0].to_bytes() < window[1].to_bytes());
+        }
+        for key in keys {
+            assert_eq!(RateKey::from_bytes(key.to_bytes()), key);
+        }
+    }
+
+    #[test]
+    fn test_insert_rate_rolls_up_and_prunes() {
+        let pair = Pair::icp_usd();
+        let hour = 1_800_000;
+        insert_rate(&pair, hour + 60, &rate(2.0), 0);
+        insert_rate(&pair, hour, &rate(1.0), 0);
+        // Fetching the same minute again replaces it in the average
+        insert_rate(&pair, hour + 60, &rate(4.0), 0);
+
+        let bucket = get_rate(&pair, Granularity::Hour, hour).unwrap();
+        assert_eq!(bucket.count, 2);
+        assert_eq!(bucket.average, 2.5);
+        assert_eq!(bucket.close, 4.0);
+        assert_eq!((bucket.open, bucket.open_timestamp), (1.0, hour));
+        assert_eq!((bucket.high, bucket.high_timestamp), (4.0, hour + 60));
+        assert_eq!(bucket.volume, 2.0);
+        assert_eq!(bucket.turnover, 5.0);
+
+        let retention = RetentionPolicy {
+            minute: Some(60),
+            hour: Some(60 * 60),
+            day: None,
+        };
+        assert_eq!(prune_expired(hour + 60 + 60, &retention, 1), 1);
+        assert_eq!(get_range(&pair, Granularity::Minute, 0, u64::MAX).len(), 1);
+        assert_eq!(prune_expired(hour + 60 * 60, &retention, 10), 1);
+        assert_eq!(get_range(&pair, Granularity::Minute, 0, u64::MAX).len(), 0);
+        assert!(get_rate(&pair, Granularity::Hour, hour).is_some());
+        assert_eq!(get_range(&pair, Granularity::Day, 0, u64::MAX).len(), 1);
+
+        // Fetching a pruned minute again doesn't count it twice, a minute after the pruned ones
+        // still extends the bucket.
+        let cutoff = hour + 60 * 60;
+        insert_rate(&pair, hour + 60, &rate(4.0), cutoff);
+        insert_rate(&pair, hour + 120, &rate(3.0), cutoff);
+        let bucket = get_rate(&pair, Granularity::Hour, hour).unwrap();
+        assert_eq!(bucket.count, 3);
+        assert_eq!(bucket.volume, 3.0);
+        assert_eq!((bucket.close, bucket.close_timestamp), (3.0, hour + 120));
+    }
+
+    #[test]
+    fn test_replacing_an_extreme_recomputes_it() {
+        let pair = Pair::icp_usd();
+        let hour = 3_600_000;
+        insert_rate(&pair, hour, &rate(2.0), 0);
+        insert_rate(&pair, hour + 60, &rate(5.0), 0);
+        insert_rate(&pair, hour + 120, &rate(1.0), 0);
+        insert_rate(&pair, hour + 180, &rate(3.0), 0);
+
+        // The high goes down, and the low goes up
+        insert_rate(&pair, hour + 60, &rate(2.5), 0);
+        insert_rate(&pair, hour + 120, &rate(2.0), 0);
+        let bucket = get_rate(&pair, Granularity::Hour, hour).unwrap();
+        assert_eq!((bucket.high, bucket.high_timestamp), (3.0, hour + 180));
+        assert_eq!((bucket.low, bucket.low_timestamp), (2.0, hour));
+        assert_eq!(bucket.count, 4);
+    }
+
+    #[test]
+    fn test_gaps_are_cleared_by_rates() {
+        let pair = Pair::icp_usd();
+        insert_rate(&pair, 120, &rate(1.0), 0);
+        // Already stored
+        mark_gap(&pair, 120, 1_000);
+        mark_gap(&pair, 180, 1_000);
+        mark_gap(&pair, 240, 1_000);
+        assert_eq!(get_gaps(&pair, 0, 1_000), vec![180, 240]);
+
+        // The minute turned up after all
+        insert_rate(&pair, 180, &rate(2.0), 0);
+        assert_eq!(get_gaps(&pair, 0, 1_000), vec![240]);
+        assert!(get_gaps(&pair, 0, 240).is_empty());
+    }
+
+    #[test]
+    fn test_baseline_state_is_decoded() {
+        let fetched: HashMap<Timestamp, Rate> = [(120, 9.5), (60, 9.25)].into_iter().collect();
+        // What the baseline's pre_upgrade saved, in a page of stable memory
+        let mut bytes = candid::encode_args((&fetched,)).unwrap();
+        bytes.resize(64 * 1024, 0);
+
+        let rates = decode_legacy_rates(&bytes).unwrap();
+        let closes: Vec<_> = rates
+            .iter()
+            .map(|(timestamp, rate)| (*timestamp, rate.rate, rate.volume))
+            .collect();
+        assert_eq!(closes, vec![(60, 9.25, 0.0), (120, 9.5, 0.0)]);
+        assert_eq!(rates[0].1.confidence.sources, vec![Source::Coinbase]);
+    }
+
+    #[test]
+    fn test_expired_gaps_are_pruned_per_pair() {
+        let btc = Pair {
+            base: "BTC".to_string(),
+            quote: "USD".to_string(),
+        };
+        for pair in [Pair::icp_usd(), btc.clone()] {
+            for minute in 0..3 {
+                mark_gap(&pair, 5_000 + minute * 60, 6_000);
+            }
+        }
+        let retention = RetentionPolicy {
+            minute: Some(60),
+            hour: Some(60 * 60),
+            day: None,
+        };
+        // The first two minutes of both pairs are expired
+        let now = 5_000 + 2 * 60 + 60;
+        assert_eq!(prune_expired(now, &retention, 3), 3);
+        assert_eq!(prune_expired(now, &retention, 10), 1);
+        for pair in [Pair::icp_usd(), btc] {
+            assert_eq!(get_gaps(&pair, 0, u64::MAX), vec![5_000 + 2 * 60]);
+        }
+    }
+
+    #[test]
+    fn test_kept_samples_are_per_source_until_cleared() {
+        let pair = Pair::icp_usd();
+        keep_samples(
+            &pair,
+            60,
+            vec![(Source::Kraken, 1.0, 2.0), (Source::Kraken, 1.5, 2.0)],
+        );
+        keep_samples(&pair, 120, vec![(Source::Coinbase, 3.0, 4.0)]);
+        keep_samples(&pair, 600, vec![(Source::Coinbase, 5.0, 6.0)]);
+        assert_eq!(
+            kept_samples(&pair, 0, 300),
+            vec![
+                (60, vec![(Source::Kraken, 1.0, 2.0)]),
+                (120, vec![(Source::Coinbase, 3.0, 4.0)])
+            ]
+        );
+
+        clear_samples(&pair, 0, 300);
+        assert!(kept_samples(&pair, 0, 300).is_empty());
+        assert_eq!(kept_samples(&pair, 0, 1_000).len(), 1);
+    }
+}
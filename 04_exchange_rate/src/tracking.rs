@@ -0,0 +1,307 @@
+use crate::jobs::{self, JobState};
+use crate::source::Pair;
+use crate::{add_job_to_job_set, config, stored_rates, TimeRange, Timestamp};
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+
+// 同时进行的补数据任务数
+// A backfill only keeps this many jobs in the queue at a time, so that tracking the latest
+// minutes isn't held up behind a long history.
+pub const BACKFILL_JOBS_IN_FLIGHT: usize = 5;
+
+// 保留的已完成补数据记录数
+pub const MAX_FINISHED_BACKFILLS: usize = 10;
+
+// 跟踪的间隔
+// The latest minutes of the tracked pairs are queued this often. A job costs the same however few
+// of its minutes are new, so queuing one every minute would spend the default daily budget (about
+// 45 jobs, see cycles::CyclesBudget) within the first hour.
+pub const TRACKING_INTERVAL: u64 = 60 * 60;
+
+// 补数据的进度
+#[derive(CandidType, Clone, Deserialize, Debug, PartialEq, Serialize)]
+pub struct Backfill {
+    pub id: u64,
+    pub pair: Pair,
+    pub range: TimeRange,
+    // Number of jobs the range is split into
+    pub total_jobs: u64,
+    // Jobs that were fetched, or didn't need to be because their minutes were all stored
+    pub completed_jobs: u64,
+    // Jobs that were given up on, see jobs::JOB_MAX_ATTEMPTS
+    pub failed_jobs: u64,
+    // Jobs queued and not finished yet
+    pub in_flight: Vec<Timestamp>,
+    // The start of the next job to queue
+    pub cursor: Timestamp,
+    pub started_at: Timestamp,
+    pub finished_at: Option<Timestamp>,
+}
+
+// 升级时需要保存的跟踪状态
+#[derive(CandidType, Clone, Deserialize, Debug, Default)]
+pub struct TrackingState {
+    // Pairs whose latest minutes are fetched continuously
+    pub pairs: Vec<Pair>,
+    // The last finished minute that was queued for the tracked pairs
+    pub last_tracked: Timestamp,
+    // The running backfill, if any, then the finished ones, most recent last
+    pub backfills: Vec<Backfill>,
+    pub next_backfill_id: u64,
+}
+
+thread_local! {
+    static TRACKING: RefCell<TrackingState> = RefCell::new(TrackingState::default());
+}
+
+pub fn save() -> TrackingState {
+    TRACKING.with(|tracking| tracking.borrow().clone())
+}
+
+pub fn restore(state: TrackingState) {
+    TRACKING.with(|tracking| *tracking.borrow_mut() = state);
+}
+
+// 可以获取的交易对
+// The sources are only queried for ICP/USD so far.
+fn validate_pair(pair: &Pair) -> Result<(), String> {
+    if pair != &Pair::icp_usd() {
+        return Err(format!("{}/{} can't be fetched", pair.base, pair.quote));
+    }
+    Ok(())
+}
+
+pub fn tracked_pairs() -> Vec<Pair> {
+    TRACKING.with(|tracking| tracking.borrow().pairs.clone())
+}
+
+// 设置持续跟踪的交易对，为空时只按需获取数据
+pub fn set_tracked_pairs(mut pairs: Vec<Pair>) -> Result<(), String> {
+    for pair in pairs.iter() {
+        validate_pair(pair)?;
+    }
+    pairs.sort();
+    pairs.dedup();
+    TRACKING.with(|tracking| tracking.borrow_mut().pairs = pairs);
+    Ok(())
+}
+
+// 开始补数据
+// Fetch the range, up to now, job by job. Returns the id of the backfill, see `backfills` for
+// its progress. Only one backfill runs at a time.
+pub fn start_backfill(pair: Pair, range: TimeRange, now: Timestamp) -> Result<u64, String> {
+    validate_pair(&pair)?;
+    let job_span = config::job_span();
+    let start = range.start / job_span * job_span;
+    let end = range.end.min(now);
+    if start >= end {
+        return Err("The range must start before it ends, and before now".to_string());
+    }
+    TRACKING.with(|tracking| {
+        let mut tracking = tracking.borrow_mut();
+        if tracking.backfills.iter().any(|b| b.finished_at.is_none()) {
+            return Err("Another backfill is still running".to_string());
+        }
+        let id = tracking.next_backfill_id;
+        tracking.next_backfill_id += 1;
+        tracking.backfills.push(Backfill {
+            id,
+            pair,
+            range: TimeRange { start, end },
+            total_jobs: (end - start + job_span - 1) / job_span,
+            completed_jobs: 0,
+            failed_jobs: 0,
+            in_flight: vec![],
+            cursor: start,
+            started_at: now,
+            finished_at: None,
+        });
+        let finished = tracking
+            .backfills
+            .len()
+            .saturating_sub(MAX_FINISHED_BACKFILLS + 1);
+        tracking.backfills.drain(..finished);
+        Ok(id)
+    })
+}
+
+pub fn backfills() -> Vec<Backfill> {
+    TRACKING.with(|tracking| tracking.borrow().backfills.clone())
+}
+
+// 定时调用，添加新任务
+// Every TRACKING_INTERVAL, queue the jobs of the minutes that finished since the last time for the
+// tracked pairs, up to one interval back, and move the running backfill forward.
+pub fn tick(now: Timestamp) {
+    let granularity = config::granularity();
+    let job_span = config::job_span();
+    let latest = (now / granularity * granularity).saturating_sub(granularity);
+    let since = TRACKING.with(|tracking| {
+        let mut tracking = tracking.borrow_mut();
+        if tracking.pairs.is_empty()
+            || latest < tracking.last_tracked.saturating_add(TRACKING_INTERVAL)
+        {
+            return None;
+        }
+        let since = (tracking.last_tracked + granularity).max(latest - TRACKING_INTERVAL);
+        tracking.last_tracked = latest;
+        Some(since)
+    });
+    // The minutes since the last time may span the end of a job
+    if let Some(since) = since {
+        let mut job = since / job_span * job_span;
+        while job <= latest {
+            add_job_to_job_set(job);
+            job += job_span;
+        }
+    }
+
+    TRACKING.with(|tracking| {
+        let mut tracking = tracking.borrow_mut();
+        if let Some(backfill) = tracking
+            .backfills
+            .iter_mut()
+            .find(|b| b.finished_at.is_none())
+        {
+            advance(backfill, now);
+        }
+    });
+}
+
+fn advance(backfill: &mut Backfill, now: Timestamp) {
+    let mut in_flight = vec![];
+    for job in backfill.in_flight.drain(..) {
+        match jobs::state(job) {
+            JobState::Pending { .. } => in_flight.push(job),
+            JobState::Failed { .. } => backfill.failed_jobs += 1,
+            _ => backfill.completed_jobs += 1,
+        }
+    }
+    backfill.in_flight = in_flight;
+
+    let job_span = config::job_span();
+    while backfill.in_flight.len() < BACKFILL_JOBS_IN_FLIGHT && backfill.cursor < backfill.range.end
+    {
+        let job = backfill.cursor;
+        backfill.cursor += job_span;
+        let chunk = TimeRange {
+            start: job,
+            end: backfill.cursor.min(backfill.range.end),
+        };
+        if stored_rates(&backfill.pair, &chunk, now).missing.is_empty() {
+            backfill.completed_jobs += 1;
+            continue;
+        }
+        add_job_to_job_set(job);
+        backfill.in_flight.push(job);
+    }
+
+    if backfill.in_flight.is_empty() && backfill.cursor >= backfill.range.end {
+        backfill.finished_at = Some(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::Source;
+
+    #[test]
+    fn test_backfill_is_chunked_and_reports_progress() {
+        let job_span = config::job_span();
+        let start = 1_000 * job_span;
+        let now = start + 100 * job_span;
+        let range = TimeRange {
+            start: start + 10,
+            end: start + 7 * job_span,
+        };
+        let id = start_backfill(Pair::icp_usd(), range.clone(), now).unwrap();
+        assert!(start_backfill(Pair::icp_usd(), range, now).is_err());
+
+        tick(now);
+        let backfill = backfills().pop().unwrap();
+        assert_eq!(backfill.id, id);
+        assert_eq!(backfill.total_jobs, 7);
+        assert_eq!(backfill.in_flight.len(), BACKFILL_JOBS_IN_FLIGHT);
+        assert_eq!(backfill.in_flight[0], start);
+
+        // Nothing is tracked, so only the backfill's jobs are queued
+        let mut jobs = vec![];
+        while let Some(job) = jobs::next_due(now) {
+            jobs.push(job);
+        }
+        assert_eq!(jobs.len(), BACKFILL_JOBS_IN_FLIGHT);
+        jobs::complete(jobs[0], now);
+        jobs::complete(jobs[1], now);
+        for _ in 0..jobs::JOB_MAX_ATTEMPTS {
+            jobs::fail(jobs[2], "timeout".to_string(), vec![Source::Kraken], now);
+        }
+
+        tick(now);
+        let backfill = backfills().pop().unwrap();
+        assert_eq!(backfill.completed_jobs, 2);
+        assert_eq!(backfill.failed_jobs, 1);
+        assert_eq!(backfill.in_flight.len(), 4);
+        assert_eq!(backfill.cursor, start + 7 * job_span);
+
+        for job in backfill.in_flight {
+            jobs::complete(job, now);
+        }
+        tick(now + 1);
+        let backfill = backfills().pop().unwrap();
+        assert_eq!(backfill.completed_jobs, 6);
+        assert_eq!(backfill.finished_at, Some(now + 1));
+    }
+
+    #[test]
+    fn test_tracked_pairs_are_queued_once_per_interval() {
+        let btc = Pair {
+            base: "BTC".to_string(),
+            quote: "USD".to_string(),
+        };
+        assert!(set_tracked_pairs(vec![btc]).is_err());
+        set_tracked_pairs(vec![Pair::icp_usd(), Pair::icp_usd()]).unwrap();
+        assert_eq!(tracked_pairs(), vec![Pair::icp_usd()]);
+
+        let granularity = config::granularity();
+        let job_span = config::job_span();
+        let now = 50_000 * job_span + TRACKING_INTERVAL + 10 * granularity + 5;
+        tick(now);
+        let job = jobs::next_due(now).unwrap();
+        assert_eq!(job, 50_000 * job_span);
+        jobs::complete(job, now);
+
+        // Within the same interval, nothing new
+        tick(now + granularity);
+        assert_eq!(jobs::next_due(now + granularity), None);
+
+        // The minutes since the last time span the end of the job
+        let now = 50_001 * job_span + 10 * granularity + 5;
+        tick(now);
+        assert_eq!(jobs::next_due(now), Some(job));
+        assert_eq!(jobs::next_due(now), Some(job + job_span));
+        assert_eq!(jobs::next_due(now), None);
+    }
+
+    #[test]
+    fn test_a_day_of_tracking_fits_the_default_budget() {
+        set_tracked_pairs(vec![Pair::icp_usd()]).unwrap();
+        let sources = crate::aggregate::AggregationConfig::default().sources;
+        let start = 60_000 * config::job_span();
+        let mut spent = 0;
+        for minute in 0..24 * 60 {
+            let now = start + minute * config::granularity();
+            tick(now);
+            while let Some(job) = jobs::next_due(now) {
+                spent += sources
+                    .iter()
+                    .map(|source| crate::outcall_cost(*source, job))
+                    .sum::<u64>();
+                jobs::complete(job, now);
+            }
+        }
+        // Leaving room for backfills
+        assert!(spent <= crate::cycles::CyclesBudget::default().daily * 3 / 4);
+    }
+}
@@ -0,0 +1,215 @@
+use crate::source::{DecodeError, Source};
+use crate::{config, CanisterHttpResponsePayload, HttpHeader, Rate, Timestamp, Volume};
+
+// 保留的响应头
+// Every replica must end up with the same response, so all headers are dropped except these.
+// Names are compared case-insensitively.
+pub const RESPONSE_HEADERS_ALLOWLIST: [&'static str; 1] = ["Content-Type"];
+
+// 解码失败时添加的响应头
+// The transform can't fail the request, so a body that couldn't be decoded is replaced by an
+// empty one, and the error is passed on in this header.
+pub const DECODE_ERROR_HEADER: &'static str = "x-decode-error";
+
+// 过滤响应头
+// Keep the allow-listed headers, with lower case names, sorted so that their order doesn't depend
+// on the replica.
+pub fn sanitize_headers(headers: &[HttpHeader]) -> Vec<HttpHeader> {
+    let mut sanitized: Vec<HttpHeader> = headers
+        .iter()
+        .filter(|header| {
+            RESPONSE_HEADERS_ALLOWLIST
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(&header.name))
+        })
+        .map(|header| HttpHeader {
+            name: header.name.to_ascii_lowercase(),
+            value: header.value.clone(),
+        })
+        .collect();
+    sanitized.sort_by(|a, b| (&a.name, &a.value).cmp(&(&b.name, &b.value)));
+    sanitized
+}
+
+// 标准化数据
+// Sort the rates by timestamp, drop duplicate and non finite ones, and only keep the job window
+// containing the earliest rate. Jobs are always aligned to their span, so that's the requested
+// window even for sources like Kraken that return more than asked for.
+pub fn normalize_rates(
+    mut rates: Vec<(Timestamp, Rate, Volume)>,
+) -> Vec<(Timestamp, Rate, Volume)> {
+    rates.retain(|(_, rate, volume)| rate.is_finite() && volume.is_finite());
+    rates.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    rates.dedup_by_key(|(timestamp, _, _)| *timestamp);
+
+    let job_span = config::job_span();
+    if let Some((first, _, _)) = rates.first() {
+        let end = first / job_span * job_span + job_span;
+        rates.retain(|(timestamp, _, _)| *timestamp < end);
+    }
+    rates
+}
+
+// 标准化响应
+// Turn the raw response of the source into a canonical one: allow-listed headers only, and a body
+// of `[[timestamp, rate, volume], ...]` as formatted by serde_json. Error responses are passed on
+// with an empty body, since error pages tend to contain request ids and the like.
+pub fn transform_response(
+    source: Source,
+    raw: CanisterHttpResponsePayload,
+) -> CanisterHttpResponsePayload {
+    let mut headers = sanitize_headers(&raw.headers);
+    let body = if raw.status == 200 {
+        let rates = String::from_utf8(raw.body)
+            .map_err(|_| DecodeError::NotUtf8)
+            .and_then(|body| source.decode_body_to_rates(&body));
+        match rates {
+            Ok(rates) => serde_json::to_vec(&normalize_rates(rates)).unwrap(),
+            Err(error) => {
+                headers.push(HttpHeader {
+                    name: DECODE_ERROR_HEADER.to_string(),
+                    value: error.to_string(),
+                });
+                vec![]
+            }
+        }
+    } else {
+        vec![]
+    };
+    CanisterHttpResponsePayload {
+        status: raw.status,
+        headers,
+        body,
+    }
+}
+
+// 解码标准化后的响应
+// The rates of a response produced by transform_response.
+pub fn decode_response(
+    response: CanisterHttpResponsePayload,
+) -> Result<Vec<(Timestamp, Rate, Volume)>, DecodeError> {
+    if let Some(header) = response
+        .headers
+        .iter()
+        .find(|header| header.name == DECODE_ERROR_HEADER)
+    {
+        return Err(DecodeError::Transform(header.value.clone()));
+    }
+    let body = String::from_utf8(response.body).map_err(|_| DecodeError::NotUtf8)?;
+    serde_json::from_str(&body).map_err(|e| DecodeError::InvalidJson(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(name: &str, value: &str) -> HttpHeader {
+        HttpHeader {
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    fn coinbase_response(rows: &[&str], headers: Vec<HttpHeader>) -> CanisterHttpResponsePayload {
+        CanisterHttpResponsePayload {
+            status: 200,
+            headers,
+            body: format!("[{}]", rows.join(",")).into_bytes(),
+        }
+    }
+
+    #[test]
+    fn test_transform_is_deterministic() {
+        let rows = [
+            "[1652454240, 9.51, 9.55, 9.55, 9.52, 2385.9735]",
+            "[1652454180, 9.54, 9.58, 9.55, 9.560, 1930.129]",
+            "[1652454300, 9.51, 9.59, 9.54, 9.51, 1103.5]",
+            // The next job's window
+            "[1652466000, 9.5, 9.5, 9.5, 9.5, 1.0]",
+        ];
+        let expected = transform_response(
+            Source::Coinbase,
+            coinbase_response(
+                &rows,
+                vec![
+                    header("Date", "Fri, 13 May 2022 15:05:00 GMT"),
+                    header("Content-Type", "application/json"),
+                    header("CF-RAY", "70ae32dc8c6a1a46-FRA"),
+                ],
+            ),
+        );
+        assert_eq!(
+            String::from_utf8(expected.body.clone()).unwrap(),
+            "[[1652454180,9.56,1930.129],[1652454240,9.52,2385.9735],[1652454300,9.51,1103.5]]"
+        );
+        assert_eq!(
+            expected.headers,
+            vec![header("content-type", "application/json")]
+        );
+
+        // The same response with the rows, headers and number formatting shuffled around
+        let reformatted = rows[1].replace("9.560", "9.56");
+        let shuffled = [rows[3], rows[0], reformatted.as_str(), rows[2]];
+        let transformed = transform_response(
+            Source::Coinbase,
+            coinbase_response(
+                &shuffled,
+                vec![
+                    header("CF-RAY", "70ae32dd0f8a1a46-AMS"),
+                    header("content-type", "application/json"),
+                    header("Date", "Fri, 13 May 2022 15:05:01 GMT"),
+                ],
+            ),
+        );
+        assert_eq!(transformed, expected);
+
+        assert_eq!(
+            decode_response(expected).unwrap(),
+            vec![
+                (1652454180, 9.56, 1930.129),
+                (1652454240, 9.52, 2385.9735),
+                (1652454300, 9.51, 1103.5)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_transform_drops_error_bodies() {
+        let raw = CanisterHttpResponsePayload {
+            status: 429,
+            headers: vec![header("Retry-After", "1")],
+            body: b"{\"message\": \"Rate limit exceeded, request id 1234\"}".to_vec(),
+        };
+        let transformed = transform_response(Source::Coinbase, raw);
+        assert_eq!(transformed.status, 429);
+        assert!(transformed.headers.is_empty());
+        assert!(transformed.body.is_empty());
+    }
+
+    #[test]
+    fn test_transform_passes_decode_errors_on() {
+        let raw = coinbase_response(&["[1652454180, 9.54]"], vec![]);
+        let transformed = transform_response(Source::Coinbase, raw);
+        assert_eq!(transformed.status, 200);
+        assert!(transformed.body.is_empty());
+        assert_eq!(
+            decode_response(transformed),
+            Err(DecodeError::Transform(
+                "Invalid close in row 0 of the response".to_string()
+            ))
+        );
+
+        let raw = CanisterHttpResponsePayload {
+            status: 200,
+            headers: vec![],
+            body: vec![0xff, 0xfe],
+        };
+        let transformed = transform_response(Source::Coinbase, raw);
+        assert_eq!(
+            decode_response(transformed),
+            Err(DecodeError::Transform(
+                "The response is not UTF-8 encoded".to_string()
+            ))
+        );
+    }
+}
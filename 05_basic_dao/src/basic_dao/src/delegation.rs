@@ -0,0 +1,178 @@
+use crate::types::{Ballot, DelegationScope, ProposalCall, Tokens, Vote, VotingPower};
+use ic_cdk::export::Principal;
+use std::collections::{HashMap, HashSet};
+
+/// The delegate of each principal, by scope
+pub type Delegations = HashMap<(Principal, DelegationScope), Principal>;
+
+/// Return whom `delegator` delegates to on a proposal calling `method` of `canister_id`
+///
+/// `None` stands for a canister, or a method, that no scope names.
+fn delegate_for(
+    delegations: &Delegations,
+    delegator: Principal,
+    canister_id: Option<Principal>,
+    method: Option<&str>,
+) -> Option<Principal> {
+    let mut scopes = vec![];
+    if let Some(canister_id) = canister_id {
+        if let Some(method) = method {
+            scopes.push(DelegationScope::Method {
+                canister_id,
+                method: method.to_string(),
+            });
+        }
+        scopes.push(DelegationScope::Canister(canister_id));
+    }
+    scopes.push(DelegationScope::Global);
+
+    scopes
+        .into_iter()
+        .find_map(|scope| delegations.get(&(delegator, scope)).copied())
+}
+
+/// Return the canister and method every call of a proposal is made to, when they all share them
+///
+/// The delegations applying to a proposal are the most specific ones covering all its calls.
+fn target(calls: &[ProposalCall]) -> (Option<Principal>, Option<&str>) {
+    let first = match calls.first() {
+        Some(first) => first,
+        None => return (None, None),
+    };
+    if calls
+        .iter()
+        .any(|call| call.canister_id != first.canister_id)
+    {
+        (None, None)
+    } else if calls.iter().any(|call| call.method != first.method) {
+        (Some(first.canister_id), None)
+    } else {
+        (Some(first.canister_id), Some(first.method.as_str()))
+    }
+}
+
+/// Follow the delegations of `delegator` to the first principal for which `is_final` holds
+///
+/// Return `None` if the delegations end before reaching one.
+fn resolve(
+    delegations: &Delegations,
+    delegator: Principal,
+    calls: &[ProposalCall],
+    is_final: impl Fn(&Principal) -> bool,
+) -> Option<Principal> {
+    let (canister_id, method) = target(calls);
+    let mut visited = HashSet::from([delegator]);
+    let mut current = delegator;
+    while let Some(delegate) = delegate_for(delegations, current, canister_id, method) {
+        if is_final(&delegate) {
+            return Some(delegate);
+        }
+        // Cycles are rejected when delegating, this only guards against looping forever
+        if !visited.insert(delegate) {
+            return None;
+        }
+        current = delegate;
+    }
+    None
+}
+
+/// Return whether adding the delegation would make the delegations of some proposal loop
+pub fn creates_cycle(
+    delegations: &Delegations,
+    delegator: Principal,
+    delegate: Principal,
+    scope: DelegationScope,
+) -> bool {
+    let mut delegations = delegations.clone();
+    delegations.insert((delegator, scope), delegate);
+
+    // Which delegations apply only differs between the canisters and methods named in the scopes,
+    // and every other call
+    let mut targets = vec![(None, None)];
+    for (_, scope) in delegations.keys() {
+        match scope {
+            DelegationScope::Global => {}
+            DelegationScope::Canister(canister_id) => targets.push((Some(*canister_id), None)),
+            DelegationScope::Method {
+                canister_id,
+                method,
+            } => {
+                targets.push((Some(*canister_id), None));
+                targets.push((Some(*canister_id), Some(method.as_str())));
+            }
+        }
+    }
+
+    targets.into_iter().any(|(canister_id, method)| {
+        let mut visited = HashSet::from([delegator]);
+        let mut current = delegator;
+        while let Some(delegate) = delegate_for(&delegations, current, canister_id, method) {
+            if !visited.insert(delegate) {
+                return true;
+            }
+            current = delegate;
+        }
+        false
+    })
+}
+
+/// Count the "yes" and "no" votes on a proposal making the calls
+///
+/// Direct voters vote with their own balance, plus the balances of the principals who haven't
+/// voted and whose delegations lead to them.
+pub fn tally(
+    delegations: &Delegations,
+    balances: &HashMap<Principal, Tokens>,
+    ballots: &HashMap<Principal, Ballot>,
+    calls: &[ProposalCall],
+) -> (Tokens, Tokens) {
+    let mut votes_yes = Tokens::default();
+    let mut votes_no = Tokens::default();
+    for (principal, tokens) in balances.iter() {
+        let voter = if ballots.contains_key(principal) {
+            Some(*principal)
+        } else {
+            resolve(delegations, *principal, calls, |p| ballots.contains_key(p))
+        };
+        match voter.and_then(|voter| ballots.get(&voter)).map(|b| &b.vote) {
+            Some(Vote::Yes) => votes_yes += *tokens,
+            Some(Vote::No) => votes_no += *tokens,
+            None => {}
+        }
+    }
+    (votes_yes, votes_no)
+}
+
+/// Return the voting power of `principal` on a proposal making the calls
+pub fn voting_power(
+    delegations: &Delegations,
+    balances: &HashMap<Principal, Tokens>,
+    ballots: &HashMap<Principal, Ballot>,
+    principal: Principal,
+    calls: &[ProposalCall],
+) -> VotingPower {
+    let mut delegated = Tokens::default();
+    for (delegator, tokens) in balances.iter() {
+        if *delegator == principal || ballots.contains_key(delegator) {
+            continue;
+        }
+        let delegate = resolve(delegations, *delegator, calls, |p| {
+            *p == principal || ballots.contains_key(p)
+        });
+        if delegate == Some(principal) {
+            delegated += *tokens;
+        }
+    }
+
+    let delegated_to = if ballots.contains_key(&principal) {
+        None
+    } else {
+        resolve(delegations, principal, calls, |p| ballots.contains_key(p))
+    };
+
+    VotingPower {
+        own: balances.get(&principal).copied().unwrap_or_default(),
+        delegated,
+        delegated_to,
+    }
+}
@@ -7,6 +7,7 @@ pub trait Environment {
     fn now(&self) -> u64;
     fn caller(&self) -> Principal;
     fn canister_id(&self) -> Principal;
+    fn cycles_balance(&self) -> u64;
 }
 
 pub struct CanisterEnvironment {}
@@ -23,6 +24,10 @@ impl Environment for CanisterEnvironment {
     fn canister_id(&self) -> Principal {
         ic_cdk::id()
     }
+
+    fn cycles_balance(&self) -> u64 {
+        ic_cdk::api::canister_balance()
+    }
 }
 
 pub struct EmptyEnvironment {}
@@ -39,6 +44,10 @@ impl Environment for EmptyEnvironment {
     fn canister_id(&self) -> Principal {
         unimplemented!()
     }
+
+    fn cycles_balance(&self) -> u64 {
+        unimplemented!()
+    }
 }
 
 #[cfg(test)]
@@ -46,6 +55,7 @@ pub struct TestEnvironment {
     pub now: u64,
     pub caller: Principal,
     pub canister_id: Principal,
+    pub cycles_balance: u64,
 }
 
 #[cfg(test)]
@@ -61,4 +71,8 @@ impl Environment for TestEnvironment {
     fn canister_id(&self) -> Principal {
         self.canister_id
     }
+
+    fn cycles_balance(&self) -> u64 {
+        self.cycles_balance
+    }
 }
@@ -1,56 +1,41 @@
-use crate::types::{Proposal, ProposalState};
+use crate::types::Proposal;
 use crate::SERVICE;
 use ic_cdk_macros::heartbeat;
 
 #[heartbeat]
 async fn heartbeat() {
+    settle_expired_proposals();
     execute_accepted_proposals().await;
 }
 
-/// Execute all accepted proposals
+/// Settle the proposals whose voting period is over, so that the accepted ones get executed
+fn settle_expired_proposals() {
+    SERVICE.with(|service| service.borrow_mut().settle_expired_proposals());
+}
+
+/// Execute the accepted proposals that are due
 async fn execute_accepted_proposals() {
-    let accepted_proposals: Vec<Proposal> = SERVICE.with(|service| {
-        service
-            .borrow_mut()
-            .proposals
-            .values_mut()
-            .filter(|proposal| proposal.state == ProposalState::Accepted)
-            .map(|proposal| {
-                proposal.state = ProposalState::Executing;
-                proposal.clone()
-            })
-            .collect()
-    });
+    let accepted_proposals: Vec<Proposal> =
+        SERVICE.with(|service| service.borrow_mut().start_executions());
 
     for proposal in accepted_proposals {
-        let state = match execute_proposal(proposal.clone()).await {
-            Ok(()) => ProposalState::Succeeded,
-            Err(msg) => ProposalState::Failed(msg),
-        };
-
-        SERVICE.with(|service| {
-            service
-                .borrow_mut()
-                .update_proposal_state(proposal.id, state)
-        })
+        execute_proposal(proposal.id).await;
     }
 }
 
-/// Execute the given proposal
-async fn execute_proposal(proposal: Proposal) -> Result<(), String> {
-    ic_cdk::api::call::call_raw(
-        proposal.payload.canister_id,
-        &proposal.payload.method,
-        &proposal.payload.message[..],
-        0,
-    )
-    .await
-    .map_err(|(code, msg)| {
-        format!(
-            "Proposal execution failed: \
-                canister: {}, method: {}, rejection code: {:?}, message: {}",
-            proposal.payload.canister_id, &proposal.payload.method, code, msg
+/// Make the calls of the given proposal one after the other, until one fails
+async fn execute_proposal(proposal_id: u64) {
+    while let Some(call) =
+        SERVICE.with(|service| service.borrow_mut().next_affordable_call(proposal_id))
+    {
+        let result = ic_cdk::api::call::call_raw(
+            call.canister_id,
+            &call.method,
+            &call.message[..],
+            call.cycles.unwrap_or(0),
         )
-    })
-    .map(|_| ())
+        .await;
+
+        SERVICE.with(|service| service.borrow_mut().record_call(proposal_id, result));
+    }
 }
@@ -0,0 +1,181 @@
+use crate::types::{LedgerParams, Tokens, TreasuryTransferArgs, WithdrawArgs};
+use crate::SERVICE;
+use ic_cdk::export::Principal;
+use ic_cdk_macros::*;
+use ic_ledger_types::{
+    AccountBalanceArgs, AccountIdentifier, BlockIndex, Memo, Subaccount, DEFAULT_SUBACCOUNT,
+};
+
+/// Return the ledger account the caller sends tokens to before calling `deposit`
+#[query]
+#[ic_cdk::export::candid::candid_method(query)]
+fn get_deposit_account() -> AccountIdentifier {
+    AccountIdentifier::new(&ic_cdk::id(), &deposit_subaccount(&ic_cdk::caller()))
+}
+
+/// Move the tokens in the caller's deposit account to the treasury, and credit them, minus the
+/// ledger's fee, to the caller's account
+///
+/// Only members can deposit, see `add_member`.
+#[update]
+#[ic_cdk::export::candid::candid_method]
+async fn deposit() -> Result<Tokens, String> {
+    let caller = ic_cdk::caller();
+    let ledger = SERVICE.with(|service| service.borrow_mut().lock_deposit(caller))?;
+
+    let result = move_deposit(caller, &ledger).await;
+    SERVICE.with(|service| {
+        let mut service = service.borrow_mut();
+        if let Ok(amount) = result {
+            service.credit_deposit(caller, amount);
+        }
+        service.unlock_ledger_operations(caller);
+    });
+    result
+}
+
+async fn move_deposit(caller: Principal, ledger: &LedgerParams) -> Result<Tokens, String> {
+    let subaccount = deposit_subaccount(&caller);
+    let balance = ic_ledger_types::account_balance(
+        ledger.ledger_canister_id,
+        AccountBalanceArgs {
+            account: AccountIdentifier::new(&ic_cdk::id(), &subaccount),
+        },
+    )
+    .await
+    .map_err(|e| format!("failed to call ledger: {:?}", e))?;
+    if balance.e8s() <= ledger.transaction_fee.amount_e8s {
+        return Err(format!("Nothing to deposit, the balance is {}", balance));
+    }
+
+    let amount = Tokens {
+        amount_e8s: balance.e8s() - ledger.transaction_fee.amount_e8s,
+    };
+    ledger_transfer(
+        ledger,
+        Some(subaccount),
+        AccountIdentifier::new(&ic_cdk::id(), &DEFAULT_SUBACCOUNT),
+        amount,
+    )
+    .await?;
+    Ok(amount)
+}
+
+/// Send tokens from the caller's account to its ledger account
+///
+/// The ledger's fee is debited on top of the amount. Withdrawals are refused while the treasury
+/// holds less than the tokens owed to the members.
+#[update]
+#[ic_cdk::export::candid::candid_method]
+async fn withdraw(args: WithdrawArgs) -> Result<BlockIndex, String> {
+    let caller = ic_cdk::caller();
+    let ledger = SERVICE.with(|service| service.borrow_mut().lock_ledger_operations(caller))?;
+
+    let result = send_withdrawal(caller, &ledger, args).await;
+    SERVICE.with(|service| service.borrow_mut().unlock_ledger_operations(caller));
+    result
+}
+
+async fn send_withdrawal(
+    caller: Principal,
+    ledger: &LedgerParams,
+    args: WithdrawArgs,
+) -> Result<BlockIndex, String> {
+    let balance = treasury_balance(ledger).await?;
+    SERVICE.with(|service| {
+        service
+            .borrow_mut()
+            .debit_withdrawal(caller, args.amount, balance)
+    })?;
+
+    let to_subaccount = args.to_subaccount.unwrap_or(DEFAULT_SUBACCOUNT);
+    let result = ledger_transfer(
+        ledger,
+        None,
+        AccountIdentifier::new(&caller, &to_subaccount),
+        args.amount,
+    )
+    .await;
+    if result.is_err() {
+        SERVICE.with(|service| service.borrow_mut().refund_withdrawal(caller, args.amount));
+    }
+    result
+}
+
+/// Send tokens the DAO owns from the treasury
+///
+/// Only callable via proposal execution. The tokens owed to the members can't be spent.
+#[update]
+#[ic_cdk::export::candid::candid_method]
+async fn treasury_transfer(args: TreasuryTransferArgs) -> Result<BlockIndex, String> {
+    let dao = ic_cdk::id();
+    let ledger = SERVICE.with(|service| service.borrow_mut().lock_treasury_transfer())?;
+
+    let result = spend_treasury(&ledger, args).await;
+    SERVICE.with(|service| service.borrow_mut().unlock_ledger_operations(dao));
+    result
+}
+
+async fn spend_treasury(
+    ledger: &LedgerParams,
+    args: TreasuryTransferArgs,
+) -> Result<BlockIndex, String> {
+    let balance = treasury_balance(ledger).await?;
+    SERVICE.with(|service| {
+        service
+            .borrow_mut()
+            .reserve_treasury_transfer(args.amount, balance)
+    })?;
+
+    let to_subaccount = args.to_subaccount.unwrap_or(DEFAULT_SUBACCOUNT);
+    ledger_transfer(
+        ledger,
+        None,
+        AccountIdentifier::new(&args.to, &to_subaccount),
+        args.amount,
+    )
+    .await
+}
+
+async fn treasury_balance(ledger: &LedgerParams) -> Result<Tokens, String> {
+    let treasury = AccountIdentifier::new(&ic_cdk::id(), &DEFAULT_SUBACCOUNT);
+    let balance = ic_ledger_types::account_balance(
+        ledger.ledger_canister_id,
+        AccountBalanceArgs { account: treasury },
+    )
+    .await
+    .map_err(|e| format!("failed to call ledger: {:?}", e))?;
+    Ok(Tokens {
+        amount_e8s: balance.e8s(),
+    })
+}
+
+async fn ledger_transfer(
+    ledger: &LedgerParams,
+    from_subaccount: Option<Subaccount>,
+    to: AccountIdentifier,
+    amount: Tokens,
+) -> Result<BlockIndex, String> {
+    let transfer_args = ic_ledger_types::TransferArgs {
+        memo: Memo(0),
+        amount: ic_ledger_types::Tokens::from_e8s(amount.amount_e8s),
+        fee: ic_ledger_types::Tokens::from_e8s(ledger.transaction_fee.amount_e8s),
+        from_subaccount,
+        to,
+        created_at_time: None,
+    };
+    ic_ledger_types::transfer(ledger.ledger_canister_id, transfer_args)
+        .await
+        .map_err(|e| format!("failed to call ledger: {:?}", e))?
+        .map_err(|e| format!("ledger transfer error {:?}", e))
+}
+
+/// The subaccount of the canister a principal deposits to: the length of the principal, followed
+/// by its bytes
+fn deposit_subaccount(principal: &Principal) -> Subaccount {
+    let bytes = principal.as_slice();
+    let mut subaccount = [0; 32];
+    subaccount[0] = bytes.len() as u8;
+    subaccount[1..1 + bytes.len()].copy_from_slice(bytes);
+    Subaccount(subaccount)
+}
@@ -1,11 +1,15 @@
+mod delegation;
 mod env;
 mod heartbeat;
 mod init;
+mod ledger;
 mod service;
 mod types;
+mod upgrade;
 
 use crate::service::BasicDaoService;
 use crate::types::*;
+use ic_cdk::export::Principal;
 use ic_cdk_macros::*;
 use std::cell::RefCell;
 
@@ -43,6 +47,12 @@ fn submit_proposal(proposal: ProposalPayload) -> Result<u64, String> {
     SERVICE.with(|service| service.borrow_mut().submit_proposal(proposal))
 }
 
+#[update]
+#[ic_cdk::export::candid::candid_method]
+fn submit_typed_proposal(kind: ProposalKind) -> Result<u64, String> {
+    SERVICE.with(|service| service.borrow_mut().submit_typed_proposal(kind))
+}
+
 #[query]
 #[ic_cdk::export::candid::candid_method(query)]
 fn get_proposal(proposal_id: u64) -> Option<Proposal> {
@@ -51,8 +61,20 @@ fn get_proposal(proposal_id: u64) -> Option<Proposal> {
 
 #[query]
 #[ic_cdk::export::candid::candid_method(query)]
-fn list_proposals() -> Vec<Proposal> {
-    SERVICE.with(|service| service.borrow().list_proposals())
+fn list_proposals(filter: ProposalFilter, cursor: Option<u64>, limit: u64) -> ProposalPage {
+    SERVICE.with(|service| service.borrow().list_proposals(filter, cursor, limit))
+}
+
+#[query]
+#[ic_cdk::export::candid::candid_method(query)]
+fn list_ballots(proposal_id: u64) -> Result<Vec<Ballot>, String> {
+    SERVICE.with(|service| service.borrow().list_ballots(proposal_id))
+}
+
+#[query]
+#[ic_cdk::export::candid::candid_method(query)]
+fn my_votes() -> Vec<CastVote> {
+    SERVICE.with(|service| service.borrow().my_votes())
 }
 
 #[update]
@@ -61,12 +83,102 @@ fn vote(args: VoteArgs) -> Result<ProposalState, String> {
     SERVICE.with(|service| service.borrow_mut().vote(args))
 }
 
+#[update]
+#[ic_cdk::export::candid::candid_method]
+fn delegate(args: DelegateArgs) -> Result<(), String> {
+    SERVICE.with(|service| service.borrow_mut().delegate(args))
+}
+
+#[update]
+#[ic_cdk::export::candid::candid_method]
+fn undelegate(scope: DelegationScope) -> Result<(), String> {
+    SERVICE.with(|service| service.borrow_mut().undelegate(scope))
+}
+
+#[query]
+#[ic_cdk::export::candid::candid_method(query)]
+fn list_delegations() -> Vec<Delegation> {
+    SERVICE.with(|service| service.borrow().list_delegations())
+}
+
+#[query]
+#[ic_cdk::export::candid::candid_method(query)]
+fn get_voting_power(proposal_id: u64, principal: Principal) -> Result<VotingPower, String> {
+    SERVICE.with(|service| service.borrow().get_voting_power(proposal_id, principal))
+}
+
+#[update]
+#[ic_cdk::export::candid::candid_method]
+fn stake(args: StakeArgs) -> Result<StakeInfo, String> {
+    SERVICE.with(|service| service.borrow_mut().stake(args))
+}
+
+#[update]
+#[ic_cdk::export::candid::candid_method]
+fn start_dissolving() -> Result<StakeInfo, String> {
+    SERVICE.with(|service| service.borrow_mut().start_dissolving())
+}
+
+#[update]
+#[ic_cdk::export::candid::candid_method]
+fn stop_dissolving() -> Result<StakeInfo, String> {
+    SERVICE.with(|service| service.borrow_mut().stop_dissolving())
+}
+
+#[update]
+#[ic_cdk::export::candid::candid_method]
+fn unstake() -> Result<Tokens, String> {
+    SERVICE.with(|service| service.borrow_mut().unstake())
+}
+
+#[query]
+#[ic_cdk::export::candid::candid_method(query)]
+fn get_stake() -> Option<StakeInfo> {
+    SERVICE.with(|service| service.borrow().get_stake())
+}
+
 #[update]
 #[ic_cdk::export::candid::candid_method]
 fn update_system_params(payload: UpdateSystemParamsPayload) {
     SERVICE.with(|service| service.borrow_mut().update_system_params(payload))
 }
 
+#[update]
+#[ic_cdk::export::candid::candid_method]
+fn add_member(account: Account) -> Result<(), String> {
+    SERVICE.with(|service| service.borrow_mut().add_member(account))
+}
+
+#[update]
+#[ic_cdk::export::candid::candid_method]
+fn remove_member(member: Principal) -> Result<(), String> {
+    SERVICE.with(|service| service.borrow_mut().remove_member(member))
+}
+
+#[query]
+#[ic_cdk::export::candid::candid_method(query)]
+fn get_execution_params() -> ExecutionParams {
+    SERVICE.with(|service| service.borrow().execution_params.clone())
+}
+
+#[update]
+#[ic_cdk::export::candid::candid_method]
+fn update_execution_params(params: ExecutionParams) -> Result<(), String> {
+    SERVICE.with(|service| service.borrow_mut().update_execution_params(params))
+}
+
+#[query]
+#[ic_cdk::export::candid::candid_method(query)]
+fn get_ledger_params() -> Option<LedgerParams> {
+    SERVICE.with(|service| service.borrow().ledger.clone())
+}
+
+#[update]
+#[ic_cdk::export::candid::candid_method]
+fn update_ledger_params(params: LedgerParams) -> Result<(), String> {
+    SERVICE.with(|service| service.borrow_mut().update_ledger_params(params))
+}
+
 // ic_cdk::export::candid::export_service!();
 
 // #[ic_cdk_macros::query(name = "__get_candid_interface_tmp_hack")]
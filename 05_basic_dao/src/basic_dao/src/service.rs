@@ -1,15 +1,47 @@
+use crate::delegation::{self, Delegations};
 use crate::env::{EmptyEnvironment, Environment};
 use crate::types::*;
+use ic_cdk::api::call::{CallResult, RejectionCode};
+use ic_cdk::export::candid::types::Type;
+use ic_cdk::export::candid::{decode_one, encode_one, CandidType, IDLArgs, TypeEnv};
 use ic_cdk::export::Principal;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+
+/// The most proposals `list_proposals` returns at once
+pub const MAX_PROPOSALS_PAGE: u64 = 100;
+
+/// The most proposals `list_proposals` looks at to fill a page
+pub const MAX_PROPOSALS_SCANNED: u64 = 1_000;
+
+/// The most cycles a proposal can attach to one of its calls
+pub const MAX_CALL_CYCLES: u64 = 100_000_000_000_000;
+
+/// The cycles the DAO keeps after attaching cycles to a call, so that attaching them doesn't trap
+pub const MIN_CYCLES_BALANCE: u64 = 1_000_000_000_000;
+
+/// Staked tokens vote with up to twice their amount, for a dissolve delay of 8 years or more
+pub const MAX_DISSOLVE_DELAY: u64 = 8 * 365 * 24 * 60 * 60 * 1_000_000_000;
 
 /// Implements the Basic DAO interface
 pub struct BasicDaoService {
     pub env: Box<dyn Environment>,
     pub accounts: HashMap<Principal, Tokens>,
-    pub proposals: HashMap<u64, Proposal>,
+    pub proposals: BTreeMap<u64, Proposal>,
     pub next_proposal_id: u64,
     pub system_params: SystemParams,
+    // The voting power, when each open proposal was submitted, of the principals whose balance or
+    // stake changed since. Votes are weighed by these so that tokens can't be transferred to another
+    // account and used to vote twice, the others still have the voting power they had then.
+    pub balance_snapshots: HashMap<u64, HashMap<Principal, Tokens>>,
+    pub delegations: Delegations,
+    // The votes cast in person on each proposal, the tallies of the open ones are recounted from these
+    pub ballots: HashMap<u64, HashMap<Principal, Ballot>>,
+    pub stakes: HashMap<Principal, Stake>,
+    pub execution_params: ExecutionParams,
+    pub ledger: Option<LedgerParams>,
+    // The principals with a deposit, withdrawal or treasury transfer in flight, and the tokens that
+    // leave the treasury with it. Those are still owed until the ledger transfer is done.
+    pub ledger_operations: HashMap<Principal, Tokens>,
 }
 
 impl Default for BasicDaoService {
@@ -17,55 +49,200 @@ impl Default for BasicDaoService {
         BasicDaoService {
             env: Box::new(EmptyEnvironment {}),
             accounts: HashMap::new(),
-            proposals: HashMap::new(),
+            proposals: BTreeMap::new(),
             next_proposal_id: 0,
             system_params: Default::default(),
+            balance_snapshots: HashMap::new(),
+            delegations: HashMap::new(),
+            ballots: HashMap::new(),
+            stakes: HashMap::new(),
+            execution_params: Default::default(),
+            ledger: None,
+            ledger_operations: HashMap::new(),
         }
     }
 }
 
 impl From<BasicDaoStableStorage> for BasicDaoService {
     fn from(stable: BasicDaoStableStorage) -> BasicDaoService {
-        let accounts = stable
+        let accounts: HashMap<Principal, Tokens> = stable
             .accounts
             .clone()
             .into_iter()
             .map(|a| (a.owner, a.tokens))
             .collect();
-        let proposals = stable
-            .proposals
-            .clone()
-            .into_iter()
-            .map(|p| (p.id, p))
+        let proposals = with_deadlines(
+            stable.proposals.clone(),
+            stable.system_params.proposal_voting_period,
+        );
+        // Open proposals are weighed by the initial balances, none changed yet
+        let balance_snapshots = proposals
+            .values()
+            .filter(|p| p.state == ProposalState::Open)
+            .map(|p| (p.id, HashMap::new()))
             .collect();
+        // Don't reissue the IDs of the given proposals
+        let next_proposal_id = proposals.keys().last().map_or(0, |id| id + 1);
 
         BasicDaoService {
             env: Box::new(EmptyEnvironment {}),
             accounts,
             proposals,
-            next_proposal_id: 0,
+            next_proposal_id,
             system_params: stable.system_params,
+            balance_snapshots,
+            delegations: HashMap::new(),
+            ballots: HashMap::new(),
+            stakes: HashMap::new(),
+            execution_params: Default::default(),
+            ledger: stable.ledger,
+            ledger_operations: HashMap::new(),
         }
     }
 }
 
+impl From<VersionedStableStorage> for BasicDaoService {
+    fn from(stable: VersionedStableStorage) -> BasicDaoService {
+        match stable {
+            VersionedStableStorage::V1(stable) => BasicDaoService {
+                env: Box::new(EmptyEnvironment {}),
+                accounts: from_accounts(stable.accounts),
+                proposals: with_deadlines(
+                    stable.proposals,
+                    stable.system_params.proposal_voting_period,
+                ),
+                next_proposal_id: stable.next_proposal_id,
+                system_params: stable.system_params,
+                balance_snapshots: stable
+                    .balance_snapshots
+                    .into_iter()
+                    .map(|s| (s.proposal_id, from_accounts(s.accounts)))
+                    .collect(),
+                delegations: stable
+                    .delegations
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|d| ((d.delegator, d.scope), d.delegate))
+                    .collect(),
+                ballots: stable
+                    .ballots
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|v| {
+                        let ballots = v.ballots.into_iter().map(|b| (b.voter, b)).collect();
+                        (v.proposal_id, ballots)
+                    })
+                    .collect(),
+                stakes: stable
+                    .stakes
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|s| (s.owner, s))
+                    .collect(),
+                execution_params: stable.execution_params.unwrap_or_default(),
+                ledger: stable.ledger,
+                ledger_operations: HashMap::new(),
+            },
+        }
+    }
+}
+
+impl From<&BasicDaoService> for VersionedStableStorage {
+    fn from(service: &BasicDaoService) -> VersionedStableStorage {
+        let proposals: Vec<Proposal> = service.proposals.values().cloned().collect();
+        let mut balance_snapshots: Vec<BalanceSnapshot> = service
+            .balance_snapshots
+            .iter()
+            .map(|(proposal_id, accounts)| BalanceSnapshot {
+                proposal_id: *proposal_id,
+                accounts: to_accounts(accounts),
+            })
+            .collect();
+        balance_snapshots.sort_by_key(|s| s.proposal_id);
+        let mut ballots: Vec<ProposalBallots> = service
+            .ballots
+            .iter()
+            .map(|(proposal_id, ballots)| {
+                let mut ballots: Vec<Ballot> = ballots.values().cloned().collect();
+                ballots.sort_by_key(|b| b.voter);
+                ProposalBallots {
+                    proposal_id: *proposal_id,
+                    ballots,
+                }
+            })
+            .collect();
+        ballots.sort_by_key(|v| v.proposal_id);
+        let mut stakes: Vec<Stake> = service.stakes.values().cloned().collect();
+        stakes.sort_by_key(|s| s.owner);
+
+        VersionedStableStorage::V1(StableStorageV1 {
+            accounts: to_accounts(&service.accounts),
+            proposals,
+            system_params: service.system_params.clone(),
+            next_proposal_id: service.next_proposal_id,
+            balance_snapshots,
+            delegations: Some(service.list_delegations()),
+            stakes: Some(stakes),
+            execution_params: Some(service.execution_params.clone()),
+            ledger: service.ledger.clone(),
+            ballots: Some(ballots),
+        })
+    }
+}
+
+/// Give the open proposals saved without a deadline the end of the current voting period
+fn with_deadlines(proposals: Vec<Proposal>, voting_period: u64) -> BTreeMap<u64, Proposal> {
+    proposals
+        .into_iter()
+        .map(|mut p| {
+            if p.state == ProposalState::Open && p.deadline.is_none() && voting_period > 0 {
+                p.deadline = Some(p.timestamp.saturating_add(voting_period));
+            }
+            (p.id, p)
+        })
+        .collect()
+}
+
+fn from_accounts(accounts: Vec<Account>) -> HashMap<Principal, Tokens> {
+    accounts.into_iter().map(|a| (a.owner, a.tokens)).collect()
+}
+
+fn to_accounts(accounts: &HashMap<Principal, Tokens>) -> Vec<Account> {
+    let mut accounts: Vec<Account> = accounts
+        .iter()
+        .map(|(owner, tokens)| Account {
+            owner: *owner,
+            tokens: *tokens,
+        })
+        .collect();
+    accounts.sort_by_key(|a| a.owner);
+    accounts
+}
+
 /// Implements the Basic DAO interface
 impl BasicDaoService {
     /// Transfer tokens from the caller's account to another account
     pub fn transfer(&mut self, transfer: TransferArgs) -> Result<(), String> {
         let caller = self.env.caller();
 
-        if let Some(account) = self.accounts.get_mut(&caller) {
-            if account.clone() < transfer.amount {
-                return Err(format!(
-                    "Caller's account has insufficient funds to transfer {:?}",
-                    transfer.amount
-                ));
-            } else {
-                *account -= transfer.amount + self.system_params.transfer_fee;
-                let to_account = self.accounts.entry(transfer.to).or_default();
-                *to_account += transfer.amount;
-            }
+        if let Some(account) = self.accounts.get(&caller) {
+            // The fee is paid on top of the amount
+            let total = transfer
+                .amount
+                .checked_add(self.system_params.transfer_fee)
+                .filter(|total| account >= total)
+                .ok_or_else(|| {
+                    format!(
+                        "Caller's account has insufficient funds to transfer {:?}",
+                        transfer.amount
+                    )
+                })?;
+            let to_balance = self.accounts.get(&transfer.to).cloned().unwrap_or_default();
+            let credited = to_balance
+                .checked_add(transfer.amount)
+                .ok_or("The recipient's account can't hold that many tokens")?;
+            *self.account_mut(caller) -= total;
+            *self.account_mut(transfer.to) = credited;
         } else {
             return Err("Caller needs an account to transfer funds".to_string());
         }
@@ -95,25 +272,91 @@ impl BasicDaoService {
     ///
     /// A proposal contains a canister ID, method name and method args. If enough users
     /// vote "yes" on the proposal, the given method will be called with the given method
-    /// args on the given canister.
+    /// args on the given canister. Only the methods of the DAO and those on the allowlist
+    /// can be called, and the args must be valid Candid.
     pub fn submit_proposal(&mut self, payload: ProposalPayload) -> Result<u64, String> {
+        let mut decoded_calls = vec![];
+        for (index, call) in payload.calls().iter().enumerate() {
+            self.check_callable(&call.canister_id, &call.method)?;
+            if call.cycles.unwrap_or(0) > MAX_CALL_CYCLES {
+                return Err(format!(
+                    "Call {} attaches more than {} cycles",
+                    index, MAX_CALL_CYCLES
+                ));
+            }
+            // The args of the DAO's methods are decoded with their types, to show the field names
+            let arg_types = if call.canister_id == self.env.canister_id() {
+                dao_arg_types(&call.method)
+            } else {
+                None
+            };
+            let args = match arg_types {
+                Some(types) => {
+                    IDLArgs::from_bytes_with_types(&call.message, &TypeEnv::new(), &types)
+                }
+                None => IDLArgs::from_bytes(&call.message),
+            }
+            .map_err(|e| format!("The message of call {} is not valid Candid: {}", index, e))?;
+            decoded_calls.push(args.to_string());
+        }
+
+        self.create_proposal(payload, None, Some(decoded_calls))
+    }
+
+    /// Submit a proposal of a built-in kind
+    ///
+    /// The proposal calls the method of the DAO matching its kind, motions make no call.
+    pub fn submit_typed_proposal(&mut self, kind: ProposalKind) -> Result<u64, String> {
+        let (method, message) = match &kind {
+            ProposalKind::UpdateSystemParams(payload) => {
+                ("update_system_params", encode_one(payload))
+            }
+            ProposalKind::TreasuryTransfer(args) => ("treasury_transfer", encode_one(args)),
+            ProposalKind::AddMember(account) => ("add_member", encode_one(account)),
+            ProposalKind::RemoveMember(member) => ("remove_member", encode_one(member)),
+            ProposalKind::Motion(_) => ("", Ok(vec![])),
+        };
+        let payload = ProposalPayload {
+            canister_id: self.env.canister_id(),
+            method: method.to_string(),
+            message: message.map_err(|e| format!("Failed to encode the proposal: {}", e))?,
+            cycles: None,
+            next_calls: None,
+        };
+
+        self.create_proposal(payload, Some(kind), None)
+    }
+
+    fn create_proposal(
+        &mut self,
+        payload: ProposalPayload,
+        kind: Option<ProposalKind>,
+        decoded_calls: Option<Vec<String>>,
+    ) -> Result<u64, String> {
         self.deduct_proposal_submission_deposit()?; // 先扣除保证金
 
         let proposal_id = self.next_proposal_id;
         self.next_proposal_id += 1;
 
+        // Changing the voting period later doesn't move the deadline of the open proposals
+        let now = self.env.now();
+        let voting_period = self.system_params.proposal_voting_period;
         let proposal = Proposal {
             id: proposal_id,
-            timestamp: self.env.now(),
+            timestamp: now,
             proposer: self.env.caller(),
             payload,
             state: ProposalState::Open,
             votes_yes: Default::default(),
             votes_no: Default::default(),
-            voters: vec![],
+            execution: None,
+            kind,
+            decoded_calls,
+            deadline: (voting_period > 0).then(|| now.saturating_add(voting_period)),
         };
 
         self.proposals.insert(proposal_id, proposal);
+        self.balance_snapshots.insert(proposal_id, HashMap::new());
         Ok(proposal_id)
     }
 
@@ -122,15 +365,85 @@ impl BasicDaoService {
         self.proposals.get(&proposal_id).cloned()
     }
 
-    /// Return the list of all proposals
-    pub fn list_proposals(&self) -> Vec<Proposal> {
-        self.proposals.values().cloned().collect()
+    /// Return the proposals matching the filter, by descending ID
+    ///
+    /// Starts below the `cursor` ID if one is given, and returns between 1 and MAX_PROPOSALS_PAGE
+    /// proposals, as many as `limit`. At most MAX_PROPOSALS_SCANNED proposals are looked at, so a
+    /// page can come back short, or even empty, with a `next_cursor` to continue where it stopped.
+    pub fn list_proposals(
+        &self,
+        filter: ProposalFilter,
+        cursor: Option<u64>,
+        limit: u64,
+    ) -> ProposalPage {
+        let limit = limit.clamp(1, MAX_PROPOSALS_PAGE) as usize;
+        let mut proposals = vec![];
+        let mut next_cursor = None;
+        let older = self.proposals.range(..cursor.unwrap_or(u64::MAX)).rev();
+        for (scanned, (id, proposal)) in (0..).zip(older) {
+            if proposals.len() == limit || scanned == MAX_PROPOSALS_SCANNED {
+                next_cursor = Some(id + 1);
+                break;
+            }
+            if filter.matches(proposal) {
+                proposals.push(proposal.clone());
+            }
+        }
+
+        ProposalPage {
+            proposals,
+            next_cursor,
+        }
+    }
+
+    /// Return how the caller voted in person, by descending proposal ID
+    pub fn my_votes(&self) -> Vec<CastVote> {
+        let caller = self.env.caller();
+        let mut votes: Vec<CastVote> = self
+            .ballots
+            .iter()
+            .filter_map(|(proposal_id, ballots)| {
+                ballots.get(&caller).map(|ballot| CastVote {
+                    proposal_id: *proposal_id,
+                    ballot: ballot.clone(),
+                })
+            })
+            .collect();
+        votes.sort_by_key(|v| std::cmp::Reverse(v.proposal_id));
+        votes
     }
 
-    // Vote on an open proposal
+    /// Return the ballots cast on a proposal, in the order they were cast
+    pub fn list_ballots(&self, proposal_id: u64) -> Result<Vec<Ballot>, String> {
+        if !self.proposals.contains_key(&proposal_id) {
+            return Err(format!("No proposal with ID {} exists", proposal_id));
+        }
+        let mut ballots: Vec<Ballot> = self
+            .ballots
+            .get(&proposal_id)
+            .map(|ballots| ballots.values().cloned().collect())
+            .unwrap_or_default();
+        ballots.sort_by_key(|b| (b.timestamp, b.voter));
+        Ok(ballots)
+    }
+
+    /// Vote on an open proposal
+    ///
+    /// The vote is weighed by the caller's balance when the proposal was submitted, plus the
+    /// balances delegated to the caller by those who haven't voted themselves. Voting again
+    /// replaces the caller's vote, as long as the proposal is open.
     pub fn vote(&mut self, args: VoteArgs) -> Result<ProposalState, String> {
         let caller = self.env.caller();
 
+        let voting_power = self.get_voting_power(args.proposal_id, caller)?;
+        let weight = voting_power.own + voting_power.delegated;
+        if weight.amount_e8s == 0 {
+            return Err(format!(
+                "Caller does not have any voting power on proposal {}",
+                args.proposal_id
+            ));
+        }
+
         let proposal = self
             .proposals
             .get_mut(&args.proposal_id)
@@ -143,38 +456,391 @@ impl BasicDaoService {
             ));
         }
 
-        let voting_tokens = self
-            .accounts
-            .get(&caller)
-            .ok_or_else(|| "Caller does not have any tokens to vote with".to_string())?
-            .clone();
+        // The heartbeat may not have settled the proposal yet
+        if proposal
+            .deadline
+            .map_or(false, |deadline| self.env.now() >= deadline)
+        {
+            return Err(format!(
+                "The voting period of proposal {} is over",
+                args.proposal_id
+            ));
+        }
+
+        // Casting a vote in person overrides the caller's delegations
+        self.ballots.entry(args.proposal_id).or_default().insert(
+            caller,
+            Ballot {
+                voter: caller,
+                vote: args.vote,
+                weight,
+                timestamp: self.env.now(),
+            },
+        );
+        let (votes_yes, votes_no) = self.tally(args.proposal_id);
+        let proposal = self.proposals.get_mut(&args.proposal_id).unwrap();
+        proposal.votes_yes = votes_yes;
+        proposal.votes_no = votes_no;
+
+        let accepted = proposal.votes_yes >= self.system_params.proposal_vote_threshold;
+        if accepted {
+            proposal.state = ProposalState::Accepted;
+            proposal.execution = Some(Execution::new(
+                self.env
+                    .now()
+                    .saturating_add(self.execution_params.timelock),
+            ));
+        }
+
+        if proposal.votes_no >= self.system_params.proposal_vote_threshold {
+            proposal.state = ProposalState::Rejected;
+        }
+
+        let state = proposal.state.clone();
+        let proposer = proposal.proposer;
+        if state != ProposalState::Open {
+            self.close_voting(args.proposal_id);
+        }
+        if accepted {
+            // 成功了要还钱
+            self.refund_submission_deposit(proposer);
+        }
+
+        Ok(state)
+    }
+
+    /// Settle the open proposals whose voting period is over
+    ///
+    /// If the votes cast reach `proposal_quorum`, the proposal is accepted when there are more
+    /// "yes" than "no" votes, and rejected otherwise. Without a quorum, it expires. The deposit is
+    /// only refunded for accepted proposals. Returns the IDs of the settled proposals.
+    pub fn settle_expired_proposals(&mut self) -> Vec<u64> {
+        let now = self.env.now();
+
+        let mut settled = vec![];
+        let mut accepted_proposers = vec![];
+        let expired: Vec<u64> = self
+            .proposals
+            .values()
+            .filter(|p| {
+                p.state == ProposalState::Open
+                    && p.deadline.map_or(false, |deadline| now >= deadline)
+            })
+            .map(|p| p.id)
+            .collect();
+        for proposal_id in expired {
+            // The delegations may have changed since the last vote
+            let (votes_yes, votes_no) = self.tally(proposal_id);
+            let proposal = self.proposals.get_mut(&proposal_id).unwrap();
+            proposal.votes_yes = votes_yes;
+            proposal.votes_no = votes_no;
 
-        if proposal.voters.contains(&self.env.caller()) {
-            return Err("Already voted".to_string());
+            let turnout = proposal.votes_yes + proposal.votes_no;
+            proposal.state = if turnout < self.system_params.proposal_quorum {
+                ProposalState::Expired
+            } else if proposal.votes_yes > proposal.votes_no {
+                accepted_proposers.push(proposal.proposer);
+                proposal.execution = Some(Execution::new(
+                    now.saturating_add(self.execution_params.timelock),
+                ));
+                ProposalState::Accepted
+            } else {
+                ProposalState::Rejected
+            };
+            settled.push(proposal.id);
+        }
+        for proposal_id in settled.iter() {
+            self.close_voting(*proposal_id);
+        }
+        for proposer in accepted_proposers {
+            self.refund_submission_deposit(proposer);
         }
+        settled
+    }
 
-        match args.vote {
-            Vote::Yes => proposal.votes_yes += voting_tokens,
-            Vote::No => proposal.votes_no += voting_tokens,
+    /// Delegate the caller's votes on the proposals in scope to another principal
+    ///
+    /// Replaces the caller's previous delegation with the same scope. Delegations that would let
+    /// the votes on some proposal go around in a circle are rejected.
+    pub fn delegate(&mut self, args: DelegateArgs) -> Result<(), String> {
+        let caller = self.env.caller();
+        if args.delegate == caller {
+            return Err("Cannot delegate to oneself".to_string());
+        }
+        if delegation::creates_cycle(&self.delegations, caller, args.delegate, args.scope.clone()) {
+            return Err(format!(
+                "Delegating to {} would create a cycle of delegations",
+                args.delegate
+            ));
         }
 
-        proposal.voters.push(caller);
+        self.delegations.insert((caller, args.scope), args.delegate);
+        Ok(())
+    }
+
+    /// Remove the caller's delegation with the given scope
+    pub fn undelegate(&mut self, scope: DelegationScope) -> Result<(), String> {
+        let caller = self.env.caller();
+        self.delegations
+            .remove(&(caller, scope))
+            .map(|_| ())
+            .ok_or_else(|| "Caller has no delegation with this scope".to_string())
+    }
+
+    /// Lists all delegations
+    pub fn list_delegations(&self) -> Vec<Delegation> {
+        let mut delegations: Vec<Delegation> = self
+            .delegations
+            .iter()
+            .map(|((delegator, scope), delegate)| Delegation {
+                delegator: *delegator,
+                delegate: *delegate,
+                scope: scope.clone(),
+            })
+            .collect();
+        delegations.sort_by_key(|d| (d.delegator, d.delegate));
+        delegations
+    }
+
+    /// Return the voting power of a principal on an open proposal
+    pub fn get_voting_power(
+        &self,
+        proposal_id: u64,
+        principal: Principal,
+    ) -> Result<VotingPower, String> {
+        let proposal = self
+            .proposals
+            .get(&proposal_id)
+            .ok_or_else(|| format!("No proposal with ID {} exists", proposal_id))?;
+        let balances = self
+            .snapshot_balances(proposal_id)
+            .ok_or_else(|| format!("Proposal {} is not open for voting", proposal_id))?;
+
+        Ok(delegation::voting_power(
+            &self.delegations,
+            &balances,
+            self.ballots.get(&proposal_id).unwrap_or(&HashMap::new()),
+            principal,
+            &proposal.calls(),
+        ))
+    }
+
+    /// Count the votes on an open proposal, with the current delegations
+    fn tally(&self, proposal_id: u64) -> (Tokens, Tokens) {
+        let (proposal, balances) = match (
+            self.proposals.get(&proposal_id),
+            self.snapshot_balances(proposal_id),
+        ) {
+            (Some(proposal), Some(balances)) => (proposal, balances),
+            _ => return Default::default(),
+        };
 
-        if proposal.votes_yes >= self.system_params.proposal_vote_threshold {
-            // Refund the proposal deposit when the proposal is accepted
-            if let Some(account) = self.accounts.get_mut(&proposal.proposer) {
-                *account += self.system_params.proposal_submission_deposit.clone();
-                // 成功了要还钱
+        delegation::tally(
+            &self.delegations,
+            &balances,
+            self.ballots.get(&proposal_id).unwrap_or(&HashMap::new()),
+            &proposal.calls(),
+        )
+    }
+
+    /// Record the weight each ballot was counted with in the final tally, and drop what is only
+    /// needed while a proposal is open
+    fn close_voting(&mut self, proposal_id: u64) {
+        if let (Some(proposal), Some(balances), Some(ballots)) = (
+            self.proposals.get(&proposal_id),
+            self.snapshot_balances(proposal_id),
+            self.ballots.get(&proposal_id),
+        ) {
+            let calls = proposal.calls();
+            let weights: Vec<(Principal, Tokens)> = ballots
+                .keys()
+                .map(|voter| {
+                    let power = delegation::voting_power(
+                        &self.delegations,
+                        &balances,
+                        ballots,
+                        *voter,
+                        &calls,
+                    );
+                    (*voter, power.own + power.delegated)
+                })
+                .collect();
+            let ballots = self.ballots.get_mut(&proposal_id).unwrap();
+            for (voter, weight) in weights {
+                ballots.get_mut(&voter).unwrap().weight = weight;
             }
+        }
+        self.balance_snapshots.remove(&proposal_id);
+    }
 
-            proposal.state = ProposalState::Accepted;
+    /// Return the voting power every principal had when the open proposal was submitted
+    fn snapshot_balances(&self, proposal_id: u64) -> Option<HashMap<Principal, Tokens>> {
+        let proposal = self.proposals.get(&proposal_id)?;
+        let changed = self.balance_snapshots.get(&proposal_id)?;
+        let mut balances = self.voting_powers(proposal.timestamp);
+        balances.extend(
+            changed
+                .iter()
+                .map(|(principal, tokens)| (*principal, *tokens)),
+        );
+        Some(balances)
+    }
+
+    /// Keep the voting power a principal had when each open proposal was submitted, before its
+    /// balance or stake changes
+    fn preserve_voting_power(&mut self, principal: Principal) {
+        for (proposal_id, balances) in self.balance_snapshots.iter_mut() {
+            if balances.contains_key(&principal) {
+                continue;
+            }
+            let submitted = self.proposals.get(proposal_id).map_or(0, |p| p.timestamp);
+            let voting_power = self.accounts.get(&principal).copied().unwrap_or_default()
+                + self
+                    .stakes
+                    .get(&principal)
+                    .map_or(Tokens::default(), |stake| {
+                        staked_voting_power(stake, submitted)
+                    });
+            balances.insert(principal, voting_power);
         }
+    }
 
-        if proposal.votes_no >= self.system_params.proposal_vote_threshold {
-            proposal.state = ProposalState::Rejected;
+    /// Return the account of a principal to change its balance
+    fn account_mut(&mut self, owner: Principal) -> &mut Tokens {
+        self.preserve_voting_power(owner);
+        self.accounts.entry(owner).or_default()
+    }
+
+    /// Give the submission deposit of an accepted proposal back to its proposer
+    fn refund_submission_deposit(&mut self, proposer: Principal) {
+        if self.accounts.contains_key(&proposer) {
+            let deposit = self.system_params.proposal_submission_deposit;
+            *self.account_mut(proposer) += deposit;
+        }
+    }
+
+    /// Lock tokens from the caller's account for at least `dissolve_delay` nanoseconds
+    ///
+    /// Adds to the caller's stake if it has one, in which case the dissolve delay can't be shorter
+    /// than what is left of the stake's. The stake stops dissolving.
+    pub fn stake(&mut self, args: StakeArgs) -> Result<StakeInfo, String> {
+        let caller = self.env.caller();
+        let now = self.env.now();
+        if args.dissolve_delay > MAX_DISSOLVE_DELAY {
+            return Err(format!(
+                "The dissolve delay can't be longer than {} nanoseconds",
+                MAX_DISSOLVE_DELAY
+            ));
+        }
+        if let Some(stake) = self.stakes.get(&caller) {
+            if args.dissolve_delay < stake.dissolve_delay(now) {
+                return Err("The dissolve delay of a stake can't be shortened".to_string());
+            }
+        }
+
+        self.accounts
+            .get(&caller)
+            .filter(|account| **account >= args.amount)
+            .ok_or_else(|| {
+                format!(
+                    "Caller's account has insufficient funds to stake {:?}",
+                    args.amount
+                )
+            })?;
+        *self.account_mut(caller) -= args.amount;
+
+        let stake = self.stakes.entry(caller).or_insert(Stake {
+            owner: caller,
+            tokens: Tokens::default(),
+            state: DissolveState::Locked { dissolve_delay: 0 },
+        });
+        stake.tokens += args.amount;
+        stake.state = DissolveState::Locked {
+            dissolve_delay: args.dissolve_delay,
+        };
+
+        self.get_stake()
+            .ok_or_else(|| "Caller has no stake".to_string())
+    }
+
+    /// Start the dissolve delay of the caller's stake
+    pub fn start_dissolving(&mut self) -> Result<StakeInfo, String> {
+        let now = self.env.now();
+        let stake = self.caller_stake()?;
+        match stake.state {
+            DissolveState::Locked { dissolve_delay } => {
+                stake.state = DissolveState::Dissolving {
+                    dissolve_at: now + dissolve_delay,
+                };
+            }
+            DissolveState::Dissolving { .. } => {
+                return Err("Caller's stake is already dissolving".to_string())
+            }
         }
+        self.get_stake()
+            .ok_or_else(|| "Caller has no stake".to_string())
+    }
 
-        Ok(proposal.state.clone())
+    /// Lock the caller's dissolving stake again, for the rest of its dissolve delay
+    pub fn stop_dissolving(&mut self) -> Result<StakeInfo, String> {
+        let now = self.env.now();
+        let stake = self.caller_stake()?;
+        match stake.state {
+            DissolveState::Dissolving { .. } => {
+                stake.state = DissolveState::Locked {
+                    dissolve_delay: stake.dissolve_delay(now),
+                };
+            }
+            DissolveState::Locked { .. } => {
+                return Err("Caller's stake is not dissolving".to_string())
+            }
+        }
+        self.get_stake()
+            .ok_or_else(|| "Caller has no stake".to_string())
+    }
+
+    /// Return the tokens of the caller's dissolved stake to its account
+    pub fn unstake(&mut self) -> Result<Tokens, String> {
+        let now = self.env.now();
+        let stake = self.caller_stake()?;
+        match stake.state {
+            DissolveState::Dissolving { dissolve_at } if dissolve_at <= now => {}
+            _ => return Err("Caller's stake has not dissolved yet".to_string()),
+        }
+
+        let stake = self.stakes.remove(&self.env.caller()).unwrap();
+        *self.accounts.entry(stake.owner).or_default() += stake.tokens;
+        Ok(stake.tokens)
+    }
+
+    /// Return the caller's stake, if it has one
+    pub fn get_stake(&self) -> Option<StakeInfo> {
+        let now = self.env.now();
+        self.stakes.get(&self.env.caller()).map(|stake| StakeInfo {
+            tokens: stake.tokens,
+            state: stake.state.clone(),
+            dissolve_delay: stake.dissolve_delay(now),
+            voting_power: staked_voting_power(stake, now),
+        })
+    }
+
+    fn caller_stake(&mut self) -> Result<&mut Stake, String> {
+        let caller = self.env.caller();
+        if !self.stakes.contains_key(&caller) {
+            return Err("Caller has no stake".to_string());
+        }
+        self.preserve_voting_power(caller);
+        Ok(self.stakes.get_mut(&caller).unwrap())
+    }
+
+    /// Return the voting power of every principal: its balance, plus its stake weighed by the
+    /// stake's dissolve delay at the given time
+    fn voting_powers(&self, now: u64) -> HashMap<Principal, Tokens> {
+        let mut voting_powers = self.accounts.clone();
+        for stake in self.stakes.values() {
+            *voting_powers.entry(stake.owner).or_default() += staked_voting_power(stake, now);
+        }
+        voting_powers
     }
 
     /// Update system params
@@ -197,26 +863,392 @@ impl BasicDaoService {
         if let Some(proposal_submission_deposit) = payload.proposal_submission_deposit {
             self.system_params.proposal_submission_deposit = proposal_submission_deposit;
         }
+
+        if let Some(proposal_voting_period) = payload.proposal_voting_period {
+            self.system_params.proposal_voting_period = proposal_voting_period;
+        }
+
+        if let Some(proposal_quorum) = payload.proposal_quorum {
+            self.system_params.proposal_quorum = proposal_quorum;
+        }
+    }
+
+    /// Update the execution params
+    ///
+    /// Only callable via proposal execution
+    pub fn update_execution_params(&mut self, params: ExecutionParams) -> Result<(), String> {
+        if self.env.caller() != self.env.canister_id() {
+            return Err("Only callable via proposal execution".to_string());
+        }
+        if params.max_attempts == 0 {
+            return Err("Calls must be made at least once".to_string());
+        }
+
+        self.execution_params = params;
+        Ok(())
+    }
+
+    /// Open an account for a new member
+    ///
+    /// Only callable via proposal execution. When the DAO is backed by a ledger, the account opens
+    /// empty and the member deposits its tokens, as minted tokens would have nothing behind them.
+    pub fn add_member(&mut self, account: Account) -> Result<(), String> {
+        if self.env.caller() != self.env.canister_id() {
+            return Err("Only callable via proposal execution".to_string());
+        }
+        if self.accounts.contains_key(&account.owner) || self.stakes.contains_key(&account.owner) {
+            return Err(format!("{} is already a member", account.owner));
+        }
+        if self.ledger.is_some() && account.tokens != Tokens::default() {
+            return Err(
+                "The DAO is backed by a ledger, new members must deposit their tokens".to_string(),
+            );
+        }
+
+        *self.account_mut(account.owner) = account.tokens;
+        Ok(())
+    }
+
+    /// Close the account and the stake of a member, and drop the delegations from and to it
+    ///
+    /// Only callable via proposal execution. The DAO keeps the tokens: with a ledger, they stay in
+    /// the treasury and a `treasury_transfer` proposal can send them back to the member.
+    pub fn remove_member(&mut self, member: Principal) -> Result<(), String> {
+        if self.env.caller() != self.env.canister_id() {
+            return Err("Only callable via proposal execution".to_string());
+        }
+        if !self.accounts.contains_key(&member) && !self.stakes.contains_key(&member) {
+            return Err(format!("{} is not a member", member));
+        }
+        // A withdrawal in flight would be refunded to the closed account if the ledger rejected it
+        if self.ledger_operations.contains_key(&member) {
+            return Err(format!("{} has a ledger operation in flight", member));
+        }
+
+        self.preserve_voting_power(member);
+        self.accounts.remove(&member);
+        self.stakes.remove(&member);
+        self.delegations
+            .retain(|(delegator, _), delegate| *delegator != member && *delegate != member);
+        Ok(())
+    }
+
+    /// Update the ledger backing the DAO's tokens
+    ///
+    /// Only callable via proposal execution
+    pub fn update_ledger_params(&mut self, params: LedgerParams) -> Result<(), String> {
+        if self.env.caller() != self.env.canister_id() {
+            return Err("Only callable via proposal execution".to_string());
+        }
+
+        self.ledger = Some(params);
+        Ok(())
     }
 
-    /// Update the state of a proposal
-    pub fn update_proposal_state(&mut self, proposal_id: u64, new_state: ProposalState) {
-        if let Some(proposal) = self.proposals.get_mut(&proposal_id) {
-            proposal.state = new_state
+    /// Start a deposit, withdrawal or treasury transfer on behalf of a principal
+    ///
+    /// A principal has at most one of those in flight, so that the same deposit isn't credited twice.
+    pub fn lock_ledger_operations(&mut self, principal: Principal) -> Result<LedgerParams, String> {
+        let ledger = self
+            .ledger
+            .clone()
+            .ok_or_else(|| "The DAO is not backed by a ledger".to_string())?;
+        if self.ledger_operations.contains_key(&principal) {
+            return Err(format!(
+                "{} already has a ledger operation in flight",
+                principal
+            ));
+        }
+
+        self.ledger_operations.insert(principal, Tokens::default());
+        Ok(ledger)
+    }
+
+    /// End the ledger operation of a principal
+    pub fn unlock_ledger_operations(&mut self, principal: Principal) {
+        self.ledger_operations.remove(&principal);
+    }
+
+    /// Start a deposit of a member
+    ///
+    /// Only the members, given an account by `init` or an `AddMember` proposal, can deposit, so
+    /// that no one gets voting power by sending tokens to the DAO.
+    pub fn lock_deposit(&mut self, principal: Principal) -> Result<LedgerParams, String> {
+        if !self.accounts.contains_key(&principal) && !self.stakes.contains_key(&principal) {
+            return Err(format!(
+                "{} is not a member, only members can deposit",
+                principal
+            ));
         }
+        self.lock_ledger_operations(principal)
+    }
+
+    /// Credit the tokens moved from the owner's deposit account to the treasury
+    pub fn credit_deposit(&mut self, owner: Principal, amount: Tokens) {
+        *self.account_mut(owner) += amount;
+    }
+
+    /// Debit the tokens to withdraw, plus the ledger's fee, from the owner's account
+    pub fn debit_withdrawal(
+        &mut self,
+        owner: Principal,
+        amount: Tokens,
+        treasury_balance: Tokens,
+    ) -> Result<(), String> {
+        // Tokens minted by `init` or by proposals have no deposit behind them, so paying out while
+        // the treasury is short would leave the last members to withdraw with nothing
+        let liabilities = self.treasury_liabilities();
+        if liabilities.map_or(true, |liabilities| liabilities > treasury_balance) {
+            return Err(format!(
+                "The treasury holds {:?} but owes {:?} to the members, withdrawals are suspended",
+                treasury_balance, liabilities
+            ));
+        }
+
+        let total = amount.checked_add(self.ledger_fee()).ok_or_else(|| {
+            format!(
+                "Caller's account has insufficient funds to withdraw {:?}",
+                amount
+            )
+        })?;
+        self.accounts
+            .get(&owner)
+            .filter(|account| **account >= total)
+            .ok_or_else(|| {
+                format!(
+                    "Caller's account has insufficient funds to withdraw {:?}",
+                    amount
+                )
+            })?;
+        *self.account_mut(owner) -= total;
+        self.ledger_operations.insert(owner, total);
+        Ok(())
+    }
+
+    /// Give back the debited tokens of a withdrawal the ledger didn't make
+    pub fn refund_withdrawal(&mut self, owner: Principal, amount: Tokens) {
+        let total = amount + self.ledger_fee();
+        *self.account_mut(owner) += total;
+    }
+
+    /// Start a transfer from the treasury
+    ///
+    /// Only callable via proposal execution
+    pub fn lock_treasury_transfer(&mut self) -> Result<LedgerParams, String> {
+        if self.env.caller() != self.env.canister_id() {
+            return Err("Only callable via proposal execution".to_string());
+        }
+        self.lock_ledger_operations(self.env.canister_id())
+    }
+
+    /// Check that the treasury can send the amount, plus the ledger's fee, without touching the
+    /// tokens owed to the members
+    pub fn reserve_treasury_transfer(
+        &mut self,
+        amount: Tokens,
+        treasury_balance: Tokens,
+    ) -> Result<(), String> {
+        let liabilities = self.treasury_liabilities();
+        let total = amount.checked_add(self.ledger_fee());
+        let needed = liabilities
+            .zip(total)
+            .and_then(|(liabilities, total)| liabilities.checked_add(total));
+        if needed.map_or(true, |needed| needed > treasury_balance) {
+            return Err(format!(
+                "The treasury holds {:?}, of which {:?} is owed to the members, and can't spend {:?}",
+                treasury_balance, liabilities, total
+            ));
+        }
+        let total = total.unwrap_or_default();
+
+        self.ledger_operations.insert(self.env.canister_id(), total);
+        Ok(())
+    }
+
+    /// Return the tokens the treasury owes: the balances, the stakes, and what is being withdrawn.
+    /// None if they add up to more than a Tokens can hold, which no treasury can cover.
+    pub fn treasury_liabilities(&self) -> Option<Tokens> {
+        self.accounts
+            .values()
+            .chain(self.stakes.values().map(|s| &s.tokens))
+            .chain(self.ledger_operations.values())
+            .try_fold(Tokens::default(), |total, tokens| {
+                total.checked_add(*tokens)
+            })
+    }
+
+    fn ledger_fee(&self) -> Tokens {
+        self.ledger
+            .as_ref()
+            .map(|l| l.transaction_fee)
+            .unwrap_or_default()
+    }
+
+    /// Mark the accepted proposals that are due as executing, and return them
+    ///
+    /// The proposals with a call that is no longer allowed fail right away.
+    pub fn start_executions(&mut self) -> Vec<Proposal> {
+        let now = self.env.now();
+        let due: Vec<u64> = self
+            .proposals
+            .values()
+            .filter(|p| p.state == ProposalState::Accepted)
+            .filter(|p| {
+                p.execution
+                    .as_ref()
+                    .map_or(true, |e| e.executable_at <= now)
+            })
+            .map(|p| p.id)
+            .collect();
+
+        let mut executing = vec![];
+        for proposal_id in due {
+            let calls = self.proposals[&proposal_id].calls();
+            let callable: Result<(), String> = calls
+                .iter()
+                .try_for_each(|call| self.check_callable(&call.canister_id, &call.method));
+            let proposal = self.proposals.get_mut(&proposal_id).unwrap();
+            if let Err(msg) = callable {
+                proposal.state = ProposalState::Failed(msg);
+                continue;
+            }
+
+            let execution = proposal
+                .execution
+                .get_or_insert_with(|| Execution::new(now));
+            execution.attempts += 1;
+            // Nothing to execute for motions
+            if calls.is_empty() {
+                proposal.state = ProposalState::Succeeded;
+                continue;
+            }
+            proposal.state = ProposalState::Executing;
+            executing.push(proposal.clone());
+        }
+        executing
+    }
+
+    /// Return the next call of an executing proposal, if there is one left to make
+    pub fn next_call(&self, proposal_id: u64) -> Option<ProposalCall> {
+        let proposal = self.proposals.get(&proposal_id)?;
+        if proposal.state != ProposalState::Executing {
+            return None;
+        }
+        let made = proposal
+            .execution
+            .as_ref()
+            .and_then(|e| e.results.as_ref())
+            .map_or(0, |results| results.len());
+        proposal.calls().into_iter().nth(made)
+    }
+
+    /// Return the next call of an executing proposal, if the DAO holds the cycles it attaches
+    ///
+    /// Attaching more cycles than the DAO can spare would trap, so the proposal fails instead.
+    pub fn next_affordable_call(&mut self, proposal_id: u64) -> Option<ProposalCall> {
+        let call = self.next_call(proposal_id)?;
+        let cycles = call.cycles.unwrap_or(0);
+        let balance = self.env.cycles_balance();
+        if cycles > 0 && cycles.saturating_add(MIN_CYCLES_BALANCE) > balance {
+            let msg = format!(
+                "The DAO holds {} cycles and can't attach {} to the call",
+                balance, cycles
+            );
+            self.record_call(proposal_id, Err((RejectionCode::CanisterError, msg)));
+            return None;
+        }
+        Some(call)
+    }
+
+    /// Record the result of the next call of an executing proposal
+    ///
+    /// The proposal succeeds once all its calls did, and fails with the first call that fails.
+    /// Calls rejected with a transient error are retried after `retry_delay`, until the proposal
+    /// was executed `max_attempts` times, without making the calls that succeeded again.
+    pub fn record_call(&mut self, proposal_id: u64, result: CallResult<Vec<u8>>) {
+        let now = self.env.now();
+        let params = &self.execution_params;
+        let call = match self.next_call(proposal_id) {
+            Some(call) => call,
+            None => return,
+        };
+        let proposal = self.proposals.get_mut(&proposal_id).unwrap();
+        let call_count = proposal.calls().len();
+        // The methods of the DAO reply with their errors
+        let result = match result {
+            Ok(reply) if call.canister_id == self.env.canister_id() => {
+                match replied_error(&call.method, &reply) {
+                    Some(msg) => Err((RejectionCode::CanisterError, msg)),
+                    None => Ok(reply),
+                }
+            }
+            result => result,
+        };
+        let execution = proposal
+            .execution
+            .get_or_insert_with(|| Execution::new(now));
+        let results = execution.results.get_or_insert_with(Vec::new);
+
+        match result {
+            Ok(reply) => {
+                results.push(CallOutcome::Replied(reply.clone()));
+                execution.last_error = None;
+                if results.len() == call_count {
+                    execution.reply = Some(reply);
+                    proposal.state = ProposalState::Succeeded;
+                }
+            }
+            Err((code, msg)) => {
+                let msg = format!(
+                    "Proposal execution failed: \
+                        call: {}, canister: {}, method: {}, rejection code: {:?}, message: {}",
+                    results.len(),
+                    call.canister_id,
+                    &call.method,
+                    code,
+                    msg
+                );
+                execution.last_error = Some(msg.clone());
+                if code == RejectionCode::SysTransient && execution.attempts < params.max_attempts {
+                    execution.executable_at = now.saturating_add(params.retry_delay);
+                    proposal.state = ProposalState::Accepted;
+                } else {
+                    results.push(CallOutcome::Rejected(msg.clone()));
+                    proposal.state = ProposalState::Failed(msg);
+                }
+            }
+        }
+    }
+
+    /// Check that proposals may call the method of the canister
+    fn check_callable(&self, canister_id: &Principal, method: &str) -> Result<(), String> {
+        let allowed = *canister_id == self.env.canister_id()
+            || self
+                .execution_params
+                .allowlist
+                .iter()
+                .any(|target| target.canister_id == *canister_id && target.method == method);
+        if !allowed {
+            return Err(format!(
+                "Proposals may not call {} of canister {}",
+                method, canister_id
+            ));
+        }
+        Ok(())
     }
 
     /// Deduct the proposal submission deposit from the caller's account
     fn deduct_proposal_submission_deposit(&mut self) -> Result<(), String> {
         let caller = self.env.caller();
-        if let Some(account) = self.accounts.get_mut(&caller) {
+        if let Some(account) = self.accounts.get(&caller) {
             if account.clone() < self.system_params.proposal_submission_deposit {
                 return Err(format!(
                     "Caller's account must have at least {:?} to submit a proposal",
                     self.system_params.proposal_submission_deposit
                 ));
             } else {
-                *account -= self.system_params.proposal_submission_deposit.clone();
+                let deposit = self.system_params.proposal_submission_deposit.clone();
+                *self.account_mut(caller) -= deposit;
             }
         } else {
             return Err("Caller needs an account to submit a proposal".to_string());
@@ -225,3 +1257,1247 @@ impl BasicDaoService {
         Ok(())
     }
 }
+
+/// Return the error the DAO's method replied with to a proposal's call, if any
+fn replied_error(method: &str, reply: &[u8]) -> Option<String> {
+    match method {
+        "treasury_transfer" | "submit_proposal" | "submit_typed_proposal" => {
+            decode_one::<Result<u64, String>>(reply).ok()?.err()
+        }
+        "transfer"
+        | "delegate"
+        | "undelegate"
+        | "add_member"
+        | "remove_member"
+        | "update_execution_params"
+        | "update_ledger_params" => decode_one::<Result<(), String>>(reply).ok()?.err(),
+        _ => None,
+    }
+}
+
+/// Return the argument types of the DAO's methods that proposals call with args
+fn dao_arg_types(method: &str) -> Option<Vec<Type>> {
+    let arg_type = match method {
+        "update_system_params" => UpdateSystemParamsPayload::ty(),
+        "treasury_transfer" => TreasuryTransferArgs::ty(),
+        "add_member" => Account::ty(),
+        "remove_member" => Principal::ty(),
+        "update_execution_params" => ExecutionParams::ty(),
+        "update_ledger_params" => LedgerParams::ty(),
+        "transfer" => TransferArgs::ty(),
+        "delegate" => DelegateArgs::ty(),
+        "undelegate" => DelegationScope::ty(),
+        _ => return None,
+    };
+    Some(vec![arg_type])
+}
+
+/// The staked tokens, weighed from 1x for no dissolve delay to 2x for MAX_DISSOLVE_DELAY
+fn staked_voting_power(stake: &Stake, now: u64) -> Tokens {
+    let dissolve_delay = stake.dissolve_delay(now).min(MAX_DISSOLVE_DELAY) as u128;
+    let bonus = stake.tokens.amount_e8s as u128 * dissolve_delay / MAX_DISSOLVE_DELAY as u128;
+    Tokens {
+        amount_e8s: stake.tokens.amount_e8s + bonus as u64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::TestEnvironment;
+
+    fn alice() -> Principal {
+        Principal::from_slice(&[1])
+    }
+
+    fn bob() -> Principal {
+        Principal::from_slice(&[2])
+    }
+
+    fn carol() -> Principal {
+        Principal::from_slice(&[3])
+    }
+
+    fn tokens(amount_e8s: u64) -> Tokens {
+        Tokens { amount_e8s }
+    }
+
+    fn act_as(service: &mut BasicDaoService, caller: Principal, now: u64) {
+        service.env = Box::new(TestEnvironment {
+            now,
+            caller,
+            canister_id: Principal::from_slice(&[100]),
+            cycles_balance: 10 * MIN_CYCLES_BALANCE,
+        });
+    }
+
+    /// Alice holds 100 tokens, Bob none, and transfers are free
+    fn test_service() -> BasicDaoService {
+        BasicDaoService::from(BasicDaoStableStorage {
+            accounts: vec![Account {
+                owner: alice(),
+                tokens: tokens(100),
+            }],
+            proposals: vec![],
+            system_params: SystemParams {
+                transfer_fee: tokens(0),
+                proposal_vote_threshold: tokens(150),
+                proposal_submission_deposit: tokens(0),
+                proposal_voting_period: 0,
+                proposal_quorum: tokens(0),
+            },
+            ledger: None,
+        })
+    }
+
+    fn payload() -> ProposalPayload {
+        ProposalPayload {
+            canister_id: Principal::from_slice(&[100]),
+            method: "update_system_params".to_string(),
+            message: encode_one(UpdateSystemParamsPayload {
+                transfer_fee: None,
+                proposal_vote_threshold: None,
+                proposal_submission_deposit: None,
+                proposal_voting_period: None,
+                proposal_quorum: None,
+            })
+            .unwrap(),
+            cycles: None,
+            next_calls: None,
+        }
+    }
+
+    /// Alice keeps 50 tokens, and gives Bob 30 and Carol 20
+    fn share_tokens(service: &mut BasicDaoService) {
+        act_as(service, alice(), 1);
+        for (to, amount) in [(bob(), 30), (carol(), 20)] {
+            service
+                .transfer(TransferArgs {
+                    to,
+                    amount: tokens(amount),
+                })
+                .unwrap();
+        }
+    }
+
+    fn delegate(
+        service: &mut BasicDaoService,
+        delegator: Principal,
+        delegate: Principal,
+        scope: DelegationScope,
+    ) -> Result<(), String> {
+        act_as(service, delegator, 1);
+        service.delegate(DelegateArgs { delegate, scope })
+    }
+
+    fn vote(
+        service: &mut BasicDaoService,
+        proposal_id: u64,
+        vote: Vote,
+    ) -> Result<ProposalState, String> {
+        service.vote(VoteArgs { proposal_id, vote })
+    }
+
+    #[test]
+    fn proposals_settle_by_quorum_and_majority_when_the_voting_period_is_over() {
+        let mut service = test_service();
+        share_tokens(&mut service);
+        service.system_params.proposal_voting_period = 100;
+        service.system_params.proposal_quorum = tokens(40);
+        service.system_params.proposal_submission_deposit = tokens(5);
+        service.execution_params.timelock = 10;
+
+        act_as(&mut service, alice(), 1);
+        let accepted = service.submit_proposal(payload()).unwrap();
+        let rejected = service.submit_proposal(payload()).unwrap();
+        let expired = service.submit_proposal(payload()).unwrap();
+        assert_eq!(service.accounts[&alice()], tokens(35));
+        for (voter, votes) in [
+            (bob(), [Vote::Yes, Vote::No]),
+            (carol(), [Vote::No, Vote::Yes]),
+        ] {
+            act_as(&mut service, voter, 50);
+            vote(&mut service, accepted, votes[0].clone()).unwrap();
+            vote(&mut service, rejected, votes[1].clone()).unwrap();
+        }
+        // A majority without the quorum isn't enough
+        vote(&mut service, expired, Vote::Yes).unwrap();
+
+        act_as(&mut service, bob(), 100);
+        assert!(service.settle_expired_proposals().is_empty());
+        act_as(&mut service, bob(), 101);
+        let err = vote(&mut service, expired, Vote::Yes).unwrap_err();
+        assert!(err.contains("voting period"));
+
+        assert_eq!(
+            service.settle_expired_proposals(),
+            vec![accepted, rejected, expired]
+        );
+        let state = |service: &BasicDaoService, id| service.get_proposal(id).unwrap().state;
+        assert_eq!(state(&service, accepted), ProposalState::Accepted);
+        assert_eq!(state(&service, rejected), ProposalState::Rejected);
+        assert_eq!(state(&service, expired), ProposalState::Expired);
+        // Only the deposit of the accepted proposal is refunded
+        assert_eq!(service.accounts[&alice()], tokens(40));
+        let execution = service.get_proposal(accepted).unwrap().execution.unwrap();
+        assert_eq!(execution.executable_at, 111);
+        assert!(service.settle_expired_proposals().is_empty());
+    }
+
+    #[test]
+    fn proposals_keep_the_deadline_they_were_submitted_with() {
+        let mut service = test_service();
+        share_tokens(&mut service);
+        service.system_params.proposal_voting_period = 100;
+        act_as(&mut service, alice(), 1);
+        let short = service.submit_proposal(payload()).unwrap();
+        assert_eq!(service.get_proposal(short).unwrap().deadline, Some(101));
+
+        service.system_params.proposal_voting_period = 1000;
+        let long = service.submit_proposal(payload()).unwrap();
+        service.system_params.proposal_voting_period = 0;
+        let open = service.submit_proposal(payload()).unwrap();
+        assert_eq!(service.get_proposal(open).unwrap().deadline, None);
+
+        act_as(&mut service, bob(), 101);
+        assert!(vote(&mut service, short, Vote::Yes).is_err());
+        vote(&mut service, long, Vote::Yes).unwrap();
+        assert_eq!(service.settle_expired_proposals(), vec![short]);
+        act_as(&mut service, bob(), 1001);
+        assert_eq!(service.settle_expired_proposals(), vec![long]);
+        vote(&mut service, open, Vote::Yes).unwrap();
+
+        // Open proposals saved without a deadline get the one of the current period
+        let VersionedStableStorage::V1(mut stable) = VersionedStableStorage::from(&service);
+        stable.system_params.proposal_voting_period = 50;
+        for proposal in stable.proposals.iter_mut() {
+            proposal.deadline = None;
+        }
+        let restored = BasicDaoService::from(VersionedStableStorage::V1(stable));
+        assert_eq!(restored.get_proposal(open).unwrap().deadline, Some(51));
+        assert_eq!(restored.get_proposal(short).unwrap().deadline, None);
+    }
+
+    #[test]
+    fn voting_periods_and_timelocks_do_not_overflow() {
+        let mut service = test_service();
+        service.system_params.proposal_voting_period = u64::MAX;
+        service.system_params.proposal_vote_threshold = tokens(100);
+        service.execution_params.timelock = u64::MAX;
+
+        act_as(&mut service, alice(), 1);
+        let proposal_id = service.submit_proposal(payload()).unwrap();
+        act_as(&mut service, alice(), u64::MAX - 1);
+        assert!(service.settle_expired_proposals().is_empty());
+        assert_eq!(
+            vote(&mut service, proposal_id, Vote::Yes),
+            Ok(ProposalState::Accepted)
+        );
+        assert!(service.start_executions().is_empty());
+
+        // A transient error at the end of time is retried as late as possible
+        service.execution_params = ExecutionParams {
+            allowlist: vec![CallTarget {
+                canister_id: Principal::from_slice(&[101]),
+                method: "transfer".to_string(),
+            }],
+            timelock: 0,
+            max_attempts: 2,
+            retry_delay: u64::MAX,
+        };
+        let proposal_id = service.submit_proposal(payload()).unwrap();
+        assert_eq!(
+            vote(&mut service, proposal_id, Vote::Yes),
+            Ok(ProposalState::Accepted)
+        );
+        assert_eq!(service.start_executions().len(), 1);
+        service.record_call(
+            proposal_id,
+            Err((RejectionCode::SysTransient, "busy".to_string())),
+        );
+        let proposal = service.get_proposal(proposal_id).unwrap();
+        assert_eq!(proposal.state, ProposalState::Accepted);
+        assert_eq!(proposal.execution.unwrap().executable_at, u64::MAX);
+    }
+
+    #[test]
+    fn tokens_transferred_after_voting_cannot_vote_again() {
+        let mut service = test_service();
+        act_as(&mut service, alice(), 1);
+        let proposal_id = service.submit_proposal(payload()).unwrap();
+
+        assert_eq!(
+            vote(&mut service, proposal_id, Vote::Yes),
+            Ok(ProposalState::Open)
+        );
+        service
+            .transfer(TransferArgs {
+                to: bob(),
+                amount: tokens(100),
+            })
+            .unwrap();
+
+        act_as(&mut service, bob(), 2);
+        assert!(vote(&mut service, proposal_id, Vote::Yes).is_err());
+        let proposal = service.get_proposal(proposal_id).unwrap();
+        assert_eq!(proposal.votes_yes, tokens(100));
+        let voters: Vec<Principal> = service
+            .list_ballots(proposal_id)
+            .unwrap()
+            .iter()
+            .map(|b| b.voter)
+            .collect();
+        assert_eq!(voters, vec![alice()]);
+
+        // Bob's tokens count on the proposals submitted after the transfer
+        let next_proposal_id = service.submit_proposal(payload()).unwrap();
+        assert_eq!(
+            vote(&mut service, next_proposal_id, Vote::No),
+            Ok(ProposalState::Open)
+        );
+        assert_eq!(
+            service.get_proposal(next_proposal_id).unwrap().votes_no,
+            tokens(100)
+        );
+    }
+
+    #[test]
+    fn votes_are_weighed_by_the_balance_at_submission() {
+        let mut service = test_service();
+        act_as(&mut service, alice(), 1);
+        let proposal_id = service.submit_proposal(payload()).unwrap();
+        service
+            .transfer(TransferArgs {
+                to: bob(),
+                amount: tokens(60),
+            })
+            .unwrap();
+
+        // Alice still votes with the 100 tokens she held when the proposal was submitted
+        assert_eq!(
+            vote(&mut service, proposal_id, Vote::No),
+            Ok(ProposalState::Open)
+        );
+        assert_eq!(
+            service.get_proposal(proposal_id).unwrap().votes_no,
+            tokens(100)
+        );
+        assert!(service.balance_snapshots.contains_key(&proposal_id));
+
+        // The snapshot is dropped once the proposal is closed
+        service.system_params.proposal_vote_threshold = tokens(40);
+        let proposal_id = service.submit_proposal(payload()).unwrap();
+        assert_eq!(
+            vote(&mut service, proposal_id, Vote::Yes),
+            Ok(ProposalState::Accepted)
+        );
+        assert!(!service.balance_snapshots.contains_key(&proposal_id));
+    }
+
+    #[test]
+    fn snapshots_only_keep_the_voting_power_of_principals_that_changed() {
+        let mut service = test_service();
+        share_tokens(&mut service);
+        let proposal_id = service.submit_proposal(payload()).unwrap();
+        assert!(service.balance_snapshots[&proposal_id].is_empty());
+
+        act_as(&mut service, bob(), 2);
+        service
+            .stake(StakeArgs {
+                amount: tokens(30),
+                dissolve_delay: MAX_DISSOLVE_DELAY,
+            })
+            .unwrap();
+        assert_eq!(
+            service.balance_snapshots[&proposal_id],
+            HashMap::from([(bob(), tokens(30))])
+        );
+
+        // Bob votes with the 30 tokens he held, not his doubled stake, and Carol with hers
+        vote(&mut service, proposal_id, Vote::Yes).unwrap();
+        act_as(&mut service, carol(), 3);
+        vote(&mut service, proposal_id, Vote::No).unwrap();
+        let proposal = service.get_proposal(proposal_id).unwrap();
+        assert_eq!(proposal.votes_yes, tokens(30));
+        assert_eq!(proposal.votes_no, tokens(20));
+        assert_eq!(service.balance_snapshots[&proposal_id].len(), 1);
+    }
+
+    #[test]
+    fn init_does_not_reissue_proposal_ids() {
+        let mut service = test_service();
+        act_as(&mut service, alice(), 1);
+        service.submit_proposal(payload()).unwrap();
+        service.submit_proposal(payload()).unwrap();
+
+        let mut service = BasicDaoService::from(BasicDaoStableStorage {
+            accounts: service.list_accounts(),
+            proposals: service.proposals.values().cloned().collect(),
+            system_params: service.system_params.clone(),
+            ledger: None,
+        });
+        assert_eq!(service.next_proposal_id, 2);
+        act_as(&mut service, alice(), 2);
+        assert_eq!(service.submit_proposal(payload()), Ok(2));
+    }
+
+    #[test]
+    fn stable_storage_round_trip() {
+        let mut service = test_service();
+        act_as(&mut service, alice(), 1);
+        let first = service.submit_proposal(payload()).unwrap();
+        let second = service.submit_proposal(payload()).unwrap();
+        vote(&mut service, first, Vote::Yes).unwrap();
+        service
+            .transfer(TransferArgs {
+                to: bob(),
+                amount: tokens(30),
+            })
+            .unwrap();
+        service.proposals.get_mut(&second).unwrap().state =
+            ProposalState::Failed("error".to_string());
+
+        let stable = VersionedStableStorage::from(&service);
+        let mut restored = BasicDaoService::from(stable.clone());
+        assert_eq!(restored.accounts, service.accounts);
+        assert_eq!(restored.proposals, service.proposals);
+        assert_eq!(restored.next_proposal_id, 2);
+        assert_eq!(restored.system_params, service.system_params);
+        assert_eq!(restored.balance_snapshots, service.balance_snapshots);
+        let VersionedStableStorage::V1(saved) = VersionedStableStorage::from(&restored);
+        let VersionedStableStorage::V1(stable) = stable;
+        assert_eq!(saved, stable);
+
+        // The restored service keeps weighing votes by the snapshot
+        act_as(&mut restored, bob(), 2);
+        assert!(vote(&mut restored, first, Vote::No).is_err());
+        assert_eq!(restored.submit_proposal(payload()), Ok(2));
+    }
+
+    #[test]
+    fn delegated_votes_follow_the_chain_until_a_direct_vote() {
+        let mut service = test_service();
+        share_tokens(&mut service);
+        delegate(&mut service, alice(), bob(), DelegationScope::Global).unwrap();
+        delegate(&mut service, bob(), carol(), DelegationScope::Global).unwrap();
+        let proposal_id = service.submit_proposal(payload()).unwrap();
+
+        act_as(&mut service, carol(), 2);
+        vote(&mut service, proposal_id, Vote::Yes).unwrap();
+        let proposal = service.get_proposal(proposal_id).unwrap();
+        assert_eq!(proposal.votes_yes, tokens(100));
+        assert_eq!(
+            service.get_voting_power(proposal_id, alice()),
+            Ok(VotingPower {
+                own: tokens(50),
+                delegated: tokens(0),
+                delegated_to: Some(carol()),
+            })
+        );
+
+        // Voting in person overrides the delegation, for Alice too who delegated to Bob
+        act_as(&mut service, bob(), 3);
+        vote(&mut service, proposal_id, Vote::No).unwrap();
+        let proposal = service.get_proposal(proposal_id).unwrap();
+        assert_eq!(proposal.votes_yes, tokens(20));
+        assert_eq!(proposal.votes_no, tokens(80));
+
+        act_as(&mut service, alice(), 4);
+        vote(&mut service, proposal_id, Vote::Yes).unwrap();
+        let proposal = service.get_proposal(proposal_id).unwrap();
+        assert_eq!(proposal.votes_yes, tokens(70));
+        assert_eq!(proposal.votes_no, tokens(30));
+    }
+
+    #[test]
+    fn the_most_specific_delegation_applies_and_cycles_are_rejected() {
+        let mut service = test_service();
+        share_tokens(&mut service);
+        let dao = Principal::from_slice(&[100]);
+        let method_scope = DelegationScope::Method {
+            canister_id: dao,
+            method: "update_system_params".to_string(),
+        };
+
+        assert!(delegate(&mut service, alice(), alice(), DelegationScope::Global).is_err());
+        delegate(&mut service, alice(), bob(), DelegationScope::Global).unwrap();
+        assert!(delegate(&mut service, bob(), alice(), DelegationScope::Global).is_err());
+        // Alice's global delegation covers the method too
+        assert!(delegate(&mut service, bob(), alice(), method_scope.clone()).is_err());
+
+        delegate(
+            &mut service,
+            alice(),
+            carol(),
+            DelegationScope::Canister(dao),
+        )
+        .unwrap();
+        delegate(&mut service, bob(), alice(), method_scope.clone()).unwrap();
+        assert_eq!(service.list_delegations().len(), 3);
+
+        // On the DAO's method, Bob delegates to Alice, who delegates to Carol
+        let proposal_id = service.submit_proposal(payload()).unwrap();
+        assert_eq!(
+            service.get_voting_power(proposal_id, carol()),
+            Ok(VotingPower {
+                own: tokens(20),
+                delegated: tokens(80),
+                delegated_to: None,
+            })
+        );
+
+        // Elsewhere, only Alice's global delegation to Bob applies
+        service.execution_params.allowlist = vec![CallTarget {
+            canister_id: Principal::from_slice(&[101]),
+            method: "update_system_params".to_string(),
+        }];
+        let other_proposal_id = service
+            .submit_proposal(ProposalPayload {
+                canister_id: Principal::from_slice(&[101]),
+                ..payload()
+            })
+            .unwrap();
+        assert_eq!(
+            service.get_voting_power(other_proposal_id, bob()),
+            Ok(VotingPower {
+                own: tokens(30),
+                delegated: tokens(50),
+                delegated_to: None,
+            })
+        );
+
+        act_as(&mut service, alice(), 2);
+        service.undelegate(DelegationScope::Canister(dao)).unwrap();
+        assert!(service.undelegate(DelegationScope::Canister(dao)).is_err());
+        assert_eq!(
+            service
+                .get_voting_power(proposal_id, carol())
+                .unwrap()
+                .delegated,
+            tokens(0)
+        );
+    }
+
+    #[test]
+    fn staked_tokens_are_locked_and_vote_with_more_weight() {
+        let mut service = test_service();
+        act_as(&mut service, alice(), 1);
+        let stake = service
+            .stake(StakeArgs {
+                amount: tokens(60),
+                dissolve_delay: MAX_DISSOLVE_DELAY / 2,
+            })
+            .unwrap();
+        assert_eq!(stake.voting_power, tokens(90));
+        assert_eq!(service.account_balance(), tokens(40));
+        assert!(service
+            .transfer(TransferArgs {
+                to: bob(),
+                amount: tokens(60),
+            })
+            .is_err());
+        assert!(service
+            .stake(StakeArgs {
+                amount: tokens(10),
+                dissolve_delay: MAX_DISSOLVE_DELAY / 4,
+            })
+            .is_err());
+
+        let proposal_id = service.submit_proposal(payload()).unwrap();
+        vote(&mut service, proposal_id, Vote::Yes).unwrap();
+        assert_eq!(
+            service.get_proposal(proposal_id).unwrap().votes_yes,
+            tokens(130)
+        );
+    }
+
+    #[test]
+    fn stakes_dissolve_over_time() {
+        let mut service = test_service();
+        let delay = MAX_DISSOLVE_DELAY / 10;
+        act_as(&mut service, alice(), 1_000);
+        service
+            .stake(StakeArgs {
+                amount: tokens(100),
+                dissolve_delay: delay,
+            })
+            .unwrap();
+        assert!(service.unstake().is_err());
+        assert!(service.stop_dissolving().is_err());
+        service.start_dissolving().unwrap();
+
+        // Half way, the voting power has gone down with the delay left
+        act_as(&mut service, alice(), 1_000 + delay / 2);
+        let stake = service.get_stake().unwrap();
+        assert_eq!(stake.dissolve_delay, delay / 2);
+        assert_eq!(stake.voting_power, tokens(105));
+        assert!(service.unstake().is_err());
+
+        // Stopping keeps the delay left, until dissolving starts again
+        service.stop_dissolving().unwrap();
+        act_as(&mut service, alice(), 1_000 + delay);
+        assert_eq!(service.get_stake().unwrap().dissolve_delay, delay / 2);
+        service.start_dissolving().unwrap();
+
+        act_as(&mut service, alice(), 1_000 + delay + delay / 2);
+        assert_eq!(service.unstake(), Ok(tokens(100)));
+        assert_eq!(service.get_stake(), None);
+        assert_eq!(service.account_balance(), tokens(100));
+    }
+
+    #[test]
+    fn accepted_proposals_wait_for_the_timelock_and_retry_transient_errors() {
+        let mut service = test_service();
+        let dao = Principal::from_slice(&[100]);
+        let ledger = Principal::from_slice(&[101]);
+        act_as(&mut service, alice(), 1);
+        let other_call = ProposalPayload {
+            canister_id: ledger,
+            method: "transfer".to_string(),
+            ..payload()
+        };
+        assert!(service.submit_proposal(other_call.clone()).is_err());
+
+        // Only the DAO itself can change how proposals are executed
+        let params = ExecutionParams {
+            allowlist: vec![CallTarget {
+                canister_id: ledger,
+                method: "transfer".to_string(),
+            }],
+            timelock: 100,
+            max_attempts: 2,
+            retry_delay: 10,
+        };
+        assert!(service.update_execution_params(params.clone()).is_err());
+        act_as(&mut service, dao, 1);
+        service.update_execution_params(params).unwrap();
+
+        act_as(&mut service, alice(), 1);
+        service.system_params.proposal_vote_threshold = tokens(100);
+        let proposal_id = service.submit_proposal(other_call).unwrap();
+        assert_eq!(
+            vote(&mut service, proposal_id, Vote::Yes),
+            Ok(ProposalState::Accepted)
+        );
+
+        act_as(&mut service, alice(), 100);
+        assert!(service.start_executions().is_empty());
+        act_as(&mut service, alice(), 101);
+        assert_eq!(service.start_executions().len(), 1);
+        service.record_call(
+            proposal_id,
+            Err((RejectionCode::SysTransient, "busy".to_string())),
+        );
+        let proposal = service.get_proposal(proposal_id).unwrap();
+        assert_eq!(proposal.state, ProposalState::Accepted);
+        assert_eq!(proposal.execution.unwrap().executable_at, 111);
+
+        act_as(&mut service, alice(), 111);
+        assert_eq!(service.start_executions().len(), 1);
+        service.record_call(proposal_id, Ok(vec![1, 2, 3]));
+        let proposal = service.get_proposal(proposal_id).unwrap();
+        assert_eq!(proposal.state, ProposalState::Succeeded);
+        let execution = proposal.execution.unwrap();
+        assert_eq!(execution.attempts, 2);
+        assert_eq!(execution.reply, Some(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn executions_fail_on_permanent_errors_and_removed_targets() {
+        let mut service = test_service();
+        service.execution_params.max_attempts = 3;
+        service.execution_params.allowlist = vec![CallTarget {
+            canister_id: Principal::from_slice(&[101]),
+            method: "transfer".to_string(),
+        }];
+        service.system_params.proposal_vote_threshold = tokens(100);
+        act_as(&mut service, alice(), 1);
+        let first = service.submit_proposal(payload()).unwrap();
+        vote(&mut service, first, Vote::Yes).unwrap();
+        let second = service
+            .submit_proposal(ProposalPayload {
+                canister_id: Principal::from_slice(&[101]),
+                method: "transfer".to_string(),
+                ..payload()
+            })
+            .unwrap();
+        vote(&mut service, second, Vote::Yes).unwrap();
+
+        service.execution_params.allowlist.clear();
+        let executing = service.start_executions();
+        assert_eq!(executing.len(), 1);
+        assert_eq!(executing[0].id, first);
+        assert!(matches!(
+            service.get_proposal(second).unwrap().state,
+            ProposalState::Failed(_)
+        ));
+
+        service.record_call(
+            first,
+            Err((RejectionCode::CanisterReject, "invalid".to_string())),
+        );
+        assert!(matches!(
+            service.get_proposal(first).unwrap().state,
+            ProposalState::Failed(_)
+        ));
+    }
+
+    #[test]
+    fn calls_attaching_more_cycles_than_the_dao_can_spare_fail() {
+        let mut service = test_service();
+        service.system_params.proposal_vote_threshold = tokens(100);
+        let with_cycles = |cycles| ProposalPayload {
+            cycles: Some(cycles),
+            ..payload()
+        };
+
+        act_as(&mut service, alice(), 1);
+        assert!(service
+            .submit_proposal(with_cycles(MAX_CALL_CYCLES + 1))
+            .is_err());
+        let affordable = service
+            .submit_proposal(with_cycles(9 * MIN_CYCLES_BALANCE))
+            .unwrap();
+        vote(&mut service, affordable, Vote::Yes).unwrap();
+        let too_costly = service
+            .submit_proposal(with_cycles(10 * MIN_CYCLES_BALANCE))
+            .unwrap();
+        vote(&mut service, too_costly, Vote::Yes).unwrap();
+        assert_eq!(service.start_executions().len(), 2);
+
+        assert!(service.next_affordable_call(affordable).is_some());
+        assert_eq!(service.next_affordable_call(too_costly), None);
+        let proposal = service.get_proposal(too_costly).unwrap();
+        assert!(matches!(proposal.state, ProposalState::Failed(_)));
+        assert!(matches!(
+            proposal.execution.unwrap().results.unwrap()[..],
+            [CallOutcome::Rejected(_)]
+        ));
+    }
+
+    #[test]
+    fn calls_are_made_in_order_until_one_fails() {
+        let mut service = test_service();
+        let ledger = Principal::from_slice(&[101]);
+        service.execution_params.max_attempts = 2;
+        service.execution_params.allowlist = ["approve", "transfer"]
+            .iter()
+            .map(|method| CallTarget {
+                canister_id: ledger,
+                method: method.to_string(),
+            })
+            .collect();
+        service.system_params.proposal_vote_threshold = tokens(100);
+        let call = |method: &str| ProposalCall {
+            canister_id: ledger,
+            method: method.to_string(),
+            message: b"DIDL\x00\x00".to_vec(),
+            cycles: Some(1_000),
+        };
+        let multi_call = ProposalPayload {
+            next_calls: Some(vec![call("approve"), call("transfer")]),
+            ..payload()
+        };
+
+        act_as(&mut service, alice(), 1);
+        assert!(service
+            .submit_proposal(ProposalPayload {
+                next_calls: Some(vec![call("burn")]),
+                ..payload()
+            })
+            .is_err());
+        let succeeding = service.submit_proposal(multi_call.clone()).unwrap();
+        vote(&mut service, succeeding, Vote::Yes).unwrap();
+        let failing = service.submit_proposal(multi_call).unwrap();
+        vote(&mut service, failing, Vote::Yes).unwrap();
+        assert_eq!(service.start_executions().len(), 2);
+
+        for reply in 1..=3 {
+            let call = service.next_call(succeeding).unwrap();
+            assert_eq!(call.cycles.is_some(), reply > 1);
+            service.record_call(succeeding, Ok(vec![reply]));
+        }
+        assert_eq!(service.next_call(succeeding), None);
+        let proposal = service.get_proposal(succeeding).unwrap();
+        assert_eq!(proposal.state, ProposalState::Succeeded);
+        let execution = proposal.execution.unwrap();
+        assert_eq!(execution.reply, Some(vec![3]));
+        assert_eq!(
+            execution.results,
+            Some(vec![
+                CallOutcome::Replied(vec![1]),
+                CallOutcome::Replied(vec![2]),
+                CallOutcome::Replied(vec![3])
+            ])
+        );
+
+        // A retry resumes with the call that was rejected
+        service.record_call(failing, Ok(vec![1]));
+        service.record_call(
+            failing,
+            Err((RejectionCode::SysTransient, "busy".to_string())),
+        );
+        assert_eq!(service.next_call(failing), None);
+        assert_eq!(service.start_executions().len(), 1);
+        assert_eq!(service.next_call(failing).unwrap().method, "approve");
+        service.record_call(
+            failing,
+            Err((RejectionCode::CanisterReject, "denied".to_string())),
+        );
+        assert_eq!(service.next_call(failing), None);
+        let proposal = service.get_proposal(failing).unwrap();
+        assert!(matches!(proposal.state, ProposalState::Failed(_)));
+        let results = proposal.execution.unwrap().results.unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(matches!(results[1], CallOutcome::Rejected(_)));
+    }
+
+    #[test]
+    fn ballots_record_the_weight_and_can_change_until_the_proposal_closes() {
+        let mut service = test_service();
+        share_tokens(&mut service);
+        delegate(&mut service, carol(), bob(), DelegationScope::Global).unwrap();
+        let proposal_id = service.submit_proposal(payload()).unwrap();
+
+        act_as(&mut service, bob(), 2);
+        vote(&mut service, proposal_id, Vote::No).unwrap();
+        act_as(&mut service, alice(), 3);
+        vote(&mut service, proposal_id, Vote::No).unwrap();
+        act_as(&mut service, bob(), 4);
+        vote(&mut service, proposal_id, Vote::Yes).unwrap();
+        let proposal = service.get_proposal(proposal_id).unwrap();
+        assert_eq!(proposal.votes_yes, tokens(50));
+        assert_eq!(proposal.votes_no, tokens(50));
+
+        assert!(service.list_ballots(proposal_id + 1).is_err());
+        assert_eq!(
+            service.list_ballots(proposal_id),
+            Ok(vec![
+                Ballot {
+                    voter: alice(),
+                    vote: Vote::No,
+                    weight: tokens(50),
+                    timestamp: 3,
+                },
+                Ballot {
+                    voter: bob(),
+                    vote: Vote::Yes,
+                    weight: tokens(50),
+                    timestamp: 4,
+                },
+            ])
+        );
+
+        // Carol votes in person, Bob's ballot keeps the weight he voted with until the proposal
+        // closes
+        act_as(&mut service, carol(), 5);
+        vote(&mut service, proposal_id, Vote::No).unwrap();
+        assert_eq!(
+            service.list_ballots(proposal_id).unwrap()[1].weight,
+            tokens(50)
+        );
+
+        // Once closed, the ballots stay with the weight they were counted with, but can't change
+        service.system_params.proposal_vote_threshold = tokens(50);
+        act_as(&mut service, alice(), 6);
+        assert_eq!(
+            vote(&mut service, proposal_id, Vote::Yes),
+            Ok(ProposalState::Accepted)
+        );
+        assert!(vote(&mut service, proposal_id, Vote::No).is_err());
+        let weights: Vec<Tokens> = service
+            .list_ballots(proposal_id)
+            .unwrap()
+            .into_iter()
+            .map(|b| b.weight)
+            .collect();
+        assert_eq!(weights, vec![tokens(30), tokens(20), tokens(50)]);
+        assert_eq!(service.my_votes()[0].ballot.timestamp, 6);
+    }
+
+    #[test]
+    fn proposals_are_listed_by_descending_id_in_pages() {
+        let mut service = test_service();
+        share_tokens(&mut service);
+        for (proposer, now) in [(alice(), 10), (bob(), 20), (alice(), 30), (bob(), 40)] {
+            act_as(&mut service, proposer, now);
+            service.submit_proposal(payload()).unwrap();
+        }
+        act_as(&mut service, alice(), 50);
+        vote(&mut service, 2, Vote::No).unwrap();
+        act_as(&mut service, bob(), 50);
+        vote(&mut service, 1, Vote::Yes).unwrap();
+        service.proposals.get_mut(&1).unwrap().state = ProposalState::Failed("error".to_string());
+
+        let ids = |page: &ProposalPage| page.proposals.iter().map(|p| p.id).collect::<Vec<u64>>();
+        let page = service.list_proposals(ProposalFilter::default(), None, 3);
+        assert_eq!(ids(&page), vec![3, 2, 1]);
+        assert_eq!(page.next_cursor, Some(1));
+        let page = service.list_proposals(ProposalFilter::default(), page.next_cursor, 3);
+        assert_eq!(ids(&page), vec![0]);
+        assert_eq!(page.next_cursor, None);
+        // A limit of 0 returns one proposal
+        let page = service.list_proposals(ProposalFilter::default(), None, 0);
+        assert_eq!(ids(&page), vec![3]);
+        assert_eq!(page.next_cursor, Some(3));
+
+        let by_alice = ProposalFilter {
+            proposer: Some(alice()),
+            ..Default::default()
+        };
+        assert_eq!(ids(&service.list_proposals(by_alice, None, 10)), vec![2, 0]);
+        let submitted = ProposalFilter {
+            submitted_from: Some(20),
+            submitted_before: Some(40),
+            ..Default::default()
+        };
+        assert_eq!(
+            ids(&service.list_proposals(submitted, None, 10)),
+            vec![2, 1]
+        );
+        // The message of a failure doesn't matter
+        let failed = ProposalFilter {
+            state: Some(ProposalState::Failed(String::new())),
+            ..Default::default()
+        };
+        assert_eq!(ids(&service.list_proposals(failed, None, 10)), vec![1]);
+
+        let votes = service.my_votes();
+        assert_eq!(votes.len(), 1);
+        assert_eq!(votes[0].proposal_id, 1);
+        assert_eq!(votes[0].ballot.vote, Vote::Yes);
+    }
+
+    #[test]
+    fn selective_filters_stop_scanning_and_return_a_cursor() {
+        let mut service = test_service();
+        act_as(&mut service, alice(), 1);
+        for _ in 0..MAX_PROPOSALS_SCANNED + 10 {
+            service.submit_proposal(payload()).unwrap();
+        }
+        let by_bob = ProposalFilter {
+            proposer: Some(bob()),
+            ..Default::default()
+        };
+
+        let page = service.list_proposals(by_bob.clone(), None, 10);
+        assert!(page.proposals.is_empty());
+        assert_eq!(page.next_cursor, Some(10));
+        let page = service.list_proposals(by_bob, page.next_cursor, 10);
+        assert!(page.proposals.is_empty());
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[test]
+    fn arbitrary_calls_must_be_valid_candid() {
+        let mut service = test_service();
+        act_as(&mut service, alice(), 1);
+        assert!(service
+            .submit_proposal(ProposalPayload {
+                message: vec![1, 2, 3],
+                ..payload()
+            })
+            .is_err());
+
+        // The methods of the DAO are decoded with their argument types
+        assert!(service
+            .submit_proposal(ProposalPayload {
+                message: b"DIDL\x00\x00".to_vec(),
+                ..payload()
+            })
+            .is_err());
+        let proposal_id = service.submit_proposal(payload()).unwrap();
+        let proposal = service.get_proposal(proposal_id).unwrap();
+        assert_eq!(proposal.kind, None);
+        assert_eq!(proposal.decoded_calls.unwrap().len(), 1);
+
+        let ledger = Principal::from_slice(&[101]);
+        service.execution_params.allowlist = vec![CallTarget {
+            canister_id: ledger,
+            method: "transfer".to_string(),
+        }];
+        let proposal_id = service
+            .submit_proposal(ProposalPayload {
+                canister_id: ledger,
+                method: "transfer".to_string(),
+                message: b"DIDL\x00\x00".to_vec(),
+                ..payload()
+            })
+            .unwrap();
+        let proposal = service.get_proposal(proposal_id).unwrap();
+        assert_eq!(proposal.decoded_calls, Some(vec!["()".to_string()]));
+    }
+
+    #[test]
+    fn calls_to_the_dao_fail_when_its_method_replies_with_an_error() {
+        let mut service = test_service();
+        service.system_params.proposal_vote_threshold = tokens(100);
+        act_as(&mut service, alice(), 1);
+        let proposal_id = service
+            .submit_proposal(ProposalPayload {
+                method: "add_member".to_string(),
+                message: encode_one(Account {
+                    owner: alice(),
+                    tokens: tokens(10),
+                })
+                .unwrap(),
+                ..payload()
+            })
+            .unwrap();
+        vote(&mut service, proposal_id, Vote::Yes).unwrap();
+        assert_eq!(service.start_executions().len(), 1);
+
+        let reply = encode_one(Err::<(), String>("already a member".to_string())).unwrap();
+        service.record_call(proposal_id, Ok(reply));
+        let proposal = service.get_proposal(proposal_id).unwrap();
+        assert!(
+            matches!(proposal.state, ProposalState::Failed(msg) if msg.contains("already a member"))
+        );
+    }
+
+    #[test]
+    fn built_in_proposals_call_the_dao_and_motions_make_no_call() {
+        let mut service = test_service();
+        let dao = Principal::from_slice(&[100]);
+        service.system_params.proposal_vote_threshold = tokens(100);
+        act_as(&mut service, alice(), 1);
+        let new_member = Account {
+            owner: bob(),
+            tokens: tokens(40),
+        };
+        let add_member = service
+            .submit_typed_proposal(ProposalKind::AddMember(new_member.clone()))
+            .unwrap();
+        let motion = service
+            .submit_typed_proposal(ProposalKind::Motion("Be excellent".to_string()))
+            .unwrap();
+        for proposal_id in [add_member, motion] {
+            vote(&mut service, proposal_id, Vote::Yes).unwrap();
+        }
+
+        let executing = service.start_executions();
+        assert_eq!(executing.len(), 1);
+        assert_eq!(executing[0].payload.canister_id, dao);
+        assert_eq!(executing[0].payload.method, "add_member");
+        assert_eq!(
+            service.get_proposal(motion).unwrap().state,
+            ProposalState::Succeeded
+        );
+
+        // What the heartbeat does for the call
+        assert!(service.add_member(new_member.clone()).is_err());
+        act_as(&mut service, dao, 2);
+        service.add_member(new_member.clone()).unwrap();
+        assert!(service.add_member(new_member).is_err());
+        assert_eq!(service.accounts[&bob()], tokens(40));
+        service.remove_member(bob()).unwrap();
+        assert!(service.remove_member(bob()).is_err());
+    }
+
+    #[test]
+    fn removed_members_leave_no_delegations_or_ledger_operations_behind() {
+        let mut service = ledger_service();
+        let dao = Principal::from_slice(&[100]);
+        service.accounts.insert(bob(), tokens(40));
+        service.accounts.insert(carol(), tokens(10));
+        delegate(&mut service, alice(), bob(), DelegationScope::Global).unwrap();
+        delegate(&mut service, bob(), carol(), DelegationScope::Global).unwrap();
+
+        act_as(&mut service, dao, 2);
+        // Members backed by a ledger deposit their tokens
+        assert!(service
+            .add_member(Account {
+                owner: Principal::from_slice(&[4]),
+                tokens: tokens(40),
+            })
+            .is_err());
+        service
+            .add_member(Account {
+                owner: Principal::from_slice(&[4]),
+                tokens: tokens(0),
+            })
+            .unwrap();
+
+        service.lock_ledger_operations(bob()).unwrap();
+        assert!(service.remove_member(bob()).is_err());
+        service.unlock_ledger_operations(bob());
+        service.remove_member(bob()).unwrap();
+        assert!(service.list_delegations().is_empty());
+        assert!(!service.accounts.contains_key(&bob()));
+    }
+
+    #[test]
+    fn transfers_cannot_overdraw_the_account_with_the_fee() {
+        let mut service = test_service();
+        service.system_params.transfer_fee = tokens(1);
+        act_as(&mut service, alice(), 1);
+        assert!(service
+            .transfer(TransferArgs {
+                to: bob(),
+                amount: tokens(100),
+            })
+            .is_err());
+        assert_eq!(service.account_balance(), tokens(100));
+
+        service
+            .transfer(TransferArgs {
+                to: bob(),
+                amount: tokens(99),
+            })
+            .unwrap();
+        assert_eq!(service.account_balance(), tokens(0));
+        assert_eq!(service.accounts[&bob()], tokens(99));
+    }
+
+    #[test]
+    fn amounts_near_u64_max_do_not_wrap() {
+        let mut service = ledger_service();
+        service.system_params.transfer_fee = tokens(1);
+        act_as(&mut service, alice(), 1);
+        assert!(service
+            .transfer(TransferArgs {
+                to: bob(),
+                amount: tokens(u64::MAX),
+            })
+            .is_err());
+        assert_eq!(service.account_balance(), tokens(100));
+
+        // The recipient's balance can't wrap around either
+        service.accounts.insert(bob(), tokens(u64::MAX - 10));
+        assert!(service
+            .transfer(TransferArgs {
+                to: bob(),
+                amount: tokens(50),
+            })
+            .is_err());
+        assert_eq!(service.account_balance(), tokens(100));
+        assert_eq!(service.treasury_liabilities(), None);
+
+        service.lock_ledger_operations(bob()).unwrap();
+        assert!(service
+            .debit_withdrawal(bob(), tokens(u64::MAX), tokens(u64::MAX))
+            .is_err());
+        service.unlock_ledger_operations(bob());
+        assert_eq!(service.accounts[&bob()], tokens(u64::MAX - 10));
+
+        service.accounts.remove(&bob());
+        let dao = Principal::from_slice(&[100]);
+        act_as(&mut service, dao, 2);
+        service.lock_treasury_transfer().unwrap();
+        assert!(service
+            .reserve_treasury_transfer(tokens(u64::MAX - 5), tokens(u64::MAX))
+            .is_err());
+    }
+
+    fn ledger_service() -> BasicDaoService {
+        let mut service = test_service();
+        service.ledger = Some(LedgerParams {
+            ledger_canister_id: Principal::from_slice(&[102]),
+            transaction_fee: tokens(10),
+        });
+        service
+    }
+
+    #[test]
+    fn withdrawals_are_owed_until_the_ledger_transfer_is_done() {
+        let mut service = test_service();
+        assert!(service.lock_ledger_operations(alice()).is_err());
+
+        let mut service = ledger_service();
+        service.lock_ledger_operations(bob()).unwrap();
+        assert!(service.lock_ledger_operations(bob()).is_err());
+        service.credit_deposit(bob(), tokens(40));
+        service.unlock_ledger_operations(bob());
+        assert_eq!(service.treasury_liabilities(), Some(tokens(140)));
+
+        // The ledger's fee comes on top of the amount withdrawn
+        service.lock_ledger_operations(bob()).unwrap();
+        assert!(service
+            .debit_withdrawal(bob(), tokens(35), tokens(140))
+            .is_err());
+        service
+            .debit_withdrawal(bob(), tokens(30), tokens(140))
+            .unwrap();
+        assert_eq!(service.accounts[&bob()], tokens(0));
+        assert_eq!(service.treasury_liabilities(), Some(tokens(140)));
+
+        // The ledger rejected the transfer
+        service.refund_withdrawal(bob(), tokens(30));
+        service.unlock_ledger_operations(bob());
+        assert_eq!(service.accounts[&bob()], tokens(40));
+        assert_eq!(service.treasury_liabilities(), Some(tokens(140)));
+    }
+
+    #[test]
+    fn only_members_can_deposit() {
+        let mut service = ledger_service();
+        assert!(service.lock_deposit(bob()).is_err());
+        service.lock_deposit(alice()).unwrap();
+        service.unlock_ledger_operations(alice());
+
+        let dao = Principal::from_slice(&[100]);
+        act_as(&mut service, dao, 1);
+        service
+            .add_member(Account {
+                owner: bob(),
+                tokens: tokens(0),
+            })
+            .unwrap();
+        service.lock_deposit(bob()).unwrap();
+        service.credit_deposit(bob(), tokens(40));
+        service.unlock_ledger_operations(bob());
+
+        service.remove_member(bob()).unwrap();
+        assert!(service.lock_deposit(bob()).is_err());
+    }
+
+    #[test]
+    fn withdrawals_are_suspended_while_the_treasury_is_short() {
+        let mut service = ledger_service();
+        // Alice's 100 tokens from `init` were never deposited
+        service.lock_ledger_operations(bob()).unwrap();
+        service.credit_deposit(bob(), tokens(40));
+        service.unlock_ledger_operations(bob());
+
+        service.lock_ledger_operations(bob()).unwrap();
+        assert!(service
+            .debit_withdrawal(bob(), tokens(30), tokens(40))
+            .is_err());
+        assert_eq!(service.accounts[&bob()], tokens(40));
+        service.unlock_ledger_operations(bob());
+
+        // Once the treasury holds what it owes, the members can withdraw again
+        service.lock_ledger_operations(bob()).unwrap();
+        service
+            .debit_withdrawal(bob(), tokens(30), tokens(140))
+            .unwrap();
+        assert_eq!(service.accounts[&bob()], tokens(0));
+    }
+
+    #[test]
+    fn treasury_transfers_only_spend_what_the_dao_owns() {
+        let mut service = ledger_service();
+        let dao = Principal::from_slice(&[100]);
+        act_as(&mut service, alice(), 1);
+        service
+            .stake(StakeArgs {
+                amount: tokens(50),
+                dissolve_delay: 0,
+            })
+            .unwrap();
+        assert!(service.lock_treasury_transfer().is_err());
+
+        act_as(&mut service, dao, 2);
+        service.lock_treasury_transfer().unwrap();
+        // Alice's balance and stake are owed, and the ledger takes a fee
+        assert!(service
+            .reserve_treasury_transfer(tokens(100), tokens(209))
+            .is_err());
+        service
+            .reserve_treasury_transfer(tokens(100), tokens(210))
+            .unwrap();
+        assert_eq!(service.treasury_liabilities(), Some(tokens(210)));
+        service.unlock_ledger_operations(dao);
+        assert_eq!(service.treasury_liabilities(), Some(tokens(100)));
+    }
+}
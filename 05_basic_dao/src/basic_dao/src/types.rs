@@ -2,6 +2,7 @@ use ic_cdk::export::{
     candid::{CandidType, Deserialize},
     Principal,
 };
+use ic_ledger_types::Subaccount;
 use std::ops::{Add, AddAssign, SubAssign, Mul};
 
 #[derive(Clone, Debug, Default, CandidType, Deserialize)]
@@ -9,6 +10,56 @@ pub struct BasicDaoStableStorage {
     pub accounts: Vec<Account>,
     pub proposals: Vec<Proposal>,
     pub system_params: SystemParams,
+    pub ledger: Option<LedgerParams>,
+}
+
+/// The state saved to stable memory across upgrades
+///
+/// Fields added later must be optional, so that the state saved by an older version still decodes.
+/// Changes that break decoding get a new variant, converted from the previous one on restore.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum VersionedStableStorage {
+    V1(StableStorageV1),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub struct StableStorageV1 {
+    pub accounts: Vec<Account>,
+    pub proposals: Vec<Proposal>,
+    pub system_params: SystemParams,
+    pub next_proposal_id: u64,
+    pub balance_snapshots: Vec<BalanceSnapshot>,
+    pub delegations: Option<Vec<Delegation>>,
+    pub stakes: Option<Vec<Stake>>,
+    pub execution_params: Option<ExecutionParams>,
+    pub ledger: Option<LedgerParams>,
+    pub ballots: Option<Vec<ProposalBallots>>,
+}
+
+/// The balances the votes on an open proposal are weighed by
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub struct BalanceSnapshot {
+    pub proposal_id: u64,
+    pub accounts: Vec<Account>,
+}
+
+/// The ballots cast on a proposal
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub struct ProposalBallots {
+    pub proposal_id: u64,
+    pub ballots: Vec<Ballot>,
+}
+
+/// A vote cast in person, the last one counts
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub struct Ballot {
+    pub voter: Principal,
+    pub vote: Vote,
+
+    // The voting power of the voter, its own and the one delegated to it: when it voted while the
+    // proposal is open, and as counted in the final tally once it is closed
+    pub weight: Tokens,
+    pub timestamp: u64,
 }
 
 #[derive(Clone, Copy, Debug, Default, CandidType, Deserialize, PartialEq, PartialOrd)]
@@ -16,30 +67,45 @@ pub struct Tokens {
     pub amount_e8s: u64,
 }
 
+impl Tokens {
+    /// Returns None instead of wrapping around when the sum doesn't fit
+    pub fn checked_add(self, other: Self) -> Option<Self> {
+        self.amount_e8s.checked_add(other.amount_e8s).map(|amount_e8s| Tokens { amount_e8s })
+    }
+
+    /// Returns None instead of wrapping around when `other` is larger
+    pub fn checked_sub(self, other: Self) -> Option<Self> {
+        self.amount_e8s.checked_sub(other.amount_e8s).map(|amount_e8s| Tokens { amount_e8s })
+    }
+}
+
+// Release builds don't check for overflow, so the operators trap explicitly rather than wrap
+// around. Amounts that come from callers go through checked_add and checked_sub instead.
 impl Add for Tokens {
     type Output = Self;
 
     fn add(self, other: Self) -> Self {
-        Tokens { amount_e8s: self.amount_e8s + other.amount_e8s }
+        self.checked_add(other).expect("Tokens overflow")
     }
 }
 
 impl AddAssign for Tokens {
     fn add_assign(&mut self, other: Self) {
-        self.amount_e8s += other.amount_e8s;
+        *self = *self + other;
     }
 }
 
 impl SubAssign for Tokens {
     fn sub_assign(&mut self, other: Self) {
-        self.amount_e8s -= other.amount_e8s;
+        *self = self.checked_sub(other).expect("Tokens underflow");
     }
 }
 
 impl Mul<u64> for Tokens {
     type Output = Tokens;
     fn mul(self, rhs: u64) -> Self {
-        Tokens { amount_e8s: self.amount_e8s * rhs }
+        let amount_e8s = self.amount_e8s.checked_mul(rhs).expect("Tokens overflow");
+        Tokens { amount_e8s }
     }
 }
 
@@ -63,6 +129,9 @@ pub enum ProposalState {
 
     // A failure occurred while executing the proposal
     Failed(String),
+
+    // The voting period ended before the quorum was reached, and it will not be executed
+    Expired,
 }
 
 /// A proposal is a proposition to execute an arbitrary canister call
@@ -70,7 +139,7 @@ pub enum ProposalState {
 /// Token holders can vote to either accept the proposal and execute the given
 /// canister call, or vote to reject the proposal and not execute the canister
 /// call.
-#[derive(Clone, Debug, CandidType, Deserialize)]
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
 pub struct Proposal {
     pub id: u64,
     pub timestamp: u64,
@@ -79,24 +148,179 @@ pub struct Proposal {
     pub state: ProposalState,
     pub votes_yes: Tokens,
     pub votes_no: Tokens,
-    pub voters: Vec<Principal>,
+    pub execution: Option<Execution>,
+
+    // The built-in kind of the proposal, whose call the payload holds. None for arbitrary calls.
+    pub kind: Option<ProposalKind>,
+
+    // The messages of the arbitrary calls, decoded from Candid
+    pub decoded_calls: Option<Vec<String>>,
+
+    // The end of the voting period, from the `proposal_voting_period` when it was submitted. None
+    // keeps the proposal open until the threshold is reached.
+    pub deadline: Option<u64>,
+}
+
+impl Proposal {
+    /// Return the calls made once the proposal is accepted, motions make none
+    pub fn calls(&self) -> Vec<ProposalCall> {
+        match self.kind {
+            Some(ProposalKind::Motion(_)) => vec![],
+            _ => self.payload.calls(),
+        }
+    }
+}
+
+/// The proposals the DAO knows how to make, besides arbitrary calls
+///
+/// Except for motions, these call the method of the DAO of the same name with the given args.
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub enum ProposalKind {
+    UpdateSystemParams(UpdateSystemParamsPayload),
+    TreasuryTransfer(TreasuryTransferArgs),
+
+    // Open an account holding the given tokens for a new member
+    AddMember(Account),
+
+    // Close the account of a member, and its stake. Its tokens go to the DAO.
+    RemoveMember(Principal),
+
+    // A text to agree on, which makes no call when accepted
+    Motion(String),
+}
+
+/// How the execution of an accepted proposal is going
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub struct Execution {
+    // The proposal is executed from this time on, after the timelock or before a retry
+    pub executable_at: u64,
+
+    // The number of times the call was made
+    pub attempts: u32,
+
+    // Why the last attempt failed, if it did
+    pub last_error: Option<String>,
+
+    // The raw reply of the last call, once all of them succeeded
+    pub reply: Option<Vec<u8>>,
+
+    // The outcome of each call made so far, a failed call stops the ones after it
+    pub results: Option<Vec<CallOutcome>>,
+}
+
+impl Execution {
+    pub fn new(executable_at: u64) -> Self {
+        Execution {
+            executable_at,
+            attempts: 0,
+            last_error: None,
+            reply: None,
+            results: Some(vec![]),
+        }
+    }
 }
 
 /// The data needed to call a given method on a given canister with given args
-#[derive(Clone, Debug, CandidType, Deserialize)]
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
 pub struct ProposalPayload {
     pub canister_id: Principal,
     pub method: String,
     pub message: Vec<u8>,
+
+    // The cycles to attach to the call, none by default
+    pub cycles: Option<u64>,
+
+    // The calls to make after this one, in order, as long as the previous ones succeed
+    pub next_calls: Option<Vec<ProposalCall>>,
+}
+
+impl ProposalPayload {
+    /// Return all the calls of the proposal, in the order they are made
+    pub fn calls(&self) -> Vec<ProposalCall> {
+        let mut calls = vec![ProposalCall {
+            canister_id: self.canister_id,
+            method: self.method.clone(),
+            message: self.message.clone(),
+            cycles: self.cycles,
+        }];
+        calls.extend(self.next_calls.iter().flatten().cloned());
+        calls
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub struct ProposalCall {
+    pub canister_id: Principal,
+    pub method: String,
+    pub message: Vec<u8>,
+    pub cycles: Option<u64>,
+}
+
+/// The result of one of the calls of a proposal
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub enum CallOutcome {
+    // The raw reply of the call
+    Replied(Vec<u8>),
+
+    // Why the call failed
+    Rejected(String),
+}
+
+/// Which proposals `list_proposals` returns, the ones matching all the given criteria
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct ProposalFilter {
+    // Failed proposals match whatever their error
+    pub state: Option<ProposalState>,
+    pub proposer: Option<Principal>,
+
+    // The proposals submitted from this time on
+    pub submitted_from: Option<u64>,
+
+    // The proposals submitted before this time
+    pub submitted_before: Option<u64>,
+}
+
+impl ProposalFilter {
+    pub fn matches(&self, proposal: &Proposal) -> bool {
+        if let Some(state) = &self.state {
+            if std::mem::discriminant(state) != std::mem::discriminant(&proposal.state) {
+                return false;
+            }
+        }
+        if let Some(proposer) = self.proposer {
+            if proposer != proposal.proposer {
+                return false;
+            }
+        }
+        if let Some(from) = self.submitted_from {
+            if proposal.timestamp < from {
+                return false;
+            }
+        }
+        if let Some(before) = self.submitted_before {
+            if proposal.timestamp >= before {
+                return false;
+            }
+        }
+        true
+    }
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ProposalPage {
+    pub proposals: Vec<Proposal>,
+
+    // The cursor to pass to `list_proposals` for the next page, none on the last one
+    pub next_cursor: Option<u64>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
 pub enum Vote {
     Yes,
     No,
 }
 
-#[derive(Clone, Debug, CandidType, Deserialize)]
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
 pub struct Account {
     pub owner: Principal,
     pub tokens: Tokens,
@@ -114,7 +338,165 @@ pub struct VoteArgs {
     pub vote: Vote,
 }
 
-#[derive(Clone, Default, Debug, CandidType, Deserialize)]
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub struct CastVote {
+    pub proposal_id: u64,
+    pub ballot: Ballot,
+}
+
+/// A method of a canister that proposals may call
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub struct CallTarget {
+    pub canister_id: Principal,
+    pub method: String,
+}
+
+/// How accepted proposals are executed
+///
+/// Like the system params, these can only be updated via proposal execution
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub struct ExecutionParams {
+    // The calls proposals may make, besides the methods of the DAO itself
+    pub allowlist: Vec<CallTarget>,
+
+    // The nanoseconds between a proposal being accepted and executed, leaving time to react to it
+    pub timelock: u64,
+
+    // How many times a call rejected with a transient error is made before the proposal fails
+    pub max_attempts: u32,
+
+    // The nanoseconds to wait before retrying a call
+    pub retry_delay: u64,
+}
+
+impl Default for ExecutionParams {
+    fn default() -> Self {
+        ExecutionParams {
+            allowlist: vec![],
+            timelock: 0,
+            max_attempts: 1,
+            retry_delay: 0,
+        }
+    }
+}
+
+/// The ledger backing the DAO's tokens, one token for one e8 of the ledger
+///
+/// The ledger account of the canister holds the treasury: the tokens deposited by the members,
+/// plus what the DAO owns itself.
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub struct LedgerParams {
+    pub ledger_canister_id: Principal,
+
+    // The fee the ledger charges for a transfer
+    pub transaction_fee: Tokens,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct WithdrawArgs {
+    pub amount: Tokens,
+    // The subaccount of the caller's ledger account the tokens are sent to, the default one if none
+    pub to_subaccount: Option<Subaccount>,
+}
+
+/// Send tokens the DAO owns from the treasury, see `LedgerParams`
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub struct TreasuryTransferArgs {
+    pub to: Principal,
+    pub to_subaccount: Option<Subaccount>,
+    pub amount: Tokens,
+}
+
+/// The proposals a delegation applies to
+///
+/// When several delegations of a principal apply to a proposal, the most specific one is used
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq, Eq, Hash)]
+pub enum DelegationScope {
+    // Every proposal
+    Global,
+
+    // Proposals calling any method of the canister
+    Canister(Principal),
+
+    // Proposals calling the method of the canister
+    Method {
+        canister_id: Principal,
+        method: String,
+    },
+}
+
+/// The delegator's votes are cast by the delegate on the proposals in scope, unless the delegator
+/// votes in person
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub struct Delegation {
+    pub delegator: Principal,
+    pub delegate: Principal,
+    pub scope: DelegationScope,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct DelegateArgs {
+    pub delegate: Principal,
+    pub scope: DelegationScope,
+}
+
+/// Whether staked tokens are still locked, or on their way to being unstaked
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub enum DissolveState {
+    // The tokens can't be unstaked until dissolve_delay nanoseconds after the stake starts dissolving
+    Locked { dissolve_delay: u64 },
+
+    // The tokens can be unstaked from dissolve_at on
+    Dissolving { dissolve_at: u64 },
+}
+
+/// Tokens locked away from an account, which vote with more weight the longer they stay locked
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub struct Stake {
+    pub owner: Principal,
+    pub tokens: Tokens,
+    pub state: DissolveState,
+}
+
+impl Stake {
+    /// Return how many nanoseconds are left until the tokens can be unstaked
+    pub fn dissolve_delay(&self, now: u64) -> u64 {
+        match self.state {
+            DissolveState::Locked { dissolve_delay } => dissolve_delay,
+            DissolveState::Dissolving { dissolve_at } => dissolve_at.saturating_sub(now),
+        }
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StakeArgs {
+    pub amount: Tokens,
+    pub dissolve_delay: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub struct StakeInfo {
+    pub tokens: Tokens,
+    pub state: DissolveState,
+    pub dissolve_delay: u64,
+    pub voting_power: Tokens,
+}
+
+/// The voting power of a principal on an open proposal
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub struct VotingPower {
+    // The balance of the principal when the proposal was submitted, plus its weighed stake
+    pub own: Tokens,
+
+    // The balances of the principals whose delegations lead to this one, and who haven't voted
+    pub delegated: Tokens,
+
+    // Who votes with the principal's tokens, if it hasn't voted itself and its delegations lead to
+    // someone who has
+    pub delegated_to: Option<Principal>,
+}
+
+#[derive(Clone, Default, Debug, CandidType, Deserialize, PartialEq)]
 pub struct SystemParams {
     // The fee incurred by transferring tokens
     pub transfer_fee: Tokens,
@@ -126,11 +508,22 @@ pub struct SystemParams {
     // a user that submits a proposal. If the proposal is Accepted, this deposit is returned,
     // otherwise it is lost. This prevents users from submitting superfluous proposals.
     pub proposal_submission_deposit: Tokens,
+
+    // How long, in nanoseconds, a proposal stays open for voting. When the period is over, the
+    // proposal is settled by majority if the quorum was reached, and expires otherwise.
+    // A period of 0 keeps proposals open until the threshold is reached.
+    pub proposal_voting_period: u64,
+
+    // The amount of tokens that must have voted, "yes" or "no", for a proposal to be settled by
+    // majority at the end of its voting period
+    pub proposal_quorum: Tokens,
 }
 
-#[derive(Clone, Debug, CandidType, Deserialize)]
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
 pub struct UpdateSystemParamsPayload  {
     pub transfer_fee: Option<Tokens>,
     pub proposal_vote_threshold: Option<Tokens>,
     pub proposal_submission_deposit: Option<Tokens>,
+    pub proposal_voting_period: Option<u64>,
+    pub proposal_quorum: Option<Tokens>,
 }
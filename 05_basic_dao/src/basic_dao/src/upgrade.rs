@@ -0,0 +1,23 @@
+use crate::env::CanisterEnvironment;
+use crate::service::BasicDaoService;
+use crate::types::VersionedStableStorage;
+use crate::SERVICE;
+use ic_cdk::storage;
+use ic_cdk_macros::{post_upgrade, pre_upgrade};
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    let stable = SERVICE.with(|service| VersionedStableStorage::from(&*service.borrow()));
+    storage::stable_save((stable,)).expect("Failed to save the state to stable memory");
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    let (stable,): (VersionedStableStorage,) =
+        storage::stable_restore().expect("Failed to restore the state from stable memory");
+
+    let mut upgraded_service = BasicDaoService::from(stable);
+    upgraded_service.env = Box::new(CanisterEnvironment {});
+
+    SERVICE.with(|service| *service.borrow_mut() = upgraded_service);
+}